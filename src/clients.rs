@@ -1,9 +1,11 @@
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{self, Display};
 
-use crate::decimal_to_string;
+use crate::{
+    currency::Currency, decimal_to_string, interest::Accrual, limits::Limits, risk::RiskState,
+};
 
 // This allows us to order and compare id's in addition to all the other derive traits
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -21,60 +23,222 @@ impl From<u16> for ClientId {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct Client {
-    pub id: ClientId,
+impl From<ClientId> for u16 {
+    fn from(id: ClientId) -> Self {
+        id.0
+    }
+}
+
+// The funds a client holds in one currency
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Balance {
     pub available: Decimal,
     pub held: Decimal,
     pub total: Decimal,
+}
+
+impl Balance {
+    pub fn is_valid(&self) -> bool {
+        self.is_within(Decimal::from(0))
+    }
+
+    // Like `is_valid`, but the available funds and the total may go down to `-overdraft`
+    pub fn is_within(&self, overdraft: Decimal) -> bool {
+        if self.available < -overdraft || self.total < -overdraft {
+            return false;
+        }
+
+        self.is_balanced()
+    }
+
+    // The balances add up and nothing negative is held. Unlike `is_valid` this allows
+    // a negative available and total, which a policy can allow for disputes.
+    pub fn is_balanced(&self) -> bool {
+        let zero_val = Decimal::from(0);
+
+        let available_amt = self.total - self.held;
+        if available_amt != self.available {
+            return false;
+        }
+
+        let held_amt = self.total - self.available;
+        if self.held < zero_val || held_amt != self.held {
+            return false;
+        }
+
+        let total_amt = self.available + self.held;
+        if total_amt != self.total {
+            return false;
+        }
+
+        true
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Client {
+    pub id: ClientId,
+    // Every currency the client has used, a currency only shows up here once a
+    // transaction in it went through (see `balances_serde` for how it is serialized)
+    #[serde(flatten, with = "balances_serde")]
+    pub balances: BTreeMap<Currency, Balance>,
     pub locked: bool,
+    // A closed account is also locked, but unlike a lock this can't be undone
+    #[serde(default)]
+    pub closed: bool,
+    // What the risk rules have seen of the client, this is empty without rules
+    #[serde(default, skip_serializing_if = "RiskState::is_empty")]
+    pub risk: RiskState,
+    // The overdraft and spending limits per currency, a currency without any has neither
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub limits: BTreeMap<Currency, Limits>,
+    // How far interest has been paid per currency, only currencies that earn any are here
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub interest: BTreeMap<Currency, Accrual>,
 }
 
 impl Client {
     pub fn new(id: u16) -> Self {
         Self {
             id: ClientId::from(id),
-            available: Decimal::from(0),
-            held: Decimal::from(0),
-            total: Decimal::from(0),
+            balances: BTreeMap::new(),
             locked: false,
+            closed: false,
+            risk: RiskState::default(),
+            limits: BTreeMap::new(),
+            interest: BTreeMap::new(),
         }
     }
 
+    // A client with funds in the default currency
     pub fn new_with_values(id: u16, available: Decimal, held: Decimal, total: Decimal) -> Self {
-        Self {
-            id: ClientId::from(id),
+        let balance = Balance {
             available,
             held,
             total,
+        };
+
+        Self {
+            id: ClientId::from(id),
+            balances: BTreeMap::from([(Currency::default(), balance)]),
             locked: false,
+            closed: false,
+            risk: RiskState::default(),
+            limits: BTreeMap::new(),
+            interest: BTreeMap::new(),
         }
     }
 
+    // The funds in the currency, which are all zero if the client never used it
+    pub fn balance(&self, currency: Currency) -> Balance {
+        self.balances.get(&currency).copied().unwrap_or_default()
+    }
+
+    pub fn balance_mut(&mut self, currency: Currency) -> &mut Balance {
+        self.balances.entry(currency).or_default()
+    }
+
+    pub fn limits(&self, currency: Currency) -> Limits {
+        self.limits.get(&currency).copied().unwrap_or_default()
+    }
+
+    pub fn limits_mut(&mut self, currency: Currency) -> &mut Limits {
+        self.limits.entry(currency).or_default()
+    }
+
+    // What can be taken out of the currency, including the overdraft
+    pub fn spendable(&self, currency: Currency) -> Decimal {
+        self.balance(currency).available + self.limits(currency).overdraft
+    }
+
+    // Every currency has to be valid on its own, funds in one can't cover another.
+    // A currency with an overdraft may go below zero, down to the overdraft.
     pub fn is_valid(&self) -> bool {
+        self.balances
+            .iter()
+            .all(|(currency, balance)| balance.is_within(self.limits(*currency).overdraft))
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        self.balances.values().all(Balance::is_balanced)
+    }
+
+    // Nothing is held or left in any currency
+    pub fn is_empty(&self) -> bool {
         let zero_val = Decimal::from(0);
 
-        let available_amt = self.total - self.held;
-        if self.available < zero_val || available_amt != self.available {
-            return false;
-        }
+        self.balances
+            .values()
+            .all(|balance| balance.total == zero_val && balance.held == zero_val)
+    }
 
-        let held_amt = self.total - self.available;
-        if self.held < zero_val || held_amt != self.held {
-            return false;
+    // The rows of the account output, one per currency. A client that never used
+    // any currency still gets a row, with zeros in the default one.
+    pub fn balance_rows(&self) -> Vec<(Currency, Balance)> {
+        if self.balances.is_empty() {
+            return vec![(Currency::default(), Balance::default())];
         }
 
-        let total_amt = self.available + self.held;
-        if self.total < zero_val || total_amt != self.total {
-            return false;
-        }
+        self.balances
+            .iter()
+            .map(|(currency, balance)| (*currency, *balance))
+            .collect()
+    }
+}
 
-        true
+// A client that only has funds in the default currency is serialized with the
+// flat `available`, `held` and `total` it had before currencies, so the HTTP API
+// and single currency snapshots look like they always did. Anything else gets a
+// `balances` map by currency. Both are read back.
+mod balances_serde {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    use super::Balance;
+    use crate::currency::Currency;
+
+    #[derive(Serialize)]
+    #[serde(untagged)]
+    enum Shape<'a> {
+        Default(&'a Balance),
+        PerCurrency {
+            balances: &'a BTreeMap<Currency, Balance>,
+        },
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OwnedShape {
+        Default(Balance),
+        PerCurrency {
+            balances: BTreeMap<Currency, Balance>,
+        },
+    }
+
+    pub fn serialize<S: Serializer>(
+        balances: &BTreeMap<Currency, Balance>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let shape = match balances.get(&Currency::default()) {
+            Some(balance) if balances.len() == 1 => Shape::Default(balance),
+            _ => Shape::PerCurrency { balances },
+        };
+
+        shape.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<Currency, Balance>, D::Error> {
+        Ok(match OwnedShape::deserialize(deserializer)? {
+            OwnedShape::Default(balance) => BTreeMap::from([(Currency::default(), balance)]),
+            OwnedShape::PerCurrency { balances } => balances,
+        })
     }
 }
 
 // This is a mapping of client id to client
-#[derive(Debug, Default)]
+#[derive(Debug, Serialize, Deserialize, Default)]
 pub struct ClientList(HashMap<ClientId, Client>);
 
 impl ClientList {
@@ -93,31 +257,76 @@ impl ClientList {
     pub fn get_or_create_client(&mut self, id: &ClientId) -> &mut Client {
         self.0.entry(*id).or_insert_with(|| Client::new(id.0))
     }
+
+    pub fn remove_client(&mut self, id: &ClientId) -> Option<Client> {
+        self.0.remove(id)
+    }
+
+    pub fn insert_client(&mut self, client: Client) {
+        self.0.insert(client.id, client);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Client> {
+        self.0.values()
+    }
+
+    // Whether any client used a currency other than the default one. Only then does
+    // the account output have a currency column, so it stays the same for inputs
+    // without currencies.
+    pub fn has_other_currencies(&self) -> bool {
+        self.iter().any(|client| {
+            client
+                .balances
+                .keys()
+                .any(|currency| *currency != Currency::default())
+        })
+    }
+}
+
+impl IntoIterator for ClientList {
+    type Item = Client;
+    type IntoIter = std::collections::hash_map::IntoValues<ClientId, Client>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_values()
+    }
 }
 
 impl Display for ClientList {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "client, available, held, total, locked")?;
-        for client in self.0.values() {
-            let Client {
-                id,
-                available,
-                held,
-                total,
-                locked,
-            } = client;
-
-            // Here we can rely on the decimal_to_string function to handle the rounding
-            // so there is a single place to change the precision and less hardcoded logic
-            writeln!(
-                f,
-                "{}, {1}, {2}, {3}, {4}",
-                id,
-                decimal_to_string(*available),
-                decimal_to_string(*held),
-                decimal_to_string(*total),
-                locked
-            )?;
+        // The currency comes last so the columns before it are where they always were
+        let with_currency = self.has_other_currencies();
+        if with_currency {
+            writeln!(f, "client, available, held, total, locked, currency")?;
+        } else {
+            writeln!(f, "client, available, held, total, locked")?;
+        }
+
+        for client in self.iter() {
+            for (currency, balance) in client.balance_rows() {
+                let Balance {
+                    available,
+                    held,
+                    total,
+                } = balance;
+
+                // Here we can rely on the decimal_to_string function to handle the rounding
+                // so there is a single place to change the precision and less hardcoded logic
+                write!(
+                    f,
+                    "{}, {1}, {2}, {3}, {4}",
+                    client.id,
+                    decimal_to_string(available),
+                    decimal_to_string(held),
+                    decimal_to_string(total),
+                    client.locked,
+                )?;
+
+                if with_currency {
+                    write!(f, ", {currency}")?;
+                }
+                writeln!(f)?;
+            }
         }
         Ok(())
     }
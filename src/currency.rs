@@ -0,0 +1,65 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use crate::errors::TpsError;
+
+// An ISO 4217 currency code, e.g. USD or EUR. The letters are kept inline instead
+// of in a String, so a currency is `Copy` and keying the balances by it doesn't
+// allocate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub const USD: Currency = Currency(*b"USD");
+
+    pub fn as_str(&self) -> &str {
+        // Only ASCII letters get in through `from_str`
+        std::str::from_utf8(&self.0).expect("currency codes are ASCII")
+    }
+}
+
+// Rows without a currency are in USD, which is what every input was before
+// currencies were added
+impl Default for Currency {
+    fn default() -> Self {
+        Currency::USD
+    }
+}
+
+impl FromStr for Currency {
+    type Err = TpsError;
+
+    // Codes are case insensitive, "eur" is the same as "EUR"
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        let code = code.trim();
+
+        match <[u8; 3]>::try_from(code.as_bytes()) {
+            Ok(letters) if letters.iter().all(u8::is_ascii_alphabetic) => {
+                Ok(Currency(letters.map(|letter| letter.to_ascii_uppercase())))
+            }
+            _ => Err(TpsError::UnknownCurrency(code.to_string())),
+        }
+    }
+}
+
+impl Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = String::deserialize(deserializer)?;
+        code.parse().map_err(de::Error::custom)
+    }
+}
@@ -9,6 +9,42 @@ pub enum TpsError {
     #[error("CSV error: {0}")]
     CsvError(#[from] csv::Error),
 
+    #[error("JSON error: {0}")]
+    JsonError(#[from] serde_json::Error),
+
+    #[error("Unknown format: {0}")]
+    UnknownFormat(String),
+
+    #[error("Unknown currency: {0}")]
+    UnknownCurrency(String),
+
+    #[error("Unsupported snapshot version: {0}")]
+    SnapshotVersion(u32),
+
+    #[error("Unsupported database version: {0}")]
+    DatabaseVersion(u32),
+
+    #[error("Policy error: {0}")]
+    PolicyError(#[from] toml::de::Error),
+
+    #[error("Invalid policy: {0}")]
+    InvalidPolicy(String),
+
+    #[error("Invalid rate: {0}")]
+    InvalidRate(String),
+
+    #[error("Invalid limits: {0}")]
+    InvalidLimits(String),
+
+    #[error("Storage error: {0}")]
+    StorageError(#[from] rusqlite::Error),
+
+    #[error("The input is not the one the write-ahead log was written for")]
+    WalMismatch,
+
+    #[error("A processing worker stopped unexpectedly")]
+    WorkerStopped,
+
     #[error("Transaction error: {0}")]
     TransactionError(#[from] TransactionError),
 }
@@ -0,0 +1,166 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{
+    clients::ClientId,
+    currency::Currency,
+    decimal_to_string,
+    errors::TpsError,
+    formats::write_report,
+    round_amount,
+    transactions::{lifecycle::StoredTransaction, Transaction, TransactionId, TransactionType},
+};
+
+// The fees charged for a transaction type, optionally only for the clients in a
+// group. A fee is `flat` plus `percent` of the amount, with the `flat` and `percent`
+// of the highest tier the amount reaches added on top, and at most `cap`.
+//
+//     [[fees]]
+//     type = "withdrawal"
+//     group = "retail"
+//     flat = "0.25"
+//     tiers = [{ from = "0", percent = "1.5" }, { from = "1000", percent = "1" }]
+//     cap = "20"
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeRule {
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    // One of the policy's `client_groups`, without one the rule is for every client
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub flat: Decimal,
+    #[serde(default)]
+    pub percent: Decimal,
+    // Sorted by `from`, lowest first
+    #[serde(default)]
+    pub tiers: Vec<FeeTier>,
+    #[serde(default)]
+    pub cap: Option<Decimal>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeTier {
+    // The smallest amount the tier applies to
+    pub from: Decimal,
+    #[serde(default)]
+    pub flat: Decimal,
+    #[serde(default)]
+    pub percent: Decimal,
+}
+
+impl FeeRule {
+    pub fn matches(
+        &self,
+        transaction: &Transaction,
+        groups: &HashMap<String, Vec<ClientId>>,
+    ) -> bool {
+        self.tx_type == transaction.tx_type
+            && self.group.as_ref().is_none_or(|group| {
+                groups
+                    .get(group)
+                    .is_some_and(|clients| clients.contains(&transaction.client_id))
+            })
+    }
+
+    // The fee for an amount, rounded like the balances are
+    pub fn fee(&self, amount: Decimal) -> Decimal {
+        let tier = self.tiers.iter().rev().find(|tier| tier.from <= amount);
+
+        let flat = self.flat + tier.map_or(Decimal::ZERO, |tier| tier.flat);
+        let percent = self.percent + tier.map_or(Decimal::ZERO, |tier| tier.percent);
+
+        // An amount this large is rejected on its own, so no fee is charged for it
+        let fee = amount
+            .checked_mul(percent / Decimal::ONE_HUNDRED)
+            .and_then(|fee| fee.checked_add(flat))
+            .unwrap_or(Decimal::ZERO);
+
+        round_amount(self.cap.map_or(fee, |cap| fee.min(cap)))
+    }
+
+    pub fn check(&self, groups: &HashMap<String, Vec<ClientId>>) -> Result<(), TpsError> {
+        if !self.tx_type.is_stored() || self.tx_type.is_admin() {
+            return Err(TpsError::InvalidPolicy(format!(
+                "{:?} transactions can't have fees",
+                self.tx_type
+            )));
+        }
+
+        if let Some(group) = &self.group {
+            if !groups.contains_key(group) {
+                return Err(TpsError::InvalidPolicy(format!(
+                    "unknown client group {group}"
+                )));
+            }
+        }
+
+        let mut amounts = [self.flat, self.percent].into_iter().chain(self.cap).chain(
+            self.tiers
+                .iter()
+                .flat_map(|tier| [tier.from, tier.flat, tier.percent]),
+        );
+        if amounts.any(|amount| amount < Decimal::ZERO) {
+            return Err(TpsError::InvalidPolicy(format!(
+                "{:?} fees can't be negative",
+                self.tx_type
+            )));
+        }
+
+        if !self.tiers.is_sorted_by_key(|tier| tier.from) {
+            return Err(TpsError::InvalidPolicy(format!(
+                "{:?} fee tiers have to be sorted",
+                self.tx_type
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+// A fee that was charged, it is kept with the stored transaction as the audit record
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fee {
+    pub amount: Decimal,
+    // The client that was charged, in the currency of the transaction
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+    pub currency: Currency,
+    // Where the fee went
+    #[serde(rename = "house")]
+    pub house_account: ClientId,
+}
+
+// One line of the fee report
+#[derive(Serialize)]
+struct FeeLine {
+    tx: TransactionId,
+    #[serde(rename = "type")]
+    tx_type: TransactionType,
+    client: ClientId,
+    fee: String,
+    house: ClientId,
+    currency: Currency,
+}
+
+// Writes every fee in the transaction store, in the order the transactions were stored.
+pub fn write_fee_report(
+    path: &str,
+    transactions: impl IntoIterator<Item = StoredTransaction>,
+) -> Result<(), TpsError> {
+    let lines = transactions.into_iter().filter_map(|stored| {
+        stored.fee.map(|fee| FeeLine {
+            tx: stored.transaction.tx_id,
+            tx_type: stored.transaction.tx_type,
+            client: fee.client_id,
+            fee: decimal_to_string(fee.amount),
+            house: fee.house_account,
+            currency: fee.currency,
+        })
+    });
+
+    write_report(path, lines)
+}
@@ -0,0 +1,256 @@
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use crate::{
+    clients::{ClientId, ClientList},
+    currency::Currency,
+    decimal_to_string,
+    errors::TpsError,
+    input,
+    transactions::{Position, Transaction},
+    CsvChunkedReader,
+};
+
+// Chunks of transactions, independent of the format they were read from
+pub type TransactionChunks = Box<dyn Iterator<Item = Result<Vec<Transaction>, TpsError>>>;
+
+// The formats transactions can be read in, and accounts written out in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Csv,
+    // One JSON object per line, e.g. `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}`
+    JsonLines,
+}
+
+impl FromStr for Format {
+    type Err = TpsError;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "csv" => Ok(Format::Csv),
+            "jsonl" | "ndjson" => Ok(Format::JsonLines),
+            _ => Err(TpsError::UnknownFormat(format.to_string())),
+        }
+    }
+}
+
+impl Format {
+    // Opens the file (or stdin for "-") and reads it in chunks of transactions
+    pub fn reader(&self, filename: &str, chunk_size: usize) -> Result<TransactionChunks, TpsError> {
+        let reader = input::open_input(filename)?;
+
+        Ok(match self {
+            Format::Csv => Box::new(CsvChunkedReader::from_reader(reader, chunk_size)),
+            Format::JsonLines => Box::new(JsonLinesChunkedReader::from_reader(reader, chunk_size)),
+        })
+    }
+
+    pub fn write_clients(
+        &self,
+        clients: &ClientList,
+        writer: &mut impl io::Write,
+    ) -> Result<(), TpsError> {
+        match self {
+            Format::Csv => writeln!(writer, "{clients}")?,
+            Format::JsonLines => {
+                let with_currency = clients.has_other_currencies();
+                for client in clients.iter() {
+                    for (currency, balance) in client.balance_rows() {
+                        let row = AccountRow {
+                            client: client.id,
+                            available: decimal_to_string(balance.available),
+                            held: decimal_to_string(balance.held),
+                            total: decimal_to_string(balance.total),
+                            locked: client.locked,
+                            currency: with_currency.then_some(currency),
+                        };
+
+                        serde_json::to_writer(&mut *writer, &row)?;
+                        writeln!(writer)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// The same columns as the CSV output. The amounts are strings so they keep
+// exactly the precision of `decimal_to_string`, instead of becoming floats.
+#[derive(Serialize)]
+struct AccountRow {
+    client: ClientId,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+    // Only there when a client used another currency than the default one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+}
+
+// The format of a report file written at the end of a run: JSON Lines for a
+// `.json`/`.jsonl` file, CSV for anything else
+pub fn report_format(path: &str) -> Format {
+    match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("json" | "jsonl" | "ndjson") => Format::JsonLines,
+        _ => Format::Csv,
+    }
+}
+
+enum ReportOutput {
+    Csv(Box<csv::Writer<File>>),
+    JsonLines(BufWriter<File>),
+}
+
+// Writes the rows of a report one at a time, in the format `report_format` picks
+pub struct ReportWriter {
+    output: ReportOutput,
+}
+
+impl ReportWriter {
+    // With `append` the rows are added to an existing report, which already has a
+    // header unless it is empty
+    pub fn create(path: &str, append: bool) -> Result<Self, TpsError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)?;
+        let is_empty = file.metadata()?.len() == 0;
+
+        let output = match report_format(path) {
+            Format::Csv => ReportOutput::Csv(Box::new(
+                csv::WriterBuilder::new()
+                    .has_headers(is_empty)
+                    .from_writer(file),
+            )),
+            Format::JsonLines => ReportOutput::JsonLines(BufWriter::new(file)),
+        };
+
+        Ok(Self { output })
+    }
+
+    pub fn write<T: Serialize>(&mut self, row: &T) -> Result<(), TpsError> {
+        match &mut self.output {
+            ReportOutput::Csv(writer) => writer.serialize(row)?,
+            ReportOutput::JsonLines(writer) => {
+                serde_json::to_writer(&mut *writer, row)?;
+                writeln!(writer)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), TpsError> {
+        match &mut self.output {
+            ReportOutput::Csv(writer) => writer.flush()?,
+            ReportOutput::JsonLines(writer) => writer.flush()?,
+        }
+
+        Ok(())
+    }
+}
+
+// Writes a whole report, replacing the file if there is one
+pub fn write_report<T: Serialize>(
+    path: &str,
+    rows: impl IntoIterator<Item = T>,
+) -> Result<(), TpsError> {
+    let mut writer = ReportWriter::create(path, false)?;
+    for row in rows {
+        writer.write(&row)?;
+    }
+
+    writer.flush()
+}
+
+// The JSON Lines version of `CsvChunkedReader`. Amounts can be given as strings
+// or numbers, both are parsed into a `Decimal` without going through a float.
+// Blank lines are skipped, but still counted for the positions of the rows.
+pub struct JsonLinesChunkedReader<R: io::Read> {
+    reader: io::BufReader<R>,
+    // Reused between rows to avoid an allocation per row
+    line: String,
+    // Where the next line starts
+    position: Position,
+    chunk_size: usize,
+}
+
+impl<R: io::Read> JsonLinesChunkedReader<R> {
+    pub fn from_reader(reader: R, chunk_size: usize) -> Self {
+        Self {
+            reader: io::BufReader::new(reader),
+            line: String::new(),
+            position: Position { line: 1, byte: 0 },
+            chunk_size,
+        }
+    }
+
+    // Reads the next row along with where it starts in the input
+    fn next_transaction(&mut self) -> Result<Option<Transaction>, TpsError> {
+        loop {
+            self.line.clear();
+            let read = self.reader.read_line(&mut self.line)?;
+            if read == 0 {
+                return Ok(None);
+            }
+
+            let position = self.position;
+            self.position = Position {
+                line: position.line + 1,
+                byte: position.byte + read as u64,
+            };
+
+            let row = self.line.trim_end_matches(['\r', '\n']);
+            if row.trim().is_empty() {
+                continue;
+            }
+
+            // A row that can't be read is rejected rather than stopping the input
+            let mut txn = serde_json::from_str(row)
+                .unwrap_or_else(|err: serde_json::Error| Transaction::unreadable(err.to_string()));
+            txn.position = Some(position);
+            txn.raw = Some(row.to_string());
+
+            return Ok(Some(txn));
+        }
+    }
+}
+
+impl<R: io::Read> Iterator for JsonLinesChunkedReader<R> {
+    type Item = Result<Vec<Transaction>, TpsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut chunk = Vec::with_capacity(self.chunk_size);
+
+        loop {
+            let txn = match self.next_transaction() {
+                Ok(Some(txn)) => txn,
+                Ok(None) => break,
+                Err(err) => return Some(Err(err)),
+            };
+
+            chunk.push(txn);
+
+            if chunk.len() == self.chunk_size {
+                return Some(Ok(chunk));
+            }
+        }
+
+        if chunk.is_empty() {
+            None
+        } else {
+            Some(Ok(chunk))
+        }
+    }
+}
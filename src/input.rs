@@ -0,0 +1,67 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    path::Path,
+};
+
+// The filename that stands for standard input
+pub const STDIN: &str = "-";
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    // Checks the first bytes of the input, this works for stdin too
+    pub fn from_magic(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(GZIP_MAGIC) {
+            Some(Compression::Gzip)
+        } else if bytes.starts_with(ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "gz" | "gzip" => Some(Compression::Gzip),
+            "zst" | "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+}
+
+// Opens a file (or stdin for "-") and decompresses it if needed. The magic bytes
+// are checked first, and the extension is only used when they are not recognized.
+pub fn open_input(filename: &str) -> io::Result<Box<dyn Read>> {
+    if filename == STDIN {
+        let reader = BufReader::new(io::stdin());
+        return decompress(reader, None);
+    }
+
+    let reader = BufReader::new(File::open(filename)?);
+    decompress(reader, Compression::from_extension(Path::new(filename)))
+}
+
+pub fn decompress(
+    mut reader: impl BufRead + 'static,
+    fallback: Option<Compression>,
+) -> io::Result<Box<dyn Read>> {
+    // fill_buf() only peeks, the bytes are still read by the decoder afterwards
+    let compression = Compression::from_magic(reader.fill_buf()?)
+        .or(fallback)
+        .unwrap_or(Compression::None);
+
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(reader)?),
+    })
+}
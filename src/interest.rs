@@ -0,0 +1,371 @@
+use chrono::{DateTime, Days, NaiveDate, NaiveTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+};
+
+use crate::{
+    clients::{ClientId, ClientList},
+    currency::Currency,
+    decimal_to_string,
+    errors::TpsError,
+    formats::write_report,
+    ledger::{Account, JournalEntry, Line},
+    policy::Policy,
+    round_amount,
+    transactions::{manager::TransactionManager, Transaction, TransactionError},
+};
+
+const DAYS_PER_YEAR: i64 = 365;
+
+// The interest paid on the available funds of a currency, optionally only for the
+// clients in a group. Each tier pays its yearly `rate` (in percent) on the part of
+// the funds between its `from` and the next tier's, so funds below the first tier
+// earn nothing. Interest is worked out per day, with 365 days to a year.
+//
+//     [[interest]]
+//     group = "savings"
+//     currency = "USD"
+//     tiers = [{ from = "0", rate = "1.5" }, { from = "10000", rate = "2.5" }]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InterestRule {
+    // One of the policy's `client_groups`, without one the rule is for every client
+    #[serde(default)]
+    pub group: Option<String>,
+    // Without one the rule is for every currency
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    // Sorted by `from`, lowest first
+    pub tiers: Vec<InterestTier>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InterestTier {
+    // The funds the tier starts at
+    pub from: Decimal,
+    pub rate: Decimal,
+}
+
+impl InterestRule {
+    pub fn matches(
+        &self,
+        client_id: ClientId,
+        currency: Currency,
+        groups: &HashMap<String, Vec<ClientId>>,
+    ) -> bool {
+        self.currency
+            .is_none_or(|rule_currency| rule_currency == currency)
+            && self.group.as_ref().is_none_or(|group| {
+                groups
+                    .get(group)
+                    .is_some_and(|clients| clients.contains(&client_id))
+            })
+    }
+
+    // The interest on `funds` over `days`, before rounding
+    pub fn interest(&self, funds: Decimal, days: i64) -> Decimal {
+        let mut yearly = Decimal::ZERO;
+
+        for (index, tier) in self.tiers.iter().enumerate() {
+            if funds <= tier.from {
+                break;
+            }
+
+            let to = self
+                .tiers
+                .get(index + 1)
+                .map_or(funds, |next| next.from.min(funds));
+            yearly += (to - tier.from) * tier.rate / Decimal::ONE_HUNDRED;
+        }
+
+        // Funds this large can't be held anyway, so nothing is paid on them
+        yearly
+            .checked_mul(Decimal::from(days))
+            .map_or(Decimal::ZERO, |interest| {
+                interest / Decimal::from(DAYS_PER_YEAR)
+            })
+    }
+
+    pub fn check(&self, groups: &HashMap<String, Vec<ClientId>>) -> Result<(), TpsError> {
+        if let Some(group) = &self.group {
+            if !groups.contains_key(group) {
+                return Err(TpsError::InvalidPolicy(format!(
+                    "unknown client group {group}"
+                )));
+            }
+        }
+
+        if self.tiers.is_empty() {
+            return Err(TpsError::InvalidPolicy(
+                "interest rules need at least one tier".to_string(),
+            ));
+        }
+
+        if self
+            .tiers
+            .iter()
+            .any(|tier| tier.from < Decimal::ZERO || tier.rate < Decimal::ZERO)
+        {
+            return Err(TpsError::InvalidPolicy(
+                "interest tiers can't be negative".to_string(),
+            ));
+        }
+
+        if !self.tiers.is_sorted_by_key(|tier| tier.from) {
+            return Err(TpsError::InvalidPolicy(
+                "interest tiers have to be sorted".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// How far a client's interest in one currency has been paid
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Accrual {
+    // The day interest was paid up to, the next accrual pays from here
+    pub accrued_to: NaiveDate,
+    // What rounding left out of the payments so far, it goes into the next one
+    pub carry: Decimal,
+}
+
+// The id of an interest payment. Payments are numbered on their own, from 1 in the
+// order they were paid, so they never take an id from the rows.
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct PostingId(u32);
+
+impl Display for PostingId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<u32> for PostingId {
+    fn from(id: u32) -> Self {
+        Self(id)
+    }
+}
+
+impl From<PostingId> for u32 {
+    fn from(id: PostingId) -> Self {
+        id.0
+    }
+}
+
+// Interest paid to a client. Payments are not rows, so they are kept apart from the
+// stored transactions (see `TransactionManager::insert_posting`) and don't take an
+// id from the rows. A client is paid at most once a day in a currency.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Posting {
+    // Given by the store that keeps the payment, it is 0 until then
+    pub id: PostingId,
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+    pub currency: Currency,
+    pub amount: Decimal,
+    pub date: NaiveDate,
+    // What the payment did to the accounts
+    pub journal: JournalEntry,
+}
+
+impl Posting {
+    // The payment along with its journal entry made at `at`
+    fn new(
+        client_id: ClientId,
+        currency: Currency,
+        amount: Decimal,
+        date: NaiveDate,
+        at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            id: PostingId::default(),
+            client_id,
+            currency,
+            amount,
+            date,
+            journal: JournalEntry {
+                at: Some(at),
+                currency,
+                lines: vec![
+                    Line::debit(Account::InterestExpense, amount),
+                    Line::credit(Account::Available(client_id), amount),
+                ],
+            },
+        }
+    }
+}
+
+// Interest is paid on the balance a client has at the end of each day, so every day
+// needs an end of day of its own. One that would skip a day is refused before
+// anything is paid, the balance of the day it skipped can't be known anymore.
+pub fn check_end_of_day(
+    clients: &ClientList,
+    policy: &Policy,
+    as_of: NaiveDate,
+) -> Result<(), TransactionError> {
+    for client in clients.iter() {
+        for (currency, accrual) in &client.interest {
+            if policy.interest_for(client.id, *currency).is_none() {
+                continue;
+            }
+
+            if let Some(next) = accrual.accrued_to.succ_opt() {
+                if as_of > next {
+                    return Err(TransactionError::MissedEndOfDay(next));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Pays every client a day of interest on their available funds at `as_of`, and
+// returns the payments, made at `at`, in order of client and currency. This expects
+// `check_end_of_day` to have passed.
+//
+// The first accrual of a currency only starts the clock, there is no telling how
+// long the funds were there before it. Locked and closed clients earn nothing, but
+// their clock goes on, so they are not paid for those days once they are unlocked.
+// Payments are rounded like the balances are, and what rounding leaves out is
+// carried over so nothing is lost over time.
+pub fn accrue(
+    clients: &mut ClientList,
+    policy: &Policy,
+    as_of: NaiveDate,
+    at: DateTime<Utc>,
+) -> Vec<Posting> {
+    let mut client_ids: Vec<ClientId> = clients.iter().map(|client| client.id).collect();
+    client_ids.sort_unstable();
+
+    let mut postings = Vec::new();
+
+    for client_id in client_ids {
+        let Some(client) = clients.get_client_mut(&client_id) else {
+            continue;
+        };
+
+        let earns = !client.locked && !client.closed;
+
+        let funds: Vec<(Currency, Decimal)> = client
+            .balances
+            .iter()
+            .map(|(currency, balance)| (*currency, balance.available.max(Decimal::ZERO)))
+            .collect();
+
+        for (currency, funds) in funds {
+            let Some(rule) = policy.interest_for(client_id, currency) else {
+                // The clock starts over if a rule comes back
+                client.interest.remove(&currency);
+                continue;
+            };
+
+            let Some(accrual) = client.interest.get_mut(&currency) else {
+                client.interest.insert(
+                    currency,
+                    Accrual {
+                        accrued_to: as_of,
+                        carry: Decimal::ZERO,
+                    },
+                );
+                continue;
+            };
+
+            let days = (as_of - accrual.accrued_to).num_days();
+            if days <= 0 {
+                continue;
+            }
+            accrual.accrued_to = as_of;
+
+            if !earns {
+                continue;
+            }
+
+            let interest = rule.interest(funds, days) + accrual.carry;
+            let amount = round_amount(interest);
+            accrual.carry = interest - amount;
+
+            if amount > Decimal::ZERO {
+                let balance = client.balance_mut(currency);
+                balance.available += amount;
+                balance.total += amount;
+
+                postings.push(Posting::new(client_id, currency, amount, as_of, at));
+            }
+        }
+    }
+
+    postings
+}
+
+// The day an end of day row accrues up to, which it has to name
+pub fn as_of(transaction: &Transaction) -> Result<NaiveDate, TransactionError> {
+    transaction.check_row()?;
+    transaction.date.ok_or(TransactionError::MissingDate)
+}
+
+// When the payments of an end of day row are made, which is at its timestamp, or
+// the end of the day it accrues up to when it has none
+pub fn paid_at(transaction: &Transaction, as_of: NaiveDate) -> DateTime<Utc> {
+    transaction
+        .timestamp
+        .unwrap_or_else(|| (as_of + Days::new(1)).and_time(NaiveTime::MIN).and_utc())
+}
+
+// Runs an end of day row, which pays interest to every client and keeps the
+// payments with the transactions
+pub fn end_of_day(
+    transaction: &Transaction,
+    clients: &mut ClientList,
+    transactions: &mut TransactionManager,
+    policy: &Policy,
+) -> Result<(), TransactionError> {
+    let as_of = as_of(transaction)?;
+    check_end_of_day(clients, policy, as_of)?;
+
+    let at = paid_at(transaction, as_of);
+    for posting in accrue(clients, policy, as_of, at) {
+        transactions.insert_posting(posting);
+    }
+
+    Ok(())
+}
+
+// One line of the interest report
+#[derive(Serialize)]
+struct InterestLine {
+    posting: PostingId,
+    client: ClientId,
+    amount: String,
+    currency: Currency,
+    date: NaiveDate,
+    at: Option<DateTime<Utc>>,
+}
+
+// Writes every interest payment made by `at`, in the order they were paid
+pub fn write_interest_report<'a>(
+    path: &str,
+    postings: impl IntoIterator<Item = &'a Posting>,
+    at: Option<DateTime<Utc>>,
+) -> Result<(), TpsError> {
+    let lines = postings
+        .into_iter()
+        .filter(|posting| posting.journal.is_before(at))
+        .map(|posting| InterestLine {
+            posting: posting.id,
+            client: posting.client_id,
+            amount: decimal_to_string(posting.amount),
+            currency: posting.currency,
+            date: posting.date,
+            at: posting.journal.at,
+        });
+
+    write_report(path, lines)
+}
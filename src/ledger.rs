@@ -0,0 +1,379 @@
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt::{self, Display},
+};
+
+use crate::{
+    clients::{Balance, ClientId, ClientList},
+    currency::Currency,
+    decimal_to_string,
+    errors::TpsError,
+    formats::write_report,
+    interest::Posting,
+    transactions::{lifecycle::StoredTransaction, TransactionError},
+};
+
+// The accounts of the double-entry ledger. Every client has an available and a held
+// account in each currency, the others belong to the house.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Account {
+    Available(ClientId),
+    Held(ClientId),
+    // The money the house holds for its clients, deposits come in and withdrawals
+    // go out through it
+    HouseCash,
+    // What chargebacks took back from clients, which the card networks reclaim from
+    // the house. It is kept apart from the cash so it can be reported on its own.
+    ChargebackLoss,
+    // The other side of conversions, it has a balance in every currency converted
+    // from or to
+    Exchange,
+    // The interest paid to clients
+    InterestExpense,
+    // What support staff added to or took from accounts with adjustments
+    Adjustments,
+}
+
+impl Account {
+    // The client the account belongs to, if it is not one of the house's
+    pub fn client_id(&self) -> Option<ClientId> {
+        match self {
+            Account::Available(client_id) | Account::Held(client_id) => Some(*client_id),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Account::Available(_) => "available",
+            Account::Held(_) => "held",
+            Account::HouseCash => "house_cash",
+            Account::ChargebackLoss => "chargeback_loss",
+            Account::Exchange => "exchange",
+            Account::InterestExpense => "interest_expense",
+            Account::Adjustments => "adjustments",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+// One side of an entry, a debit when the amount is positive and a credit when it
+// is negative. Client accounts are what the house owes its clients, so funds
+// given to a client are a credit to its account.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Line {
+    pub account: Account,
+    pub amount: Decimal,
+}
+
+impl Line {
+    pub fn debit(account: Account, amount: Decimal) -> Self {
+        Self { account, amount }
+    }
+
+    pub fn credit(account: Account, amount: Decimal) -> Self {
+        Self {
+            account,
+            amount: -amount,
+        }
+    }
+}
+
+// What one row changed in one currency, the lines of an entry always add up to zero
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    // When the row happened, rows without a timestamp made entries without one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub at: Option<DateTime<Utc>>,
+    pub currency: Currency,
+    pub lines: Vec<Line>,
+}
+
+impl JournalEntry {
+    // Whether the entry was made by `at`. Entries without a time are from rows
+    // without a timestamp, or from before the journal kept one, so they always were.
+    pub fn is_before(&self, at: Option<DateTime<Utc>>) -> bool {
+        match (self.at, at) {
+            (Some(entry_at), Some(at)) => entry_at <= at,
+            _ => true,
+        }
+    }
+}
+
+// One entry made at `at` for every currency the lines are in, leaving out the lines
+// of nothing. An entry that doesn't add up to zero is refused, there is no account
+// to put the difference in.
+pub fn journal_entries(
+    lines: Vec<(Currency, Line)>,
+    at: Option<DateTime<Utc>>,
+) -> Result<Vec<JournalEntry>, TransactionError> {
+    let mut entries: BTreeMap<Currency, Vec<Line>> = BTreeMap::new();
+    for (currency, line) in lines {
+        if line.amount != Decimal::ZERO {
+            entries.entry(currency).or_default().push(line);
+        }
+    }
+
+    entries
+        .into_iter()
+        .map(|(currency, lines)| {
+            if lines.iter().map(|line| line.amount).sum::<Decimal>() != Decimal::ZERO {
+                return Err(TransactionError::UnbalancedEntry(currency));
+            }
+
+            Ok(JournalEntry {
+                at,
+                currency,
+                lines,
+            })
+        })
+        .collect()
+}
+
+// The balances of the clients an operation changes, taken before it is applied so
+// its journal entries can be checked against what it did to them afterwards
+pub struct BalanceSnapshot(Vec<(ClientId, BTreeMap<Currency, Balance>)>);
+
+impl BalanceSnapshot {
+    pub fn take(clients: &ClientList, client_ids: impl IntoIterator<Item = ClientId>) -> Self {
+        let mut seen = HashSet::new();
+
+        Self(
+            client_ids
+                .into_iter()
+                .filter(|client_id| seen.insert(*client_id))
+                .map(|client_id| {
+                    let balances = clients
+                        .get_client(&client_id)
+                        .map(|client| client.balances.clone())
+                        .unwrap_or_default();
+                    (client_id, balances)
+                })
+                .collect(),
+        )
+    }
+
+    // Whether the lines for the client accounts in `entries` add up to what the
+    // balances of the clients changed by. The entries are worked out by the operation
+    // on its own, so this catches an operation whose books and balances disagree.
+    pub fn check(
+        &self,
+        clients: &ClientList,
+        entries: &[JournalEntry],
+    ) -> Result<(), TransactionError> {
+        // What the lines gave each client, as (available, held)
+        let mut posted: BTreeMap<(ClientId, Currency), (Decimal, Decimal)> = BTreeMap::new();
+        for entry in entries {
+            for line in &entry.lines {
+                let Some(client_id) = line.account.client_id() else {
+                    continue;
+                };
+
+                let (available, held) = posted.entry((client_id, entry.currency)).or_default();
+                match line.account {
+                    Account::Held(_) => *held -= line.amount,
+                    _ => *available -= line.amount,
+                }
+            }
+        }
+
+        for (client_id, before) in &self.0 {
+            let empty = BTreeMap::new();
+            let after = clients
+                .get_client(client_id)
+                .map_or(&empty, |client| &client.balances);
+
+            let currencies: BTreeSet<Currency> =
+                before.keys().chain(after.keys()).copied().collect();
+
+            for currency in currencies {
+                let old = before.get(&currency).copied().unwrap_or_default();
+                let new = after.get(&currency).copied().unwrap_or_default();
+                let changed = (new.available - old.available, new.held - old.held);
+
+                let lines = posted.remove(&(*client_id, currency)).unwrap_or_default();
+                if lines != changed {
+                    return Err(TransactionError::JournalMismatch(*client_id, currency));
+                }
+            }
+        }
+
+        // Lines for a client the operation didn't change
+        match posted.into_keys().next() {
+            Some((client_id, currency)) => {
+                Err(TransactionError::JournalMismatch(client_id, currency))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+// The balance of every account, worked out from the journal of the stored
+// transactions and the interest payments alone
+#[derive(Debug, Default)]
+pub struct Ledger {
+    balances: BTreeMap<(Currency, Account), Decimal>,
+}
+
+impl Ledger {
+    pub fn from_transactions<'a>(
+        transactions: impl IntoIterator<Item = &'a StoredTransaction>,
+        postings: &[Posting],
+    ) -> Self {
+        Self::as_of(transactions, postings, None)
+    }
+
+    // The balances as they were at `at`, leaving out the entries made after it
+    pub fn as_of<'a>(
+        transactions: impl IntoIterator<Item = &'a StoredTransaction>,
+        postings: &[Posting],
+        at: Option<DateTime<Utc>>,
+    ) -> Self {
+        let mut ledger = Self::default();
+
+        let entries = transactions
+            .into_iter()
+            .flat_map(|stored| &stored.journal)
+            .chain(postings.iter().map(|posting| &posting.journal))
+            .filter(|entry| entry.is_before(at));
+        for entry in entries {
+            for line in &entry.lines {
+                *ledger
+                    .balances
+                    .entry((entry.currency, line.account))
+                    .or_default() += line.amount;
+            }
+        }
+
+        ledger
+    }
+
+    // The debits less the credits of an account
+    pub fn account(&self, currency: Currency, account: Account) -> Decimal {
+        self.balances
+            .get(&(currency, account))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // A client's funds, as the journal has them
+    pub fn balance(&self, client_id: ClientId, currency: Currency) -> Balance {
+        let available = -self.account(currency, Account::Available(client_id));
+        let held = -self.account(currency, Account::Held(client_id));
+
+        Balance {
+            available,
+            held,
+            total: available + held,
+        }
+    }
+
+    // What all the accounts add up to in every currency, which is zero for each
+    // when the books balance
+    pub fn trial_balance(&self) -> BTreeMap<Currency, Decimal> {
+        let mut totals = BTreeMap::new();
+        for ((currency, _), amount) in &self.balances {
+            *totals.entry(*currency).or_default() += amount;
+        }
+        totals
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        self.trial_balance()
+            .values()
+            .all(|total| *total == Decimal::ZERO)
+    }
+
+    // The clients and currencies where the balances kept with the clients differ
+    // from the journal
+    pub fn reconcile(&self, clients: &ClientList) -> Vec<(ClientId, Currency)> {
+        let mut mismatches: Vec<(ClientId, Currency)> = clients
+            .iter()
+            .flat_map(|client| {
+                client
+                    .balances
+                    .iter()
+                    .filter(|(currency, balance)| self.balance(client.id, **currency) != **balance)
+                    .map(|(currency, _)| (client.id, *currency))
+            })
+            .collect();
+
+        // A client the journal has funds for may be missing altogether
+        for (currency, account) in self.balances.keys() {
+            if let Some(client_id) = account.client_id() {
+                let kept = clients
+                    .get_client(&client_id)
+                    .map(|client| client.balance(*currency))
+                    .unwrap_or_default();
+                if kept != self.balance(client_id, *currency)
+                    && !mismatches.contains(&(client_id, *currency))
+                {
+                    mismatches.push((client_id, *currency));
+                }
+            }
+        }
+
+        mismatches.sort_unstable();
+        mismatches
+    }
+}
+
+// One line of the trial balance report
+#[derive(Serialize)]
+struct TrialBalanceLine {
+    currency: Currency,
+    account: String,
+    client: Option<ClientId>,
+    debit: String,
+    credit: String,
+}
+
+// Writes the balance of every account as a debit or a credit, followed by the total
+// debits and credits of each currency, which are equal when the books balance.
+pub fn write_trial_balance(path: &str, ledger: &Ledger) -> Result<(), TpsError> {
+    let mut lines = Vec::new();
+
+    for currency in ledger.trial_balance().into_keys() {
+        let mut debits = Decimal::ZERO;
+        let mut credits = Decimal::ZERO;
+
+        let accounts = ledger
+            .balances
+            .iter()
+            .filter(|((account_currency, _), _)| *account_currency == currency);
+        for ((_, account), amount) in accounts {
+            let (debit, credit) = if *amount >= Decimal::ZERO {
+                (*amount, Decimal::ZERO)
+            } else {
+                (Decimal::ZERO, -*amount)
+            };
+            debits += debit;
+            credits += credit;
+
+            lines.push(TrialBalanceLine {
+                currency,
+                account: account.to_string(),
+                client: account.client_id(),
+                debit: decimal_to_string(debit),
+                credit: decimal_to_string(credit),
+            });
+        }
+
+        lines.push(TrialBalanceLine {
+            currency,
+            account: "total".to_string(),
+            client: None,
+            debit: decimal_to_string(debits),
+            credit: decimal_to_string(credits),
+        });
+    }
+
+    write_report(path, lines)
+}
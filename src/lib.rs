@@ -1,47 +1,169 @@
-use rust_decimal::Decimal;
+use rust_decimal::{Decimal, RoundingStrategy};
+use std::io;
 
 pub mod clients;
+pub mod currency;
 pub mod errors;
+pub mod fees;
+pub mod formats;
+pub mod input;
+pub mod interest;
+pub mod ledger;
+pub mod limits;
+pub mod policy;
+pub mod rates;
+pub mod rejects;
+pub mod risk;
+pub mod server;
+pub mod snapshot;
+pub mod storage;
 pub mod transactions;
+pub mod wal;
 
 use errors::TpsError;
-use transactions::Transaction;
+use transactions::{Position, Transaction};
 
 const DECIMAL_PRECISION: u32 = 4;
 
-pub struct CsvChunkedReader {
-    entries: csv::DeserializeRecordsIntoIter<std::fs::File, Transaction>,
+pub struct CsvChunkedReader<R: io::Read = Box<dyn io::Read>> {
+    reader: csv::Reader<RecordingReader<R>>,
+    headers: Option<csv::StringRecord>,
+    // Reused between rows to avoid an allocation per row
+    record: csv::StringRecord,
     chunk_size: usize,
 }
 
 impl CsvChunkedReader {
+    // Reads a file, or stdin when the filename is "-". Gzip and zstd compressed
+    // input is decompressed on the fly.
     pub fn new(filename: &str, chunk_size: usize) -> Result<Self, TpsError> {
-        let file = std::fs::File::open(filename)?;
+        let reader = input::open_input(filename)?;
+        Ok(Self::from_reader(reader, chunk_size))
+    }
+}
+
+impl<R: io::Read> CsvChunkedReader<R> {
+    // Reads from any source, e.g. a socket or an in-memory buffer
+    pub fn from_reader(reader: R, chunk_size: usize) -> Self {
         let reader = csv::ReaderBuilder::new()
             .trim(csv::Trim::All)
             .has_headers(true)
-            .from_reader(file);
+            .from_reader(RecordingReader::new(reader));
 
-        Ok(Self {
-            entries: reader.into_deserialize(),
+        Self {
+            reader,
+            headers: None,
+            record: csv::StringRecord::new(),
             chunk_size,
-        })
+        }
+    }
+
+    // Reads the next row along with where it starts in the input. A row that can't
+    // be read is passed along as `Transaction::unreadable`, only a failing input
+    // stops the reader.
+    fn next_transaction(&mut self) -> Result<Option<Transaction>, TpsError> {
+        let headers = match &self.headers {
+            Some(headers) => headers,
+            None => self.headers.insert(self.reader.headers()?.clone()),
+        };
+
+        let read = match self.reader.read_record(&mut self.record) {
+            Ok(false) => return Ok(None),
+            Ok(true) => self
+                .record
+                .deserialize::<Transaction>(Some(headers))
+                .map_err(|err| (row_error(&err), self.record.position().cloned())),
+            Err(err) if err.is_io_error() => return Err(err.into()),
+            Err(err) => Err((row_error(&err), err.position().cloned())),
+        };
+
+        let (mut txn, position) = match read {
+            Ok(txn) => (txn, self.record.position().cloned()),
+            Err((reason, position)) => (Transaction::unreadable(reason), position),
+        };
+        txn.position = position.map(|position| Position {
+            line: position.line(),
+            byte: position.byte(),
+        });
+
+        // The reader is now at the start of the next row, so the row is what was
+        // read in between
+        let end = self.reader.position().byte();
+        txn.raw = txn
+            .position
+            .map(|position| self.reader.get_mut().take(position.byte, end));
+
+        Ok(Some(txn))
+    }
+}
+
+// Why a row couldn't be read, without the position the report already has
+fn row_error(err: &csv::Error) -> String {
+    match err.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+        csv::ErrorKind::UnequalLengths {
+            expected_len, len, ..
+        } => format!("found {len} fields, expected {expected_len}"),
+        _ => err.to_string(),
+    }
+}
+
+// Keeps the bytes the csv reader has read but not yet handed out as a row, so
+// the rejected rows can be reported exactly as they were in the input
+struct RecordingReader<R> {
+    inner: R,
+    buffer: Vec<u8>,
+    // The offset in the input of the first byte in `buffer`
+    start: u64,
+}
+
+impl<R> RecordingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buffer: Vec::new(),
+            start: 0,
+        }
+    }
+
+    // The input between the offsets `from` and `to` without the line terminator.
+    // Everything before `to` is dropped, rows are only ever taken in order.
+    fn take(&mut self, from: u64, to: u64) -> String {
+        let from = (from.saturating_sub(self.start) as usize).min(self.buffer.len());
+        let to = (to.saturating_sub(self.start) as usize).min(self.buffer.len());
+
+        let row = String::from_utf8_lossy(&self.buffer[from..to])
+            .trim_end_matches(['\r', '\n'])
+            .to_string();
+
+        self.buffer.drain(..to);
+        self.start += to as u64;
+        row
+    }
+}
+
+impl<R: io::Read> io::Read for RecordingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.buffer.extend_from_slice(&buf[..read]);
+        Ok(read)
     }
 }
 
 // This allows the caller to iterate over the chunks of transactions
 // in a more memory efficient way. Also it's easier to adopt for
 // async/multi-threaded processing if needed
-impl Iterator for CsvChunkedReader {
+impl<R: io::Read> Iterator for CsvChunkedReader<R> {
     type Item = Result<Vec<Transaction>, TpsError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         let mut chunk = Vec::with_capacity(self.chunk_size);
 
-        for record in self.entries.by_ref() {
-            let txn = match record {
-                Ok(txn) => txn,
-                Err(err) => return Some(Err(TpsError::CsvError(err))),
+        loop {
+            let txn = match self.next_transaction() {
+                Ok(Some(txn)) => txn,
+                Ok(None) => break,
+                Err(err) => return Some(Err(err)),
             };
 
             chunk.push(txn);
@@ -79,5 +201,16 @@ pub fn read_whole_csv(filename: &str) -> Result<Vec<Transaction>, TpsError> {
 // because I would need to implement a lot of the std::ops traits for the NewType to be useful
 // and that is not worth it to just get the one easier displaying function
 pub fn decimal_to_string(decimal: Decimal) -> String {
-    format!("{:.4}", decimal.round_dp(DECIMAL_PRECISION))
+    format!("{:.4}", round_amount(decimal))
+}
+
+// Rounds an amount the engine computed (rather than read) to the precision it is shown with
+pub fn round_amount(decimal: Decimal) -> Decimal {
+    decimal.round_dp(DECIMAL_PRECISION)
+}
+
+// Like `round_amount`, but towards zero, so what is rounded off always has the
+// sign of the amount
+pub fn round_amount_down(decimal: Decimal) -> Decimal {
+    decimal.round_dp_with_strategy(DECIMAL_PRECISION, RoundingStrategy::ToZero)
 }
@@ -0,0 +1,186 @@
+use chrono::{Datelike, NaiveDate};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clients::{Client, ClientId, ClientList},
+    currency::Currency,
+    errors::TpsError,
+    storage::Storage,
+};
+
+// The limits a `limit` transaction can set, named in its `limit` column
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitKind {
+    Overdraft,
+    Daily,
+    Monthly,
+}
+
+// What a client may spend in one currency. The overdraft is how far below zero the
+// available funds (and the total) may go, and the daily and monthly limits cap what
+// withdrawals, transfers, and conversions take in a calendar day and month. The day
+// is the one of the row's timestamp, a row without one counts towards the day of
+// the client's last one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    #[serde(default)]
+    pub overdraft: Decimal,
+    #[serde(default)]
+    pub daily: Option<Decimal>,
+    #[serde(default)]
+    pub monthly: Option<Decimal>,
+    #[serde(default)]
+    pub spent: Spent,
+}
+
+// What was spent on `day`, and in its month
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Spent {
+    pub day: Option<NaiveDate>,
+    pub daily: Decimal,
+    pub monthly: Decimal,
+}
+
+impl Limits {
+    // Whether spending `amount` on `day` stays within the daily and monthly limits
+    pub fn allows(&self, amount: Decimal, day: Option<NaiveDate>) -> bool {
+        let spent = self.spent.on(day);
+
+        self.daily.is_none_or(|daily| spent.daily + amount <= daily)
+            && self
+                .monthly
+                .is_none_or(|monthly| spent.monthly + amount <= monthly)
+    }
+
+    pub fn spend(&mut self, amount: Decimal, day: Option<NaiveDate>) {
+        let mut spent = self.spent.on(day);
+        spent.daily += amount;
+        spent.monthly += amount;
+        self.spent = spent;
+    }
+
+    // Undoes a `spend`. A later spend may have started a new day or month since,
+    // the counts of one that has passed are gone anyway.
+    pub fn unspend(&mut self, amount: Decimal, day: Option<NaiveDate>) {
+        let day = day.or(self.spent.day);
+        let month = |day: Option<NaiveDate>| day.map(|day| (day.year(), day.month()));
+
+        if self.spent.day == day {
+            self.spent.daily -= amount;
+        }
+        if month(self.spent.day) == month(day) {
+            self.spent.monthly -= amount;
+        }
+    }
+}
+
+impl Spent {
+    // The counts as of `day`, which start over with every day and month
+    fn on(&self, day: Option<NaiveDate>) -> Spent {
+        let Some(day) = day else {
+            return *self;
+        };
+
+        match self.day {
+            Some(last) if last == day => *self,
+            Some(last) if (last.year(), last.month()) == (day.year(), day.month()) => Spent {
+                day: Some(day),
+                daily: Decimal::ZERO,
+                monthly: self.monthly,
+            },
+            _ => Spent {
+                day: Some(day),
+                ..Spent::default()
+            },
+        }
+    }
+}
+
+// One row of a limits file. Every limit the row leaves empty is removed.
+//
+//     client, currency, overdraft, daily, monthly
+//     7, USD, 500, 1000, 10000
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LimitEntry {
+    pub client: ClientId,
+    // Rows without one are for the default currency
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    #[serde(default)]
+    pub overdraft: Option<Decimal>,
+    #[serde(default)]
+    pub daily: Option<Decimal>,
+    #[serde(default)]
+    pub monthly: Option<Decimal>,
+}
+
+impl LimitEntry {
+    pub fn load(path: &str) -> Result<Vec<Self>, TpsError> {
+        let entries: Vec<Self> = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)?
+            .deserialize()
+            .collect::<Result<_, _>>()?;
+
+        for entry in &entries {
+            let amounts = [entry.overdraft, entry.daily, entry.monthly];
+            if amounts
+                .into_iter()
+                .flatten()
+                .any(|amount| amount < Decimal::ZERO)
+            {
+                return Err(TpsError::InvalidLimits(format!(
+                    "client {} has a negative limit",
+                    entry.client
+                )));
+            }
+        }
+
+        Ok(entries)
+    }
+
+    // Sets the limits, keeping what was already spent
+    pub fn apply(&self, client: &mut Client) {
+        let limits = client.limits_mut(self.currency.unwrap_or_default());
+        limits.overdraft = self.overdraft.unwrap_or_default();
+        limits.daily = self.daily;
+        limits.monthly = self.monthly;
+    }
+}
+
+// Applies the entries of a limits file to the clients in memory. A client that does
+// not exist yet is not created, it gets its limits when it is (see `Policy::with_limits`).
+pub fn apply_limits(entries: &[LimitEntry], clients: &mut ClientList) {
+    for entry in entries {
+        if let Some(client) = clients.get_client_mut(&entry.client) {
+            entry.apply(client);
+        }
+    }
+}
+
+// Applies the entries of a limits file to the clients in a database, all at once,
+// the same way `apply_limits` does
+pub fn apply_limits_to_storage(
+    entries: &[LimitEntry],
+    storage: &mut dyn Storage,
+) -> Result<(), TpsError> {
+    storage.begin()?;
+
+    let result = entries.iter().try_for_each(|entry| {
+        let Some(mut client) = storage.get_client(&entry.client)? else {
+            return Ok(());
+        };
+        entry.apply(&mut client);
+        storage.put_client(&client)
+    });
+
+    match result {
+        Ok(()) => storage.commit(),
+        Err(err) => {
+            let _ = storage.rollback();
+            Err(err)
+        }
+    }
+}
@@ -1,46 +1,465 @@
+use chrono::{DateTime, NaiveDate, Utc};
 use std::process;
 
 use tps2::{
     clients::ClientList,
     errors::TpsError,
-    transactions::{manager::TransactionManager, process::process_transactions},
-    CsvChunkedReader,
+    fees::write_fee_report,
+    formats::{Format, TransactionChunks},
+    interest::{check_end_of_day, write_interest_report},
+    ledger::{write_trial_balance, Ledger},
+    limits::{apply_limits, apply_limits_to_storage, LimitEntry},
+    policy::Policy,
+    rates::RateTable,
+    rejects::{RejectSink, RejectWriter, StderrRejects},
+    risk::write_review_report,
+    server::{http, tcp, EngineHandle},
+    snapshot::{save_snapshot, Snapshot},
+    storage::{process_with_storage, MemoryStorage, SqliteStorage, Storage},
+    transactions::{
+        lifecycle::StoredTransaction,
+        manager::{StoreLimits, TransactionManager},
+        parallel::ShardedProcessor,
+        process::process_transactions_reporting,
+        Transaction, TransactionError,
+    },
+    wal::WriteAheadLog,
 };
 
 // This is how many transactions we will read at a time,
 // the ideal configuration will depend on the characteristics of the problem
 const CHUNK_SIZE: usize = 100;
 
+const USAGE: &str = "Usage: cargo run -- [--wal <log_file>] [--resume-from <snapshot>] \
+[--snapshot <snapshot>] [--threads <count>] [--input-format csv|jsonl] \
+[--output-format csv|jsonl] [--rejects <report_file>] [--fees <report_file>] \
+[--review <report_file>] [--interest <report_file>] [--trial-balance <report_file>] [--policy <policy_file>] [--rates <rates_file>] [--limits <limits_file>] \
+[--accrue <yyyy-mm-dd>] [--as-of <timestamp>] [--memory-limit <count>] [--spill <spill_file>] [--dispute-horizon-rows <count>] \
+[--db <database>] <input_file>
+       cargo run -- [--resume-from <snapshot>] [--snapshot <snapshot>] [--output-format csv|jsonl] \
+[--fees <report_file>] [--review <report_file>] [--interest <report_file>] [--trial-balance <report_file>] [--as-of <timestamp>] \
+[--policy <policy_file>] [--rates <rates_file>] [--limits <limits_file>] [--memory-limit <count>] \
+[--spill <spill_file>] [--dispute-horizon-rows <count>] [--db <database>] serve <address>
+       cargo run -- [--resume-from <snapshot>] [--snapshot <snapshot>] [--output-format csv|jsonl] \
+[--fees <report_file>] [--review <report_file>] [--interest <report_file>] [--trial-balance <report_file>] [--as-of <timestamp>] \
+[--policy <policy_file>] [--rates <rates_file>] [--limits <limits_file>] [--memory-limit <count>] \
+[--spill <spill_file>] [--dispute-horizon-rows <count>] [--db <database>] http <address>";
+
+// The protocol a server mode speaks
+#[derive(Clone, Copy)]
+enum Server {
+    // CSV rows over plain TCP
+    Tcp,
+    // JSON over HTTP
+    Http,
+}
+
+#[derive(Default)]
+struct Options {
+    filename: Option<String>,
+    server: Option<(Server, String)>,
+    wal_path: Option<String>,
+    resume_path: Option<String>,
+    snapshot_path: Option<String>,
+    threads: Option<usize>,
+    input_format: Format,
+    output_format: Format,
+    rejects_path: Option<String>,
+    fees_path: Option<String>,
+    review_path: Option<String>,
+    interest_path: Option<String>,
+    trial_balance_path: Option<String>,
+    policy_path: Option<String>,
+    rates_path: Option<String>,
+    limits_path: Option<String>,
+    // Accrues interest up to this day once the input is processed
+    accrue: Option<NaiveDate>,
+    // The reports only cover what happened up to this time
+    as_of: Option<DateTime<Utc>>,
+    store_limits: StoreLimits,
+    db_path: Option<String>,
+}
+
 fn main() {
-    let mut args = std::env::args();
-    let _program_name = args.next();
+    let options = parse_args();
 
-    let Some(filename) = args.next() else {
-        eprintln!("Usage: cargo run -- <input_file.csv>");
+    // Start from the ending state of a previous run if one was given
+    let (mut clients, mut transactions) = match &options.resume_path {
+        Some(path) => match Snapshot::load(path) {
+            Ok(snapshot) => (snapshot.clients, snapshot.transactions),
+            Err(err) => {
+                eprintln!("Error loading snapshot {}: {}", path, err);
+                process::exit(1);
+            }
+        },
+        None => (ClientList::new(), TransactionManager::new()),
+    };
+
+    // Applied after resuming so the transactions from the snapshot are covered too
+    if let Err(err) = transactions.set_limits(options.store_limits.clone()) {
+        eprintln!("Error setting up the transaction store: {}", err);
         process::exit(1);
+    }
+
+    // Like a run with a dispute horizon, a snapshot taken with one is missing the
+    // journal entries of the transactions it dropped
+    if options.trial_balance_path.is_some() && transactions.journal_truncated() {
+        eprintln!(
+            "The snapshot {} was taken with --dispute-horizon-rows and doesn't have the whole \
+             journal, so there is no trial balance for it",
+            options.resume_path.as_deref().unwrap_or_default()
+        );
+        process::exit(1);
+    }
+
+    let policy = match &options.policy_path {
+        Some(path) => Policy::load(path).unwrap_or_else(|err| {
+            eprintln!("Error loading policy {}: {}", path, err);
+            process::exit(1);
+        }),
+        None => Policy::default(),
     };
 
-    let incoming_transactions = match CsvChunkedReader::new(&filename, CHUNK_SIZE) {
-        Ok(transactions) => transactions,
-        Err(TpsError::IoError(err)) => {
-            eprintln!("Error occurred when reading {}: {}", filename, err);
+    let policy = match &options.rates_path {
+        Some(path) => RateTable::load(path)
+            .and_then(|rates| policy.with_rates(rates))
+            .unwrap_or_else(|err| {
+                eprintln!("Error loading rates {}: {}", path, err);
+                process::exit(1);
+            }),
+        None => policy,
+    };
+
+    // A database holds the state itself and is used in place of the in-memory maps
+    let mut database = options.db_path.as_ref().map(|path| {
+        SqliteStorage::open(path).unwrap_or_else(|err| {
+            eprintln!("Error opening database {}: {}", path, err);
             process::exit(1);
+        })
+    });
+
+    // The file is applied on every start, so its clients always have the limits in it.
+    // Clients that don't exist yet get them from the policy when they are created.
+    let policy = match &options.limits_path {
+        Some(path) => {
+            let result = LimitEntry::load(path).and_then(|entries| {
+                match &mut database {
+                    Some(database) => apply_limits_to_storage(&entries, database)?,
+                    None => apply_limits(&entries, &mut clients),
+                }
+                Ok(entries)
+            });
+
+            match result {
+                Ok(entries) => policy.with_limits(entries),
+                Err(err) => {
+                    eprintln!("Error loading limits {}: {}", path, err);
+                    process::exit(1);
+                }
+            }
         }
-        Err(TpsError::CsvError(err)) => {
-            eprintln!("CSV parsing error encountered in {}: {}", filename, err);
+        None => policy,
+    };
+
+    let (clients, transactions) = match (&options.server, &options.filename, database) {
+        (Some((server, address)), _, database) => {
+            let storage: Box<dyn Storage + Send> = match database {
+                Some(database) => Box::new(database),
+                None => Box::new(MemoryStorage::new(clients, transactions)),
+            };
+            run_server(*server, address, storage, policy.clone())
+        }
+        (None, Some(filename), Some(database)) => {
+            process_file_into(filename, &options, Box::new(database), &policy)
+        }
+        (None, Some(filename), None) => {
+            process_file(filename, &options, clients, transactions, &policy)
+        }
+        (None, None, _) => usage(),
+    };
+
+    if let Some(path) = &options.snapshot_path {
+        if let Err(err) = save_snapshot(path, &clients, &transactions) {
+            eprintln!("Error saving snapshot {}: {}", path, err);
             process::exit(1);
         }
-        Err(err) => {
-            eprintln!("Error: {}", err);
+    }
+
+    if let Err(err) = options
+        .output_format
+        .write_clients(&clients, &mut std::io::stdout().lock())
+    {
+        eprintln!("Error writing accounts: {}", err);
+        process::exit(1);
+    }
+
+    // The end of day `--accrue` adds is refused when it would skip a day, like any
+    // other, but that is easy to miss among the rejected rows. The run still
+    // writes everything out, and then fails.
+    let missed_end_of_day =
+        options
+            .accrue
+            .and_then(|date| match check_end_of_day(&clients, &policy, date) {
+                Err(TransactionError::MissedEndOfDay(next)) => Some((date, next)),
+                _ => None,
+            });
+    if let Some((date, next)) = missed_end_of_day {
+        eprintln!(
+            "Interest was not accrued to {date}, {next} has no end of day yet and every day \
+             needs one. Accrue each day from {next} on first."
+        );
+    }
+
+    write_reports(&options, &clients, transactions);
+
+    if missed_end_of_day.is_some() {
+        process::exit(1);
+    }
+}
+
+// The reports are written from the stored transactions, so they cover every run
+// in the state. The transactions can only be read out once, so they are shared.
+fn write_reports(options: &Options, clients: &ClientList, mut transactions: TransactionManager) {
+    if options.trial_balance_path.is_none()
+        && options.fees_path.is_none()
+        && options.review_path.is_none()
+        && options.interest_path.is_none()
+    {
+        return;
+    }
+    let postings = transactions.take_postings();
+
+    if let Some(path) = &options.interest_path {
+        if let Err(err) = write_interest_report(path, &postings, options.as_of) {
+            eprintln!("Error writing interest report {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+
+    let transactions: Vec<StoredTransaction> = transactions
+        .into_iter()
+        .collect::<Result<_, _>>()
+        .unwrap_or_else(|err| {
+            eprintln!("Error reading stored transactions: {}", err);
+            process::exit(1);
+        });
+
+    if let Some(path) = &options.trial_balance_path {
+        let ledger = Ledger::as_of(&transactions, &postings, options.as_of);
+        if let Err(err) = write_trial_balance(path, &ledger) {
+            eprintln!("Error writing trial balance {}: {}", path, err);
+            process::exit(1);
+        }
+
+        if !ledger.is_balanced() {
+            eprintln!("The trial balance does not sum to zero");
+            process::exit(1);
+        }
+
+        // The clients are only known as they are now, so with --as-of they are
+        // checked against the whole journal rather than the balances written out
+        let mismatches = match options.as_of {
+            Some(_) => Ledger::from_transactions(&transactions, &postings).reconcile(clients),
+            None => ledger.reconcile(clients),
+        };
+        for (client_id, currency) in &mismatches {
+            eprintln!("Client {client_id} has other {currency} balances than the journal");
+        }
+        if !mismatches.is_empty() {
+            process::exit(1);
+        }
+    }
+
+    let transactions: Vec<StoredTransaction> = transactions
+        .into_iter()
+        .filter(|stored| stored.is_before(options.as_of))
+        .collect();
+
+    if let Some(path) = &options.review_path {
+        if let Err(err) = write_review_report(path, &transactions, options.as_of) {
+            eprintln!("Error writing review report {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+
+    if let Some(path) = &options.fees_path {
+        if let Err(err) = write_fee_report(path, transactions) {
+            eprintln!("Error writing fee report {}: {}", path, err);
             process::exit(1);
         }
+    }
+}
+
+fn parse_args() -> Options {
+    let mut args = std::env::args();
+    let _program_name = args.next();
+
+    let mut options = Options::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--wal" => options.wal_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--resume-from" => options.resume_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--snapshot" => options.snapshot_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--threads" => options.threads = Some(parse_count(args.next())),
+            "--input-format" => options.input_format = parse_format(args.next()),
+            "--output-format" => options.output_format = parse_format(args.next()),
+            "--rejects" => options.rejects_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--fees" => options.fees_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--review" => options.review_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--interest" => options.interest_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--trial-balance" => {
+                options.trial_balance_path = Some(args.next().unwrap_or_else(|| usage()))
+            }
+            "--policy" => options.policy_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--rates" => options.rates_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--limits" => options.limits_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--accrue" => options.accrue = Some(parse_date(args.next())),
+            "--as-of" => options.as_of = Some(parse_timestamp(args.next())),
+            "--memory-limit" => {
+                options.store_limits.memory_capacity = Some(parse_count(args.next()))
+            }
+            "--spill" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                options.store_limits.spill_path = Some(path.into());
+            }
+            "--db" => options.db_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--dispute-horizon-rows" => {
+                options.store_limits.dispute_horizon_rows = Some(parse_count(args.next()))
+            }
+            "serve" | "http" if options.filename.is_none() && options.server.is_none() => {
+                let server = if arg == "serve" {
+                    Server::Tcp
+                } else {
+                    Server::Http
+                };
+                options.server = Some((server, args.next().unwrap_or_else(|| usage())));
+            }
+            _ if options.filename.is_none() && options.server.is_none() => {
+                options.filename = Some(arg)
+            }
+            _ => usage(),
+        }
+    }
+
+    // These only make sense when reading from a file
+    if options.server.is_some()
+        && (options.wal_path.is_some()
+            || options.threads.is_some()
+            || options.rejects_path.is_some()
+            || options.accrue.is_some())
+    {
+        usage();
+    }
+
+    // The log rebuilds the state from nothing, a snapshot would apply its rows twice
+    if options.wal_path.is_some() && options.resume_path.is_some() {
+        usage();
+    }
+
+    // The shards each keep their own transactions, which the store limits don't cover
+    if options.threads.is_some()
+        && (options.store_limits.memory_capacity.is_some()
+            || options.store_limits.spill_path.is_some()
+            || options.store_limits.dispute_horizon_rows.is_some())
+    {
+        usage();
+    }
+
+    // The database already keeps every change, and the state is in the database
+    // rather than split across shards or a snapshot
+    if options.db_path.is_some()
+        && (options.wal_path.is_some()
+            || options.threads.is_some()
+            || options.resume_path.is_some()
+            || options.store_limits.memory_capacity.is_some()
+            || options.store_limits.dispute_horizon_rows.is_some())
+    {
+        usage();
+    }
+
+    // The journal of a transaction is dropped along with it, so the books can't be checked
+    if options.trial_balance_path.is_some() && options.store_limits.dispute_horizon_rows.is_some() {
+        usage();
+    }
+
+    // A spill file is only used when memory is limited
+    if options.store_limits.spill_path.is_some() && options.store_limits.memory_capacity.is_none() {
+        usage();
+    }
+
+    options
+}
+
+fn parse_count(arg: Option<String>) -> usize {
+    arg.and_then(|count| count.parse::<usize>().ok())
+        .unwrap_or_else(|| usage())
+}
+
+fn parse_date(arg: Option<String>) -> NaiveDate {
+    arg.and_then(|date| date.parse::<NaiveDate>().ok())
+        .unwrap_or_else(|| usage())
+}
+
+fn parse_timestamp(arg: Option<String>) -> DateTime<Utc> {
+    arg.and_then(|timestamp| DateTime::parse_from_rfc3339(&timestamp).ok())
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .unwrap_or_else(|| usage())
+}
+
+fn process_file(
+    filename: &str,
+    options: &Options,
+    mut clients: ClientList,
+    mut transactions: TransactionManager,
+    policy: &Policy,
+) -> (ClientList, TransactionManager) {
+    let incoming_transactions = read_input(filename, options);
+
+    // Rebuild the state from the log, the rows it holds are skipped in the input
+    let mut wal = options.wal_path.as_ref().map(|path| {
+        let mut wal = WriteAheadLog::open(path).unwrap_or_else(|err| {
+            eprintln!("Error opening write-ahead log {}: {}", path, err);
+            process::exit(1);
+        });
+
+        if let Err(err) = wal.replay(CHUNK_SIZE, &mut clients, &mut transactions, policy) {
+            eprintln!("Error replaying write-ahead log {}: {}", path, err);
+            process::exit(1);
+        }
+
+        wal
+    });
+
+    // A resumed run adds to the report of the run it continues, the replayed
+    // rows were reported then
+    let resumed = wal.as_ref().is_some_and(|wal| !wal.is_empty());
+    let mut rejects: Box<dyn RejectSink + Send> = match &options.rejects_path {
+        Some(path) => Box::new(RejectWriter::create(path, resumed).unwrap_or_else(|err| {
+            eprintln!("Error opening reject report {}: {}", path, err);
+            process::exit(1);
+        })),
+        None => Box::new(StderrRejects),
     };
 
-    let mut clients = ClientList::new();
-    let mut transactions = TransactionManager::new();
+    // The state is split across the worker threads until the input is done,
+    // the workers then own the reject report
+    let mut sharded = options.threads.map(|threads| {
+        ShardedProcessor::with_reject_sink(
+            threads,
+            std::mem::take(&mut clients),
+            std::mem::take(&mut transactions),
+            policy.clone(),
+            std::mem::replace(&mut rejects, Box::new(StderrRejects)),
+        )
+        .unwrap_or_else(|err| {
+            eprintln!("Error splitting the state across threads: {}", err);
+            process::exit(1);
+        })
+    });
 
     for chunk in incoming_transactions {
-        let chunk = match chunk {
+        let mut chunk = match chunk {
             Ok(chunk) => chunk,
             Err(err) => {
                 eprintln!("Error reading chunk: {}", err);
@@ -48,11 +467,185 @@ fn main() {
             }
         };
 
-        if let Err(err) = process_transactions(chunk, &mut clients, &mut transactions) {
+        if let Some(wal) = wal.as_mut() {
+            if let Err(err) = wal.skip_logged(&mut chunk) {
+                eprintln!("Error resuming from write-ahead log: {}", err);
+                process::exit(1);
+            }
+
+            if let Err(err) = wal.append(&chunk) {
+                eprintln!("Error writing to write-ahead log: {}", err);
+                process::exit(1);
+            }
+        }
+
+        let result = match sharded.as_mut() {
+            Some(sharded) => sharded.process(chunk),
+            None => process_transactions_reporting(
+                chunk,
+                &mut clients,
+                &mut transactions,
+                policy,
+                rejects.as_mut(),
+            ),
+        };
+
+        if let Err(err) = result {
+            eprintln!("Error processing transactions: {}", err);
+            process::exit(1);
+        }
+    }
+
+    let state = match sharded {
+        Some(sharded) => sharded.finish().unwrap_or_else(|err| {
+            eprintln!("Error processing transactions: {}", err);
+            process::exit(1);
+        }),
+        None => {
+            if let Err(err) = rejects.flush() {
+                eprintln!("Error writing reject report: {}", err);
+                process::exit(1);
+            }
+
+            (clients, transactions)
+        }
+    };
+
+    // Every row is processed, so a rerun starts over rather than resuming
+    if let Some(wal) = wal {
+        if let Err(err) = wal.finish() {
+            eprintln!("Error finishing write-ahead log: {}", err);
+            process::exit(1);
+        }
+    }
+
+    state
+}
+
+// Processes the file with the state in a database, every row is committed before the next
+fn process_file_into(
+    filename: &str,
+    options: &Options,
+    mut storage: Box<dyn Storage>,
+    policy: &Policy,
+) -> (ClientList, TransactionManager) {
+    let incoming_transactions = read_input(filename, options);
+
+    // Rows already in the database were reported by the run that stored them
+    let mut rejects: Box<dyn RejectSink> = match &options.rejects_path {
+        Some(path) => Box::new(RejectWriter::create(path, true).unwrap_or_else(|err| {
+            eprintln!("Error opening reject report {}: {}", path, err);
+            process::exit(1);
+        })),
+        None => Box::new(StderrRejects),
+    };
+
+    for chunk in incoming_transactions {
+        let chunk = chunk.unwrap_or_else(|err| {
+            eprintln!("Error reading chunk: {}", err);
+            process::exit(1);
+        });
+
+        if let Err(err) = process_with_storage(chunk, storage.as_mut(), policy, rejects.as_mut()) {
             eprintln!("Error processing transactions: {}", err);
             process::exit(1);
         }
     }
 
-    println!("{clients}");
+    if let Err(err) = rejects.flush() {
+        eprintln!("Error writing reject report: {}", err);
+        process::exit(1);
+    }
+
+    storage.into_state().unwrap_or_else(|err| {
+        eprintln!("Error reading database: {}", err);
+        process::exit(1);
+    })
+}
+
+// The rows of the file, followed by an end of day row if `--accrue` was given
+fn read_input(filename: &str, options: &Options) -> TransactionChunks {
+    match options.input_format.reader(filename, CHUNK_SIZE) {
+        Ok(transactions) => match options.accrue {
+            Some(date) => Box::new(
+                transactions.chain(std::iter::once(Ok(vec![Transaction::end_of_day(date)]))),
+            ),
+            None => transactions,
+        },
+        Err(TpsError::IoError(err)) => {
+            eprintln!("Error occurred when reading {}: {}", filename, err);
+            process::exit(1);
+        }
+        Err(TpsError::CsvError(err)) => {
+            eprintln!("CSV parsing error encountered in {}: {}", filename, err);
+            process::exit(1);
+        }
+        Err(TpsError::JsonError(err)) => {
+            eprintln!("JSON parsing error encountered in {}: {}", filename, err);
+            process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+// Serves connections until ctrl-c, then returns the state at that point
+fn run_server(
+    server: Server,
+    address: &str,
+    storage: Box<dyn Storage + Send>,
+    policy: Policy,
+) -> (ClientList, TransactionManager) {
+    let runtime = tokio::runtime::Runtime::new().unwrap_or_else(|err| {
+        eprintln!("Error starting runtime: {}", err);
+        process::exit(1);
+    });
+
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind(address)
+            .await
+            .unwrap_or_else(|err| {
+                eprintln!("Error listening on {}: {}", address, err);
+                process::exit(1);
+            });
+
+        let engine = EngineHandle::with_storage(storage, policy);
+
+        let serve = async {
+            match server {
+                Server::Tcp => tcp::serve(listener, engine.clone()).await,
+                Server::Http => http::serve(listener, engine.clone()).await,
+            }
+        };
+
+        tokio::select! {
+            result = serve => {
+                if let Err(err) = result {
+                    eprintln!("Error accepting connections: {}", err);
+                    process::exit(1);
+                }
+            }
+            _ = tokio::signal::ctrl_c() => {}
+        }
+
+        engine.shutdown().await.unwrap_or_else(|err| {
+            eprintln!("Error stopping engine: {}", err);
+            process::exit(1);
+        })
+    })
+}
+
+fn parse_format(format: Option<String>) -> Format {
+    let format = format.unwrap_or_else(|| usage());
+    format.parse().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        usage();
+    })
+}
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    process::exit(1);
 }
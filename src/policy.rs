@@ -0,0 +1,322 @@
+use chrono::TimeDelta;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::{
+    clients::ClientId,
+    currency::Currency,
+    errors::TpsError,
+    fees::{Fee, FeeRule},
+    interest::InterestRule,
+    limits::LimitEntry,
+    rates::RateTable,
+    risk::RiskRule,
+    transactions::{OperatorId, Transaction, TransactionType},
+};
+
+// The business rules that differ between the networks we settle with. The
+// defaults are the rules the engine has always used, so an empty policy file
+// (or `Policy::default()`) changes nothing.
+//
+// A policy can be read from a TOML file:
+//
+//     disputable_types = ["deposit", "transfer", "withdrawal"]
+//     chargeback_locks_account = true
+//     allow_negative_balance = false
+//     dispute_window_days = 120
+//     withdrawal_creates_client = true
+//     admin_operators = [1, 2]
+//     house_account = 9000
+//
+//     [client_groups]
+//     retail = [1, 2, 3]
+//
+//     [[fees]]
+//     type = "withdrawal"
+//     flat = "0.50"
+//
+//     [[interest]]
+//     tiers = [{ from = "0", rate = "1.5" }]
+//
+// or built in code with `Policy::builder()`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    // The stored transaction types that a dispute can hold funds for. Withdrawals
+    // are left out by default, disputing them has to be asked for.
+    disputable_types: Vec<TransactionType>,
+    // Whether a chargeback locks the client's account
+    chargeback_locks_account: bool,
+    // Whether a dispute (and then a chargeback) can take a client's balance below
+    // zero, instead of failing with `InsufficientFunds`
+    allow_negative_balance: bool,
+    // How long after a transaction it can still be disputed, in days. Without one a
+    // transaction can be disputed at any time.
+    dispute_window_days: Option<u32>,
+    // Whether a withdrawal from an unknown client creates it,
+    // instead of failing with `MissingClient`
+    withdrawal_creates_client: bool,
+    // The operators allowed to make administrative transactions. None are by
+    // default, so naming an operator isn't enough to change an account.
+    admin_operators: Vec<OperatorId>,
+    // The client that fees are credited to, it is needed when there are fees
+    house_account: Option<ClientId>,
+    // Named sets of clients that fee and interest rules can be limited to
+    client_groups: HashMap<String, Vec<ClientId>>,
+    // The first rule that matches a transaction sets its fee (see `FeeRule`)
+    fees: Vec<FeeRule>,
+    // Checked before every transaction is applied (see `RiskRule`)
+    risk_rules: Vec<RiskRule>,
+    // The first rule that matches a client's currency sets the interest it earns
+    // at the end of a day (see `InterestRule`)
+    interest: Vec<InterestRule>,
+    // The exchange rates for conversions, these come from their own file (see `with_rates`)
+    #[serde(skip)]
+    rates: RateTable,
+    // The limits a client gets when it is created, from the limits file (see `with_limits`)
+    #[serde(skip)]
+    limits: Vec<LimitEntry>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            disputable_types: vec![TransactionType::Deposit, TransactionType::Transfer],
+            chargeback_locks_account: true,
+            allow_negative_balance: false,
+            dispute_window_days: None,
+            withdrawal_creates_client: true,
+            admin_operators: Vec::new(),
+            house_account: None,
+            client_groups: HashMap::new(),
+            fees: Vec::new(),
+            risk_rules: Vec::new(),
+            interest: Vec::new(),
+            rates: RateTable::default(),
+            limits: Vec::new(),
+        }
+    }
+}
+
+impl Policy {
+    pub fn builder() -> PolicyBuilder {
+        PolicyBuilder::default()
+    }
+
+    pub fn load(path: &str) -> Result<Self, TpsError> {
+        let contents = std::fs::read_to_string(path)?;
+        let policy: Policy = toml::from_str(&contents)?;
+        policy.check()?;
+
+        Ok(policy)
+    }
+
+    pub fn is_disputable(&self, transaction: &Transaction) -> bool {
+        self.disputable_types.contains(&transaction.tx_type)
+    }
+
+    pub fn chargeback_locks_account(&self) -> bool {
+        self.chargeback_locks_account
+    }
+
+    pub fn allow_negative_balance(&self) -> bool {
+        self.allow_negative_balance
+    }
+
+    pub fn dispute_window(&self) -> Option<TimeDelta> {
+        self.dispute_window_days
+            .map(|days| TimeDelta::days(i64::from(days)))
+    }
+
+    pub fn withdrawal_creates_client(&self) -> bool {
+        self.withdrawal_creates_client
+    }
+
+    pub fn house_account(&self) -> Option<ClientId> {
+        self.house_account
+    }
+
+    // The fee the transaction's client pays for it, if any. The house account
+    // never pays fees, they would only go back to itself.
+    pub fn fee_for(&self, transaction: &Transaction) -> Option<Fee> {
+        let house_account = self.house_account?;
+        if transaction.client_id == house_account {
+            return None;
+        }
+
+        let amount = transaction.amount?;
+        let rule = self
+            .fees
+            .iter()
+            .find(|rule| rule.matches(transaction, &self.client_groups))?;
+
+        let fee = rule.fee(amount);
+        (fee > Decimal::ZERO).then_some(Fee {
+            amount: fee,
+            client_id: transaction.client_id,
+            currency: transaction.currency(),
+            house_account,
+        })
+    }
+
+    pub fn risk_rules(&self) -> &[RiskRule] {
+        &self.risk_rules
+    }
+
+    pub fn interest_for(&self, client_id: ClientId, currency: Currency) -> Option<&InterestRule> {
+        self.interest
+            .iter()
+            .find(|rule| rule.matches(client_id, currency, &self.client_groups))
+    }
+
+    pub fn rates(&self) -> &RateTable {
+        &self.rates
+    }
+
+    // Rates change far more often than the rules, so they are loaded separately
+    pub fn with_rates(mut self, rates: RateTable) -> Result<Self, TpsError> {
+        self.rates = rates;
+        self.check()?;
+
+        Ok(self)
+    }
+
+    // The limits file sets the limits of the clients that already exist when it is
+    // loaded (see `apply_limits`), the others get theirs from here once they are created
+    pub fn with_limits(mut self, limits: Vec<LimitEntry>) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits_for(&self, client_id: ClientId) -> impl Iterator<Item = &LimitEntry> {
+        self.limits
+            .iter()
+            .filter(move |entry| entry.client == client_id)
+    }
+
+    // An administrative transaction has to name an operator the policy allows
+    pub fn is_authorized(&self, transaction: &Transaction) -> bool {
+        transaction
+            .operator_id
+            .is_some_and(|operator_id| self.admin_operators.contains(&operator_id))
+    }
+
+    // Only transactions that moved money between the client and the outside can be disputed
+    fn check(&self) -> Result<(), TpsError> {
+        for tx_type in &self.disputable_types {
+            if !tx_type.is_stored() || tx_type.is_admin() || *tx_type == TransactionType::Convert {
+                return Err(TpsError::InvalidPolicy(format!(
+                    "{tx_type:?} transactions can't be disputed"
+                )));
+            }
+        }
+
+        if self.dispute_window_days == Some(0) {
+            return Err(TpsError::InvalidPolicy(
+                "dispute_window_days has to be at least 1".to_string(),
+            ));
+        }
+
+        if !self.fees.is_empty() && self.house_account.is_none() {
+            return Err(TpsError::InvalidPolicy(
+                "fees need a house_account".to_string(),
+            ));
+        }
+
+        // What the rounding of conversions takes off goes to the house
+        if !self.rates.is_empty() && self.house_account.is_none() {
+            return Err(TpsError::InvalidPolicy(
+                "conversion rates need a house_account".to_string(),
+            ));
+        }
+
+        for rule in &self.fees {
+            rule.check(&self.client_groups)?;
+        }
+
+        for rule in &self.risk_rules {
+            rule.check()?;
+        }
+
+        for rule in &self.interest {
+            rule.check(&self.client_groups)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct PolicyBuilder {
+    policy: Policy,
+}
+
+impl PolicyBuilder {
+    pub fn disputable_types(mut self, types: impl IntoIterator<Item = TransactionType>) -> Self {
+        self.policy.disputable_types = types.into_iter().collect();
+        self
+    }
+
+    pub fn chargeback_locks_account(mut self, locks: bool) -> Self {
+        self.policy.chargeback_locks_account = locks;
+        self
+    }
+
+    pub fn allow_negative_balance(mut self, allow: bool) -> Self {
+        self.policy.allow_negative_balance = allow;
+        self
+    }
+
+    pub fn dispute_window_days(mut self, days: u32) -> Self {
+        self.policy.dispute_window_days = Some(days);
+        self
+    }
+
+    pub fn withdrawal_creates_client(mut self, creates: bool) -> Self {
+        self.policy.withdrawal_creates_client = creates;
+        self
+    }
+
+    pub fn admin_operators(mut self, operators: impl IntoIterator<Item = OperatorId>) -> Self {
+        self.policy.admin_operators = operators.into_iter().collect();
+        self
+    }
+
+    pub fn house_account(mut self, client_id: ClientId) -> Self {
+        self.policy.house_account = Some(client_id);
+        self
+    }
+
+    pub fn client_group(mut self, name: &str, clients: impl IntoIterator<Item = ClientId>) -> Self {
+        self.policy
+            .client_groups
+            .insert(name.to_string(), clients.into_iter().collect());
+        self
+    }
+
+    pub fn fee(mut self, rule: FeeRule) -> Self {
+        self.policy.fees.push(rule);
+        self
+    }
+
+    pub fn risk_rule(mut self, rule: RiskRule) -> Self {
+        self.policy.risk_rules.push(rule);
+        self
+    }
+
+    pub fn interest(mut self, rule: InterestRule) -> Self {
+        self.policy.interest.push(rule);
+        self
+    }
+
+    pub fn rates(mut self, rates: RateTable) -> Self {
+        self.policy.rates = rates;
+        self
+    }
+
+    pub fn build(self) -> Result<Policy, TpsError> {
+        self.policy.check()?;
+        Ok(self.policy)
+    }
+}
@@ -0,0 +1,106 @@
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::{collections::HashMap, fs::File, io::BufReader, path::Path};
+
+use crate::{currency::Currency, errors::TpsError};
+
+// One row of a rates file: one unit of `from` buys `rate` units of `to`, starting at `timestamp`
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct RateEntry {
+    pub from: Currency,
+    pub to: Currency,
+    pub rate: Decimal,
+    pub timestamp: DateTime<Utc>,
+}
+
+// When each rate of a pair started to apply, oldest first
+type RateHistory = Vec<(DateTime<Utc>, Decimal)>;
+
+// The exchange rates used by `convert` transactions. Every currency pair keeps
+// its history, so the rate that applied at a given time can be looked up.
+//
+// A rates file is CSV:
+//
+//     from,to,rate,timestamp
+//     EUR,USD,1.0850,2024-05-01T00:00:00Z
+//
+// or, when it ends in `.json`, an array of objects with the same fields.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateTable {
+    rates: HashMap<(Currency, Currency), RateHistory>,
+}
+
+impl RateTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, TpsError> {
+        let path = path.as_ref();
+
+        let entries: Vec<RateEntry> = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_reader(BufReader::new(File::open(path)?))?,
+            _ => csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_path(path)?
+                .deserialize()
+                .collect::<Result<_, _>>()?,
+        };
+
+        let mut table = Self::new();
+        for entry in entries {
+            table.insert(entry)?;
+        }
+
+        Ok(table)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rates.is_empty()
+    }
+
+    pub fn insert(&mut self, entry: RateEntry) -> Result<(), TpsError> {
+        if entry.rate <= Decimal::from(0) {
+            return Err(TpsError::InvalidRate(format!(
+                "{} to {} at {} is not positive",
+                entry.from, entry.to, entry.timestamp
+            )));
+        }
+
+        if entry.from == entry.to {
+            return Err(TpsError::InvalidRate(format!(
+                "{} can't be converted to itself",
+                entry.from
+            )));
+        }
+
+        let history = self.rates.entry((entry.from, entry.to)).or_default();
+        let index = history.partition_point(|(timestamp, _)| *timestamp <= entry.timestamp);
+        history.insert(index, (entry.timestamp, entry.rate));
+
+        Ok(())
+    }
+
+    // The rate from one currency to another that applied at `at`, or the latest one
+    // without a time. Only the rates given for the other direction are inverted.
+    pub fn rate(&self, from: Currency, to: Currency, at: Option<DateTime<Utc>>) -> Option<Decimal> {
+        if let Some(rate) = self.lookup(from, to, at) {
+            return Some(rate);
+        }
+
+        self.lookup(to, from, at)
+            .and_then(|rate| Decimal::from(1).checked_div(rate))
+    }
+
+    fn lookup(&self, from: Currency, to: Currency, at: Option<DateTime<Utc>>) -> Option<Decimal> {
+        let history = self.rates.get(&(from, to))?;
+
+        let applies = match at {
+            Some(at) => history.partition_point(|(timestamp, _)| *timestamp <= at),
+            None => history.len(),
+        };
+
+        applies.checked_sub(1).map(|index| history[index].1)
+    }
+}
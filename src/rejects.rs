@@ -0,0 +1,119 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{
+    clients::{Client, ClientId},
+    currency::Currency,
+    decimal_to_string,
+    errors::TpsError,
+    formats::ReportWriter,
+    transactions::{Transaction, TransactionError, TransactionId, TransactionType},
+};
+
+// Receives every transaction that was rejected during processing
+pub trait RejectSink {
+    // `client` is the state of the transaction's client right after the rejection
+    fn reject(
+        &mut self,
+        transaction: &Transaction,
+        error: &TransactionError,
+        client: Option<&Client>,
+    ) -> Result<(), TpsError>;
+
+    fn flush(&mut self) -> Result<(), TpsError> {
+        Ok(())
+    }
+}
+
+// Logs rejections to stderr, this is what happens when no report file is used
+pub struct StderrRejects;
+
+impl RejectSink for StderrRejects {
+    fn reject(
+        &mut self,
+        transaction: &Transaction,
+        error: &TransactionError,
+        _client: Option<&Client>,
+    ) -> Result<(), TpsError> {
+        eprintln!("Error processing transaction: {transaction:?}, error: {error}");
+        Ok(())
+    }
+}
+
+// One row of the report: where the row came from, its fields, why it was
+// rejected, the client's balances at that point, and the row as it was read
+#[derive(Serialize)]
+struct RejectRecord {
+    line: Option<u64>,
+    byte: Option<u64>,
+    #[serde(rename = "type")]
+    tx_type: Option<TransactionType>,
+    client: Option<ClientId>,
+    tx: Option<TransactionId>,
+    amount: Option<Decimal>,
+    to: Option<ClientId>,
+    code: &'static str,
+    reason: String,
+    available: Option<String>,
+    held: Option<String>,
+    total: Option<String>,
+    locked: Option<bool>,
+    // Last so the columns before it stay where they were before currencies
+    currency: Option<Currency>,
+    // The row exactly as it was read, when it came from a file
+    row: Option<String>,
+}
+
+// Writes rejected transactions to a file that can be handed back upstream
+pub struct RejectWriter {
+    output: ReportWriter,
+}
+
+impl RejectWriter {
+    // With `append` the rows are added to an existing report, e.g. when resuming a run
+    pub fn create(path: &str, append: bool) -> Result<Self, TpsError> {
+        Ok(Self {
+            output: ReportWriter::create(path, append)?,
+        })
+    }
+}
+
+impl RejectSink for RejectWriter {
+    fn reject(
+        &mut self,
+        transaction: &Transaction,
+        error: &TransactionError,
+        client: Option<&Client>,
+    ) -> Result<(), TpsError> {
+        // Of a row that couldn't be read only the position and the row are known
+        let fields = transaction.unreadable.is_none().then_some(transaction);
+        let client = fields.and(client);
+
+        // The balances in the currency of the row, which is what it was checked against
+        let balance = client.map(|client| client.balance(transaction.currency()));
+
+        let record = RejectRecord {
+            line: transaction.position.map(|position| position.line),
+            byte: transaction.position.map(|position| position.byte),
+            tx_type: fields.map(|fields| fields.tx_type),
+            client: fields.map(|fields| fields.client_id),
+            tx: fields.map(|fields| fields.tx_id),
+            amount: transaction.amount,
+            to: transaction.to_client_id,
+            code: error.code(),
+            reason: error.to_string(),
+            available: balance.map(|balance| decimal_to_string(balance.available)),
+            held: balance.map(|balance| decimal_to_string(balance.held)),
+            total: balance.map(|balance| decimal_to_string(balance.total)),
+            locked: client.map(|client| client.locked),
+            currency: fields.map(Transaction::currency),
+            row: transaction.raw.clone(),
+        };
+
+        self.output.write(&record)
+    }
+
+    fn flush(&mut self) -> Result<(), TpsError> {
+        self.output.flush()
+    }
+}
@@ -0,0 +1,369 @@
+use chrono::{DateTime, Duration, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+use crate::{
+    clients::{ClientId, ClientList},
+    currency::Currency,
+    decimal_to_string,
+    errors::TpsError,
+    formats::write_report,
+    transactions::{
+        lifecycle::StoredTransaction, Transaction, TransactionError, TransactionId, TransactionType,
+    },
+};
+
+// The kinds of behavior a risk rule looks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RiskKind {
+    // More than `count` transactions of `type` within the window
+    Velocity,
+    // An amount above `above`, for transactions of `type` or any type with an amount
+    Amount,
+    // A withdrawal of at least what the client's previous transaction deposited
+    DepositThenWithdrawal,
+    // More than `count` disputes within the window
+    RepeatedDisputes,
+}
+
+// What happens to a transaction that a rule matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RiskAction {
+    // Skips the rules after this one, e.g. to exempt some transactions
+    Accept,
+    // Fails the transaction with `TransactionError::RiskRejected`
+    Reject,
+    // Lets the transaction through, but keeps a `Flag` on the client for review
+    Flag,
+}
+
+// A rule that is checked before every transaction is applied. The rules are
+// checked in order, and the first one that accepts or rejects a transaction
+// decides, flags don't stop the rules after them.
+//
+//     [[risk_rules]]
+//     name = "withdrawal burst"
+//     rule = "velocity"
+//     type = "withdrawal"
+//     count = 3
+//     rows = 10
+//     action = "reject"
+//
+// A window is the client's last `rows` transactions (this one included), or the
+// last `seconds` before this one. Only transactions that went through count. The
+// seconds are those between the timestamps of the rows, so a rule with `seconds`
+// skips a row without one, and rows without one never count for it.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RiskRule {
+    // Shown in rejections and flags, the kind of rule is used without one
+    #[serde(default)]
+    pub name: Option<String>,
+    pub rule: RiskKind,
+    pub action: RiskAction,
+    #[serde(rename = "type", default)]
+    pub tx_type: Option<TransactionType>,
+    #[serde(default)]
+    pub count: Option<usize>,
+    #[serde(default)]
+    pub rows: Option<u64>,
+    #[serde(default)]
+    pub seconds: Option<u64>,
+    #[serde(default)]
+    pub above: Option<Decimal>,
+}
+
+impl RiskRule {
+    pub fn name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => match self.rule {
+                RiskKind::Velocity => "velocity",
+                RiskKind::Amount => "amount",
+                RiskKind::DepositThenWithdrawal => "deposit_then_withdrawal",
+                RiskKind::RepeatedDisputes => "repeated_disputes",
+            }
+            .to_string(),
+        }
+    }
+
+    fn matches(&self, transaction: &Transaction, state: &RiskState) -> bool {
+        if self.seconds.is_some() && transaction.timestamp.is_none() {
+            return false;
+        }
+
+        let at = transaction.timestamp;
+        match self.rule {
+            RiskKind::Velocity => {
+                self.tx_type == Some(transaction.tx_type)
+                    && self.recent(transaction.tx_type, state, at) >= self.count.unwrap_or(0)
+            }
+            RiskKind::RepeatedDisputes => {
+                transaction.tx_type == TransactionType::Dispute
+                    && self.recent(TransactionType::Dispute, state, at) >= self.count.unwrap_or(0)
+            }
+            RiskKind::Amount => {
+                self.tx_type
+                    .is_none_or(|tx_type| tx_type == transaction.tx_type)
+                    && transaction
+                        .amount
+                        .zip(self.above)
+                        .is_some_and(|(amount, above)| amount > above)
+            }
+            RiskKind::DepositThenWithdrawal => {
+                transaction.tx_type == TransactionType::Withdrawal
+                    && state.recent.back().is_some_and(|last| {
+                        last.tx_type == TransactionType::Deposit
+                            && last.currency == transaction.currency()
+                            && last.amount.is_some_and(|deposited| {
+                                transaction.amount.is_some_and(|amount| amount >= deposited)
+                            })
+                    })
+            }
+        }
+    }
+
+    // How many transactions of the type the client made within the rule's window
+    fn recent(
+        &self,
+        tx_type: TransactionType,
+        state: &RiskState,
+        at: Option<DateTime<Utc>>,
+    ) -> usize {
+        state
+            .recent
+            .iter()
+            .filter(|activity| activity.tx_type == tx_type)
+            .filter(|activity| {
+                self.rows
+                    .is_none_or(|rows| activity.row + rows > state.rows)
+            })
+            .filter(|activity| {
+                self.seconds
+                    .is_none_or(|seconds| in_window(activity.at, at, seconds))
+            })
+            .count()
+    }
+
+    pub fn check(&self) -> Result<(), TpsError> {
+        let invalid = |reason: &str| {
+            Err(TpsError::InvalidPolicy(format!(
+                "risk rule {} {reason}",
+                self.name()
+            )))
+        };
+
+        let has_window = self.rows.is_some() || self.seconds.is_some();
+
+        match self.rule {
+            RiskKind::Velocity if self.tx_type.is_none() => invalid("needs a type"),
+            RiskKind::Velocity | RiskKind::RepeatedDisputes if self.count.is_none() => {
+                invalid("needs a count")
+            }
+            RiskKind::Velocity | RiskKind::RepeatedDisputes if !has_window => {
+                invalid("needs rows or seconds")
+            }
+            RiskKind::Amount if self.above.is_none() => invalid("needs an amount above"),
+            RiskKind::RepeatedDisputes | RiskKind::DepositThenWithdrawal
+                if self.tx_type.is_some() =>
+            {
+                invalid("can't have a type")
+            }
+            _ if self.rows == Some(0) => invalid("needs at least one row"),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Checks the transaction against the rules, returning the flags of the rules that
+// flagged it, or the rejection if one rejected it. A new client has no history.
+pub fn screen(
+    rules: &[RiskRule],
+    transaction: &Transaction,
+    clients: &ClientList,
+) -> Result<Vec<Flag>, TransactionError> {
+    let mut flags = Vec::new();
+
+    let new_client = RiskState::default();
+    let state = clients
+        .get_client(&transaction.client_id)
+        .map_or(&new_client, |client| &client.risk);
+
+    for rule in rules {
+        if !rule.matches(transaction, state) {
+            continue;
+        }
+
+        match rule.action {
+            RiskAction::Accept => break,
+            RiskAction::Reject => {
+                return Err(TransactionError::RiskRejected(
+                    transaction.tx_id,
+                    rule.name(),
+                ))
+            }
+            RiskAction::Flag => flags.push(Flag::new(transaction, rule.name())),
+        }
+    }
+
+    Ok(flags)
+}
+
+// Records a transaction that went through with the client that made it
+pub fn record(rules: &[RiskRule], transaction: &Transaction, clients: &mut ClientList) {
+    if let Some(client) = clients.get_client_mut(&transaction.client_id) {
+        client.risk.record(rules, transaction);
+    }
+}
+
+// A transaction that went through, as far as the rules are concerned
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Activity {
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    pub amount: Option<Decimal>,
+    pub currency: Currency,
+    // How many transactions of the client came before this one
+    pub row: u64,
+    // The timestamp of the row, if it had one
+    #[serde(default)]
+    pub at: Option<DateTime<Utc>>,
+}
+
+// A rule that flagged a transaction for review. Flags are kept with the stored
+// transaction they are about (in `flags`), not with the client.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Flag {
+    // The client of the flagged row
+    pub client: ClientId,
+    #[serde(rename = "type")]
+    pub tx_type: TransactionType,
+    pub rule: String,
+    pub amount: Option<Decimal>,
+    pub currency: Currency,
+    #[serde(default)]
+    pub at: Option<DateTime<Utc>>,
+}
+
+impl Flag {
+    fn new(transaction: &Transaction, rule: String) -> Self {
+        Self {
+            client: transaction.client_id,
+            tx_type: transaction.tx_type,
+            rule,
+            amount: transaction.amount,
+            currency: transaction.currency(),
+            at: transaction.timestamp,
+        }
+    }
+
+    // Whether the flag was raised by `at`, like `JournalEntry::is_before`
+    pub fn is_before(&self, at: Option<DateTime<Utc>>) -> bool {
+        match (self.at, at) {
+            (Some(flag_at), Some(at)) => flag_at <= at,
+            _ => true,
+        }
+    }
+}
+
+// What the rules know about a client. It is kept with the client, so it is in
+// snapshots and databases and follows the client to its shard.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RiskState {
+    // How many of the client's transactions went through
+    pub rows: u64,
+    // Only as much as the widest window of the rules needs
+    pub recent: VecDeque<Activity>,
+}
+
+impl RiskState {
+    pub fn is_empty(&self) -> bool {
+        self.rows == 0
+    }
+
+    // Remembers a transaction that went through. Nothing is kept without rules, so
+    // clients look the same as they did before rules.
+    pub fn record(&mut self, rules: &[RiskRule], transaction: &Transaction) {
+        if rules.is_empty() {
+            return;
+        }
+
+        self.recent.push_back(Activity {
+            tx_type: transaction.tx_type,
+            amount: transaction.amount,
+            currency: transaction.currency(),
+            row: self.rows,
+            at: transaction.timestamp,
+        });
+        self.rows += 1;
+
+        // A deposit followed by a withdrawal needs the previous transaction
+        let keep_rows = rules.iter().filter_map(|rule| rule.rows).max().unwrap_or(1);
+        let keep_seconds = rules.iter().filter_map(|rule| rule.seconds).max();
+
+        // A row without a timestamp can't tell how old the others are, so it only
+        // drops activity by rows
+        let rows = self.rows;
+        self.recent.retain(|activity| {
+            let in_rows = activity.row + keep_rows >= rows;
+            let in_time = keep_seconds.is_some_and(|seconds| match transaction.timestamp {
+                Some(at) => in_window(activity.at, Some(at), seconds),
+                None => activity.at.is_some(),
+            });
+
+            in_rows || in_time
+        });
+    }
+}
+
+// Whether activity at `activity_at` is within `seconds` before `at`. Without both
+// times it isn't.
+fn in_window(activity_at: Option<DateTime<Utc>>, at: Option<DateTime<Utc>>, seconds: u64) -> bool {
+    let duration = Duration::seconds(i64::try_from(seconds).unwrap_or(i64::MAX / 1000));
+
+    activity_at
+        .zip(at)
+        .is_some_and(|(activity_at, at)| activity_at + duration > at)
+}
+
+// One line of the review report
+#[derive(Serialize)]
+struct ReviewLine<'a> {
+    client: ClientId,
+    tx: TransactionId,
+    #[serde(rename = "type")]
+    tx_type: TransactionType,
+    rule: &'a str,
+    amount: Option<String>,
+    currency: Currency,
+    at: Option<DateTime<Utc>>,
+}
+
+// Writes every flag in the transaction store raised by `at`, for the risk team to
+// review, in the order the transactions were stored.
+pub fn write_review_report<'a>(
+    path: &str,
+    transactions: impl IntoIterator<Item = &'a StoredTransaction>,
+    at: Option<DateTime<Utc>>,
+) -> Result<(), TpsError> {
+    let lines = transactions.into_iter().flat_map(|stored| {
+        stored
+            .flags
+            .iter()
+            .filter(move |flag| flag.is_before(at))
+            .map(|flag| ReviewLine {
+                client: flag.client,
+                tx: stored.transaction.tx_id,
+                tx_type: flag.tx_type,
+                rule: &flag.rule,
+                amount: flag.amount.map(decimal_to_string),
+                currency: flag.currency,
+                at: flag.at,
+            })
+    });
+
+    write_report(path, lines)
+}
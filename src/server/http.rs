@@ -0,0 +1,156 @@
+use std::io;
+
+use axum::{
+    extract::{rejection::JsonRejection, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+
+use crate::{
+    clients::{Client, ClientId},
+    errors::TpsError,
+    transactions::{lifecycle::StoredTransaction, Transaction, TransactionError, TransactionId},
+};
+
+use super::EngineHandle;
+
+// The routes are:
+//   POST /transactions       a single transaction, or an array applied as one atomic batch
+//   GET  /clients/{id}       the client's balances
+//   GET  /transactions/{tx}  the stored transaction, along with its dispute state
+pub fn router(engine: EngineHandle) -> Router {
+    Router::new()
+        .route("/transactions", post(submit_transactions))
+        .route("/transactions/{tx}", get(get_transaction))
+        .route("/clients/{id}", get(get_client))
+        .with_state(engine)
+}
+
+pub async fn serve(listener: TcpListener, engine: EngineHandle) -> io::Result<()> {
+    axum::serve(listener, router(engine)).await
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Submission {
+    Single(Transaction),
+    Batch(Vec<Transaction>),
+}
+
+#[derive(Serialize)]
+struct Accepted {
+    accepted: usize,
+}
+
+async fn submit_transactions(
+    State(engine): State<EngineHandle>,
+    submission: Result<Json<Submission>, JsonRejection>,
+) -> Result<Json<Accepted>, ApiError> {
+    let Json(submission) = submission.map_err(|err| ApiError {
+        status: StatusCode::BAD_REQUEST,
+        code: "invalid_json",
+        message: err.body_text(),
+    })?;
+
+    let accepted = match submission {
+        Submission::Single(transaction) => {
+            engine.process(transaction).await?;
+            1
+        }
+        Submission::Batch(transactions) => {
+            let count = transactions.len();
+            engine.process_batch(transactions).await?;
+            count
+        }
+    };
+
+    Ok(Json(Accepted { accepted }))
+}
+
+async fn get_client(
+    State(engine): State<EngineHandle>,
+    Path(id): Path<u16>,
+) -> Result<Json<Client>, ApiError> {
+    engine
+        .client(ClientId::from(id))
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found("client"))
+}
+
+async fn get_transaction(
+    State(engine): State<EngineHandle>,
+    Path(tx): Path<u32>,
+) -> Result<Json<StoredTransaction>, ApiError> {
+    engine
+        .transaction(TransactionId::from(tx))
+        .await?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found("transaction"))
+}
+
+// Every error is returned as `{"code": "...", "message": "..."}`, for rejected
+// transactions the code is the one from `TransactionError::code`
+struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn not_found(what: &str) -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            code: "not_found",
+            message: format!("No such {what}"),
+        }
+    }
+}
+
+impl From<TpsError> for ApiError {
+    fn from(err: TpsError) -> Self {
+        match err {
+            TpsError::TransactionError(err @ TransactionError::Storage(_)) => Self {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                code: err.code(),
+                message: err.to_string(),
+            },
+            TpsError::TransactionError(err) => Self {
+                status: StatusCode::UNPROCESSABLE_ENTITY,
+                code: err.code(),
+                message: err.to_string(),
+            },
+            TpsError::WorkerStopped => Self {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                code: "unavailable",
+                message: err.to_string(),
+            },
+            err => Self {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                code: "internal",
+                message: err.to_string(),
+            },
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        #[derive(Serialize)]
+        struct Body {
+            code: &'static str,
+            message: String,
+        }
+
+        let body = Body {
+            code: self.code,
+            message: self.message,
+        };
+
+        (self.status, Json(body)).into_response()
+    }
+}
@@ -0,0 +1,135 @@
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    clients::{Client, ClientId, ClientList},
+    errors::TpsError,
+    policy::Policy,
+    storage::{MemoryStorage, Storage},
+    transactions::{
+        lifecycle::StoredTransaction, manager::TransactionManager, Transaction, TransactionId,
+    },
+};
+
+pub mod http;
+pub mod tcp;
+
+// How many requests can wait for the engine before senders have to wait
+const ENGINE_QUEUE_SIZE: usize = 1024;
+
+enum Request {
+    Process(Transaction, oneshot::Sender<Result<(), TpsError>>),
+    Batch(Vec<Transaction>, oneshot::Sender<Result<(), TpsError>>),
+    Client(ClientId, oneshot::Sender<Result<Option<Client>, TpsError>>),
+    Transaction(
+        TransactionId,
+        oneshot::Sender<Result<Option<StoredTransaction>, TpsError>>,
+    ),
+    Shutdown(oneshot::Sender<Result<(ClientList, TransactionManager), TpsError>>),
+}
+
+// The engine state is owned by a single task, and every connection talks to it
+// through a channel. This keeps the processing order well defined without any
+// locking, and a slow connection never holds up the others.
+#[derive(Clone)]
+pub struct EngineHandle {
+    sender: mpsc::Sender<Request>,
+}
+
+impl EngineHandle {
+    // Starts the engine task, this has to be called from within a tokio runtime
+    pub fn spawn(clients: ClientList, transactions: TransactionManager, policy: Policy) -> Self {
+        Self::with_storage(Box::new(MemoryStorage::new(clients, transactions)), policy)
+    }
+
+    // Like `spawn`, but with the state in `storage`, e.g. a database
+    pub fn with_storage(storage: Box<dyn Storage + Send>, policy: Policy) -> Self {
+        let (sender, receiver) = mpsc::channel(ENGINE_QUEUE_SIZE);
+        tokio::spawn(run_engine(receiver, storage, policy));
+
+        Self { sender }
+    }
+
+    // Processes the transaction, a rejected transaction is a `TpsError::TransactionError`
+    pub async fn process(&self, transaction: Transaction) -> Result<(), TpsError> {
+        let (reply, response) = oneshot::channel();
+        self.request(Request::Process(transaction, reply), response)
+            .await?
+    }
+
+    // Processes all of the transactions or none of them, see `process_batch`
+    pub async fn process_batch(&self, transactions: Vec<Transaction>) -> Result<(), TpsError> {
+        let (reply, response) = oneshot::channel();
+        self.request(Request::Batch(transactions, reply), response)
+            .await?
+    }
+
+    pub async fn client(&self, client_id: ClientId) -> Result<Option<Client>, TpsError> {
+        let (reply, response) = oneshot::channel();
+        self.request(Request::Client(client_id, reply), response)
+            .await?
+    }
+
+    // The stored transaction along with its dispute state and history
+    pub async fn transaction(
+        &self,
+        tx_id: TransactionId,
+    ) -> Result<Option<StoredTransaction>, TpsError> {
+        let (reply, response) = oneshot::channel();
+        self.request(Request::Transaction(tx_id, reply), response)
+            .await?
+    }
+
+    // Stops the engine and returns its final state, requests still waiting
+    // (and any made afterwards) fail with `TpsError::WorkerStopped`
+    pub async fn shutdown(&self) -> Result<(ClientList, TransactionManager), TpsError> {
+        let (reply, response) = oneshot::channel();
+        self.request(Request::Shutdown(reply), response).await?
+    }
+
+    async fn request<T>(
+        &self,
+        request: Request,
+        response: oneshot::Receiver<T>,
+    ) -> Result<T, TpsError> {
+        self.sender
+            .send(request)
+            .await
+            .map_err(|_| TpsError::WorkerStopped)?;
+
+        response.await.map_err(|_| TpsError::WorkerStopped)
+    }
+}
+
+async fn run_engine(
+    mut receiver: mpsc::Receiver<Request>,
+    mut storage: Box<dyn Storage + Send>,
+    policy: Policy,
+) {
+    while let Some(request) = receiver.recv().await {
+        // A requester that went away does not need an answer, so send errors are ignored
+        match request {
+            Request::Process(transaction, reply) => {
+                let result = storage
+                    .process(transaction, &policy)
+                    .map_err(TpsError::from);
+                let _ = reply.send(result);
+            }
+            Request::Batch(batch, reply) => {
+                let result = storage
+                    .process_batch(&batch, &policy)
+                    .map_err(TpsError::from);
+                let _ = reply.send(result);
+            }
+            Request::Client(client_id, reply) => {
+                let _ = reply.send(storage.get_client(&client_id));
+            }
+            Request::Transaction(tx_id, reply) => {
+                let _ = reply.send(storage.get_transaction(&tx_id));
+            }
+            Request::Shutdown(reply) => {
+                let _ = reply.send(storage.into_state());
+                return;
+            }
+        }
+    }
+}
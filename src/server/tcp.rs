@@ -0,0 +1,82 @@
+use std::io;
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{errors::TpsError, transactions::Transaction};
+
+use super::EngineHandle;
+
+// Accepts connections until the listener fails, every connection is handled on its own task.
+//
+// The protocol is line based: a connection first sends a CSV header line (e.g.
+// `type, client, tx, amount`), followed by one transaction row per line. Every row
+// is answered with one line, in order, using the row's line number in the stream:
+//   `<line>,ok`
+//   `<line>,rejected,<reason>`
+pub async fn serve(listener: TcpListener, engine: EngineHandle) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let engine = engine.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(stream, engine).await {
+                eprintln!("Error on connection from {peer}: {err}");
+            }
+        });
+    }
+}
+
+pub async fn handle_connection(stream: TcpStream, engine: EngineHandle) -> Result<(), TpsError> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let mut headers = None;
+    let mut line_number = 0;
+
+    while let Some(line) = lines.next_line().await? {
+        line_number += 1;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let Some(headers) = &headers else {
+            headers = Some(parse_record(&line)?);
+            continue;
+        };
+
+        let result = match parse_record(&line)
+            .and_then(|record| Ok(record.deserialize::<Transaction>(Some(headers))?))
+        {
+            Ok(transaction) => engine.process(transaction).await,
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(()) => respond(&mut writer, format!("{line_number},ok\n")).await?,
+            Err(TpsError::WorkerStopped) => return Err(TpsError::WorkerStopped),
+            Err(err) => respond(&mut writer, format!("{line_number},rejected,{err}\n")).await?,
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_record(line: &str) -> Result<csv::StringRecord, TpsError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .has_headers(false)
+        .from_reader(line.as_bytes());
+
+    let mut record = csv::StringRecord::new();
+    reader.read_record(&mut record)?;
+
+    Ok(record)
+}
+
+async fn respond(writer: &mut (impl AsyncWrite + Unpin), response: String) -> io::Result<()> {
+    writer.write_all(response.as_bytes()).await
+}
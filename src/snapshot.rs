@@ -0,0 +1,160 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use serde_json::{json, Map, Value};
+
+use crate::{
+    clients::ClientList,
+    currency::Currency,
+    errors::TpsError,
+    interest::Posting,
+    transactions::{lifecycle::DisputeState, manager::TransactionManager},
+};
+
+// Bumped whenever the layout of the snapshot changes in an incompatible way
+const SNAPSHOT_VERSION: u32 = 4;
+
+// The full engine state: balances, locked flags, and the stored transactions
+// along with their dispute state. This lets a run continue from where a
+// previous one ended, e.g. processing today's file on top of yesterday's state.
+// The interest payments are saved next to the transactions, in `postings`, and
+// loaded back into the transaction manager, and so is `journal_truncated` when the
+// dispute horizon dropped transactions along with their journal entries.
+#[derive(Debug, Deserialize)]
+pub struct Snapshot {
+    pub clients: ClientList,
+    pub transactions: TransactionManager,
+}
+
+impl Snapshot {
+    // Snapshots written by an older version are upgraded, newer ones are refused
+    pub fn load(path: &str) -> Result<Self, TpsError> {
+        let file = File::open(path)?;
+        let mut snapshot: Value = serde_json::from_reader(BufReader::new(file))?;
+
+        let version = snapshot["version"].as_u64().unwrap_or_default() as u32;
+        match version {
+            1 => {
+                migrate_dispute_state(&mut snapshot["transactions"]);
+                migrate_balances(&mut snapshot["clients"]);
+            }
+            2 => migrate_balances(&mut snapshot["clients"]),
+            // Version 3 had no risk rules, fees, journals, or interest yet, and
+            // everything it did have is still where it was
+            3 => (),
+            SNAPSHOT_VERSION => (),
+            _ => return Err(TpsError::SnapshotVersion(version)),
+        }
+        snapshot["version"] = Value::from(SNAPSHOT_VERSION);
+
+        let postings: Vec<Posting> = match snapshot.get_mut("postings") {
+            Some(postings) => serde_json::from_value(postings.take())?,
+            None => Vec::new(),
+        };
+
+        let journal_truncated = snapshot
+            .get("journal_truncated")
+            .and_then(Value::as_bool)
+            .unwrap_or_default();
+
+        let mut snapshot: Snapshot = serde_json::from_value(snapshot)?;
+        for posting in postings {
+            snapshot.transactions.insert_posting(posting);
+        }
+        if journal_truncated {
+            snapshot.transactions.mark_journal_truncated();
+        }
+
+        Ok(snapshot)
+    }
+}
+
+// Version 1 kept the row itself with an `in_dispute` flag. A resolved or charged
+// back transaction had the flag cleared, so it comes back as processed, like it
+// was treated then.
+fn migrate_dispute_state(transactions: &mut Value) {
+    let Some(transactions) = transactions.as_object_mut() else {
+        return;
+    };
+
+    for stored in transactions.values_mut() {
+        let mut transaction = stored.take();
+        let in_dispute = transaction
+            .as_object_mut()
+            .and_then(|transaction| transaction.remove("in_dispute"))
+            .and_then(|in_dispute| in_dispute.as_bool())
+            .unwrap_or_default();
+
+        let state = if in_dispute {
+            DisputeState::Disputed
+        } else {
+            DisputeState::Processed
+        };
+        *stored = json!({ "transaction": transaction, "state": state });
+    }
+}
+
+// Versions before 3 had one balance per client, which is in the default currency
+fn migrate_balances(clients: &mut Value) {
+    let Some(clients) = clients.as_object_mut() else {
+        return;
+    };
+
+    for client in clients.values_mut().filter_map(Value::as_object_mut) {
+        let mut balance = Map::new();
+        for field in ["available", "held", "total"] {
+            if let Some(amount) = client.remove(field) {
+                balance.insert(field.to_string(), amount);
+            }
+        }
+
+        client.insert(
+            "balances".to_string(),
+            json!({ Currency::default().as_str(): balance }),
+        );
+    }
+}
+
+// Writes the state to a temporary file first and then renames it over the target,
+// so a crash while saving never leaves a half written snapshot behind
+pub fn save_snapshot(
+    path: &str,
+    clients: &ClientList,
+    transactions: &TransactionManager,
+) -> Result<(), TpsError> {
+    // Borrowed version of Snapshot, so the state does not have to be cloned to be saved
+    #[derive(Serialize)]
+    struct SnapshotRef<'a> {
+        version: u32,
+        clients: &'a ClientList,
+        transactions: &'a TransactionManager,
+        postings: &'a [Posting],
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        journal_truncated: bool,
+    }
+
+    let tmp_path = format!("{path}.tmp");
+    let file = File::create(&tmp_path)?;
+    let mut writer = BufWriter::new(&file);
+
+    serde_json::to_writer(
+        &mut writer,
+        &SnapshotRef {
+            version: SNAPSHOT_VERSION,
+            clients,
+            transactions,
+            postings: transactions.postings(),
+            journal_truncated: transactions.journal_truncated(),
+        },
+    )?;
+    writer.flush()?;
+    file.sync_all()?;
+
+    std::fs::rename(&tmp_path, Path::new(path))?;
+
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use crate::{
+    clients::{Client, ClientId, ClientList},
+    errors::TpsError,
+    interest::Posting,
+    policy::Policy,
+    transactions::{
+        batch::process_batch, lifecycle::StoredTransaction, manager::TransactionManager,
+        process::process_transaction, Transaction, TransactionError, TransactionId,
+    },
+};
+
+use super::Storage;
+
+// The state kept in the in-memory maps, which is lost on exit unless it is saved
+// to a snapshot. This is what the engine uses when no database is given.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    clients: ClientList,
+    transactions: TransactionManager,
+}
+
+impl MemoryStorage {
+    pub fn new(clients: ClientList, transactions: TransactionManager) -> Self {
+        Self {
+            clients,
+            transactions,
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get_client(&mut self, id: &ClientId) -> Result<Option<Client>, TpsError> {
+        Ok(self.clients.get_client(id).cloned())
+    }
+
+    fn put_client(&mut self, client: &Client) -> Result<(), TpsError> {
+        self.clients.insert_client(client.clone());
+        Ok(())
+    }
+
+    fn client_ids(&mut self) -> Result<Vec<ClientId>, TpsError> {
+        Ok(self.clients.iter().map(|client| client.id).collect())
+    }
+
+    // The transaction may have been spilled to disk, so it is loaded like for processing
+    fn get_transaction(
+        &mut self,
+        id: &TransactionId,
+    ) -> Result<Option<StoredTransaction>, TpsError> {
+        self.transactions.load(id)?;
+        let stored = self.transactions.get(id).cloned();
+        self.transactions.trim()?;
+
+        Ok(stored)
+    }
+
+    fn put_transaction(&mut self, stored: &StoredTransaction) -> Result<(), TpsError> {
+        self.transactions.insert_stored(stored.clone());
+        Ok(())
+    }
+
+    fn put_posting(&mut self, posting: &Posting) -> Result<(), TpsError> {
+        self.transactions.insert_posting(posting.clone());
+        Ok(())
+    }
+
+    fn into_state(self: Box<Self>) -> Result<(ClientList, TransactionManager), TpsError> {
+        Ok((self.clients, self.transactions))
+    }
+
+    // The maps are changed in place, there is nothing to load or save back
+    fn process(
+        &mut self,
+        transaction: Transaction,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        process_transaction(
+            &transaction,
+            &mut self.clients,
+            &mut self.transactions,
+            policy,
+        )
+    }
+
+    fn process_batch(
+        &mut self,
+        transactions: &[Transaction],
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        process_batch(
+            transactions,
+            &mut self.clients,
+            &mut self.transactions,
+            policy,
+        )
+    }
+}
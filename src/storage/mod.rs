@@ -0,0 +1,217 @@
+use crate::{
+    clients::{Client, ClientId, ClientList},
+    errors::TpsError,
+    interest::Posting,
+    policy::Policy,
+    rejects::RejectSink,
+    transactions::{
+        batch::process_batch, lifecycle::StoredTransaction, logic::operation_for,
+        manager::TransactionManager, process::process_transaction, Transaction, TransactionError,
+        TransactionId, TransactionType,
+    },
+};
+
+pub mod memory;
+pub mod sqlite;
+
+pub use memory::MemoryStorage;
+pub use sqlite::SqliteStorage;
+
+// Where the engine state lives. The transaction logic always works on a `ClientList`
+// and a `TransactionManager`, so a backend only has to get and save single records,
+// and `process` loads the few that a row touches into those, runs the row, and
+// saves them back within one storage transaction.
+pub trait Storage {
+    fn get_client(&mut self, id: &ClientId) -> Result<Option<Client>, TpsError>;
+
+    // Inserts the client, or updates it if it is already stored
+    fn put_client(&mut self, client: &Client) -> Result<(), TpsError>;
+
+    // Every stored client, an end of day changes all of them
+    fn client_ids(&mut self) -> Result<Vec<ClientId>, TpsError>;
+
+    fn get_transaction(
+        &mut self,
+        id: &TransactionId,
+    ) -> Result<Option<StoredTransaction>, TpsError>;
+
+    // Inserts the transaction, or updates it (e.g. its dispute state) if it is already stored
+    fn put_transaction(&mut self, stored: &StoredTransaction) -> Result<(), TpsError>;
+
+    // Interest payments are only ever added, an end of day can't be undone. The id
+    // the payment comes with is ignored, it is numbered as the next one kept.
+    fn put_posting(&mut self, posting: &Posting) -> Result<(), TpsError>;
+
+    // The writes between `begin` and `commit` are applied together or not at all
+    fn begin(&mut self) -> Result<(), TpsError> {
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), TpsError> {
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), TpsError> {
+        Ok(())
+    }
+
+    // Everything that is stored, e.g. for the account output or a snapshot
+    fn into_state(self: Box<Self>) -> Result<(ClientList, TransactionManager), TpsError>;
+
+    // Processes a single transaction like `process_transaction`. A failing backend
+    // is reported as `TransactionError::Storage`.
+    fn process(
+        &mut self,
+        transaction: Transaction,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        atomically(
+            self,
+            std::slice::from_ref(&transaction),
+            policy,
+            |clients, transaction_manager| {
+                process_transaction(&transaction, clients, transaction_manager, policy)
+            },
+        )
+    }
+
+    // Processes all of the transactions or none of them, see `process_batch`
+    fn process_batch(
+        &mut self,
+        transactions: &[Transaction],
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        atomically(
+            self,
+            transactions,
+            policy,
+            |clients, transaction_manager| {
+                process_batch(transactions, clients, transaction_manager, policy)
+            },
+        )
+    }
+}
+
+// Like `process_transactions_reporting`, but with the state in `storage`
+pub fn process_with_storage(
+    transactions: Vec<Transaction>,
+    storage: &mut dyn Storage,
+    policy: &Policy,
+    rejects: &mut dyn RejectSink,
+) -> Result<(), TpsError> {
+    for transaction in transactions {
+        match storage.process(transaction.clone(), policy) {
+            Ok(()) => (),
+            // The state can't be trusted anymore, so processing stops
+            Err(e @ TransactionError::Storage(_)) => return Err(e.into()),
+            Err(e) => {
+                let client = storage.get_client(&transaction.client_id)?;
+                rejects.reject(&transaction, &e, client.as_ref())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Runs `process` on the records the transactions touch and saves them back. A
+// rejected transaction is saved too, since it is still stored (as rejected) for
+// its id, but a failing backend rolls everything back.
+fn atomically<S: Storage + ?Sized>(
+    storage: &mut S,
+    transactions: &[Transaction],
+    policy: &Policy,
+    process: impl FnOnce(&mut ClientList, &mut TransactionManager) -> Result<(), TransactionError>,
+) -> Result<(), TransactionError> {
+    storage.begin().map_err(storage_error)?;
+
+    let result = (|| {
+        let (mut clients, mut transaction_manager) = load_involved(storage, transactions, policy)?;
+
+        let outcome = process(&mut clients, &mut transaction_manager);
+        if let Err(TransactionError::Storage(err)) = outcome {
+            return Err(TpsError::from(TransactionError::Storage(err)));
+        }
+
+        for client in clients.iter() {
+            storage.put_client(client)?;
+        }
+        for posting in transaction_manager.take_postings() {
+            storage.put_posting(&posting)?;
+        }
+        for stored in transaction_manager {
+            storage.put_transaction(&stored?)?;
+        }
+
+        Ok(outcome)
+    })();
+
+    match result {
+        Ok(outcome) => {
+            storage.commit().map_err(storage_error)?;
+            outcome
+        }
+        Err(err) => {
+            let _ = storage.rollback();
+            Err(storage_error(err))
+        }
+    }
+}
+
+// The clients and stored transactions the transactions can change. Every row
+// works on the stored transaction with its id, and its operation says which
+// clients it involves (see `TransactionOp::involved`). An end of day changes
+// every client.
+fn load_involved<S: Storage + ?Sized>(
+    storage: &mut S,
+    transactions: &[Transaction],
+    policy: &Policy,
+) -> Result<(ClientList, TransactionManager), TpsError> {
+    let mut clients = ClientList::new();
+    let mut transaction_manager = TransactionManager::new();
+    let mut client_ids = Vec::new();
+
+    for transaction in transactions {
+        // A row that couldn't be read touches nothing
+        if transaction.unreadable.is_some() {
+            continue;
+        }
+
+        if transaction.tx_type == TransactionType::Eod {
+            client_ids.extend(storage.client_ids()?);
+            continue;
+        }
+
+        if !transaction_manager.contains(&transaction.tx_id) {
+            if let Some(stored) = storage.get_transaction(&transaction.tx_id)? {
+                transaction_manager.insert_stored(stored);
+            }
+        }
+
+        // A row that isn't a valid operation is rejected before it changes anything,
+        // its client is only needed for the balances in the reject report
+        match operation_for(transaction, policy) {
+            Ok(operation) => client_ids.extend(operation.involved(&transaction_manager)),
+            Err(_) => client_ids.push(transaction.client_id),
+        }
+    }
+
+    for client_id in client_ids {
+        if clients.get_client(&client_id).is_some() {
+            continue;
+        }
+
+        if let Some(client) = storage.get_client(&client_id)? {
+            clients.insert_client(client);
+        }
+    }
+
+    Ok((clients, transaction_manager))
+}
+
+fn storage_error(err: TpsError) -> TransactionError {
+    match err {
+        TpsError::TransactionError(err) => err,
+        err => TransactionError::Storage(err.to_string()),
+    }
+}
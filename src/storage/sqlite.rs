@@ -0,0 +1,219 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+
+use crate::{
+    clients::{Client, ClientId, ClientList},
+    errors::TpsError,
+    interest::{Posting, PostingId},
+    transactions::{lifecycle::StoredTransaction, manager::TransactionManager, TransactionId},
+};
+
+use super::Storage;
+
+// This has to change whenever the tables or the layout of the records do, along
+// with a step in `UPGRADES` from the previous version
+const DATABASE_VERSION: u32 = 3;
+
+// The oldest version that can be upgraded, anything older is refused
+const OLDEST_VERSION: u32 = 2;
+
+// The steps from each version to the next, starting at `OLDEST_VERSION`. A database
+// is taken through all of the steps it is behind in one go.
+const UPGRADES: [&str; (DATABASE_VERSION - OLDEST_VERSION) as usize] = [ADD_TYPE_AND_POSTINGS];
+
+// Version 2 only had the JSON of the transactions, and no interest payments
+const ADD_TYPE_AND_POSTINGS: &str = "
+    ALTER TABLE transactions ADD COLUMN type TEXT NOT NULL DEFAULT '';
+    UPDATE transactions SET type = json_extract(stored, '$.transaction.type');
+    CREATE TABLE postings (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        posting TEXT NOT NULL
+    );";
+
+// The state kept in an embedded SQLite database, so it outlives the process.
+//
+// The records are stored as JSON next to their id rather than a column per field,
+// the same way they are in a snapshot, so the tables don't change every time
+// `Client` or `StoredTransaction` gains a field. What the queries look records up
+// by is a column of its own, the JSON is only ever read whole.
+#[derive(Debug)]
+pub struct SqliteStorage {
+    connection: Connection,
+}
+
+impl SqliteStorage {
+    // Opens the database, creating it if it does not exist yet
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, TpsError> {
+        let connection = Connection::open(path)?;
+
+        // A commit is on disk before the row that made it is answered
+        connection.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = FULL;",
+        )?;
+
+        let version: u32 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        match version {
+            0 => connection.execute_batch(&format!(
+                "BEGIN;
+                 CREATE TABLE clients (
+                     id INTEGER PRIMARY KEY,
+                     client TEXT NOT NULL
+                 );
+                 CREATE TABLE transactions (
+                     seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                     tx INTEGER NOT NULL UNIQUE,
+                     type TEXT NOT NULL,
+                     stored TEXT NOT NULL
+                 );
+                 CREATE TABLE postings (
+                     seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                     posting TEXT NOT NULL
+                 );
+                 PRAGMA user_version = {DATABASE_VERSION};
+                 COMMIT;"
+            ))?,
+            OLDEST_VERSION..DATABASE_VERSION => {
+                let steps = UPGRADES[(version - OLDEST_VERSION) as usize..].concat();
+                connection.execute_batch(&format!(
+                    "BEGIN;
+                     {steps}
+                     PRAGMA user_version = {DATABASE_VERSION};
+                     COMMIT;"
+                ))?
+            }
+            DATABASE_VERSION => (),
+            version => return Err(TpsError::DatabaseVersion(version)),
+        }
+
+        Ok(Self { connection })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn get_client(&mut self, id: &ClientId) -> Result<Option<Client>, TpsError> {
+        let json: Option<String> = self
+            .connection
+            .prepare_cached("SELECT client FROM clients WHERE id = ?1")?
+            .query_row(params![u16::from(*id)], |row| row.get(0))
+            .optional()?;
+
+        Ok(json.map(|json| serde_json::from_str(&json)).transpose()?)
+    }
+
+    fn put_client(&mut self, client: &Client) -> Result<(), TpsError> {
+        self.connection
+            .prepare_cached(
+                "INSERT INTO clients (id, client) VALUES (?1, ?2)
+                 ON CONFLICT (id) DO UPDATE SET client = excluded.client",
+            )?
+            .execute(params![
+                u16::from(client.id),
+                serde_json::to_string(client)?
+            ])?;
+
+        Ok(())
+    }
+
+    fn client_ids(&mut self) -> Result<Vec<ClientId>, TpsError> {
+        let mut statement = self.connection.prepare_cached("SELECT id FROM clients")?;
+        let ids = statement
+            .query_map([], |row| row.get::<_, u16>(0))?
+            .map(|id| id.map(ClientId::from))
+            .collect::<Result<_, _>>()?;
+
+        Ok(ids)
+    }
+
+    fn get_transaction(
+        &mut self,
+        id: &TransactionId,
+    ) -> Result<Option<StoredTransaction>, TpsError> {
+        let json: Option<String> = self
+            .connection
+            .prepare_cached("SELECT stored FROM transactions WHERE tx = ?1")?
+            .query_row(params![u32::from(*id)], |row| row.get(0))
+            .optional()?;
+
+        Ok(json.map(|json| serde_json::from_str(&json)).transpose()?)
+    }
+
+    // An update keeps the place of the transaction in the order they were stored
+    fn put_transaction(&mut self, stored: &StoredTransaction) -> Result<(), TpsError> {
+        // The name the type has in the input, e.g. `deposit`
+        let tx_type = serde_json::to_value(stored.transaction.tx_type)?;
+
+        self.connection
+            .prepare_cached(
+                "INSERT INTO transactions (tx, type, stored) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (tx) DO UPDATE SET stored = excluded.stored",
+            )?
+            .execute(params![
+                u32::from(stored.transaction.tx_id),
+                tx_type.as_str(),
+                serde_json::to_string(stored)?
+            ])?;
+
+        Ok(())
+    }
+
+    // The id is the row's `seq`, so the postings read back in order keep their ids
+    fn put_posting(&mut self, posting: &Posting) -> Result<(), TpsError> {
+        let seq: u32 = self
+            .connection
+            .prepare_cached("SELECT IFNULL(MAX(seq), 0) + 1 FROM postings")?
+            .query_row([], |row| row.get(0))?;
+        let posting = Posting {
+            id: PostingId::from(seq),
+            ..posting.clone()
+        };
+
+        self.connection
+            .prepare_cached("INSERT INTO postings (seq, posting) VALUES (?1, ?2)")?
+            .execute(params![seq, serde_json::to_string(&posting)?])?;
+
+        Ok(())
+    }
+
+    // IMMEDIATE takes the write lock up front, so another process using the same
+    // database can't change the records between reading and saving them
+    fn begin(&mut self) -> Result<(), TpsError> {
+        self.connection.execute_batch("BEGIN IMMEDIATE")?;
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), TpsError> {
+        self.connection.execute_batch("COMMIT")?;
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), TpsError> {
+        self.connection.execute_batch("ROLLBACK")?;
+        Ok(())
+    }
+
+    fn into_state(self: Box<Self>) -> Result<(ClientList, TransactionManager), TpsError> {
+        let mut clients = ClientList::new();
+        let mut statement = self.connection.prepare("SELECT client FROM clients")?;
+        for json in statement.query_map([], |row| row.get::<_, String>(0))? {
+            clients.insert_client(serde_json::from_str(&json?)?);
+        }
+
+        let mut transactions = TransactionManager::new();
+        let mut statement = self
+            .connection
+            .prepare("SELECT stored FROM transactions ORDER BY seq")?;
+        for json in statement.query_map([], |row| row.get::<_, String>(0))? {
+            transactions.insert_stored(serde_json::from_str(&json?)?);
+        }
+
+        let mut statement = self
+            .connection
+            .prepare("SELECT posting FROM postings ORDER BY seq")?;
+        for json in statement.query_map([], |row| row.get::<_, String>(0))? {
+            transactions.insert_posting(serde_json::from_str(&json?)?);
+        }
+
+        Ok((clients, transactions))
+    }
+}
@@ -0,0 +1,162 @@
+use crate::{
+    clients::{ClientId, ClientList},
+    policy::Policy,
+    risk::{self, RiskState},
+};
+
+use super::{
+    lifecycle::StoredTransaction,
+    logic::{execute, operation_for, TransactionOp},
+    manager::TransactionManager,
+    Transaction, TransactionError, TransactionId,
+};
+
+// Every change a batch makes is recorded here, so it can be undone in reverse order
+enum Undo<'a> {
+    Operation(Box<dyn TransactionOp + 'a>),
+    StoredTransaction(TransactionId),
+    CreatedClient(ClientId),
+    // What the risk rules knew of the client before the transaction
+    Risk(ClientId, RiskState),
+    // How many journal entries and flags the disputed transaction had before the transaction
+    Journal(TransactionId, usize, usize),
+}
+
+// Applies all of the transactions, or none of them. Unlike `process_transactions`,
+// the first failure stops the batch and every change made so far is rolled back,
+// including dispute flags, stored transactions, and newly created clients.
+pub fn process_batch(
+    transactions: &[Transaction],
+    clients: &mut ClientList,
+    transaction_manager: &mut TransactionManager,
+    policy: &Policy,
+) -> Result<(), TransactionError> {
+    let mut undo_log = Vec::with_capacity(transactions.len());
+
+    for transaction in transactions {
+        if let Err(err) = apply_one(
+            transaction,
+            clients,
+            transaction_manager,
+            policy,
+            &mut undo_log,
+        ) {
+            rollback(undo_log, clients, transaction_manager)?;
+            transaction_manager.trim()?;
+            return Err(err);
+        }
+    }
+
+    // Nothing is spilled or dropped in the middle of a batch, so a rollback
+    // always finds the transactions it has to undo in memory
+    transaction_manager.trim()
+}
+
+fn apply_one<'a>(
+    transaction: &Transaction,
+    clients: &mut ClientList,
+    transaction_manager: &mut TransactionManager,
+    policy: &'a Policy,
+    undo_log: &mut Vec<Undo<'a>>,
+) -> Result<(), TransactionError> {
+    transaction.check_row()?;
+    transaction_manager.load(&transaction.tx_id)?;
+
+    if transaction.tx_type.is_stored() && transaction_manager.contains(&transaction.tx_id) {
+        return Err(TransactionError::DuplicateTransactionId(transaction.tx_id));
+    }
+
+    let mut flags = risk::screen(policy.risk_rules(), transaction, clients)?;
+
+    let operation = operation_for(transaction, policy)?;
+
+    // execute() creates the clients before validating, so this has to be recorded first
+    let client_id = operation.client_id();
+    if operation.creates_client() && clients.get_client(&client_id).is_none() {
+        undo_log.push(Undo::CreatedClient(client_id));
+    }
+
+    let others = operation
+        .counterparty_id(transaction_manager)
+        .into_iter()
+        .chain(operation.fee_account());
+    for other_id in others {
+        if clients.get_client(&other_id).is_none() && !created(undo_log, other_id) {
+            undo_log.push(Undo::CreatedClient(other_id));
+        }
+    }
+
+    let mut journal = execute(
+        operation.as_ref(),
+        clients,
+        transaction_manager,
+        policy,
+        transaction.timestamp,
+    )?;
+
+    let stored = transaction.tx_type.is_stored().then(|| {
+        let mut stored = StoredTransaction::new(transaction.clone());
+        operation.record(&mut stored);
+        stored.journal = std::mem::take(&mut journal);
+        stored.flags = std::mem::take(&mut flags);
+        stored
+    });
+    undo_log.push(Undo::Operation(operation));
+
+    if let Some(client) = clients.get_client(&transaction.client_id) {
+        undo_log.push(Undo::Risk(client.id, client.risk.clone()));
+    }
+    risk::record(policy.risk_rules(), transaction, clients);
+
+    if let Some(stored) = stored {
+        transaction_manager.insert_stored(stored);
+        undo_log.push(Undo::StoredTransaction(transaction.tx_id));
+    } else if let Some(stored) = transaction_manager.get_mut(&transaction.tx_id) {
+        undo_log.push(Undo::Journal(
+            transaction.tx_id,
+            stored.journal.len(),
+            stored.flags.len(),
+        ));
+        stored.journal.extend(journal);
+        stored.flags.extend(flags);
+    }
+
+    Ok(())
+}
+
+fn created(undo_log: &[Undo], client_id: ClientId) -> bool {
+    undo_log
+        .iter()
+        .any(|undo| matches!(undo, Undo::CreatedClient(id) if *id == client_id))
+}
+
+fn rollback(
+    undo_log: Vec<Undo>,
+    clients: &mut ClientList,
+    transaction_manager: &mut TransactionManager,
+) -> Result<(), TransactionError> {
+    for undo in undo_log.into_iter().rev() {
+        match undo {
+            Undo::Operation(operation) => operation.revert(clients, transaction_manager)?,
+            Undo::StoredTransaction(tx_id) => {
+                transaction_manager.remove(&tx_id);
+            }
+            Undo::CreatedClient(client_id) => {
+                clients.remove_client(&client_id);
+            }
+            Undo::Risk(client_id, state) => {
+                if let Some(client) = clients.get_client_mut(&client_id) {
+                    client.risk = state;
+                }
+            }
+            Undo::Journal(tx_id, journal_len, flags_len) => {
+                if let Some(stored) = transaction_manager.get_mut(&tx_id) {
+                    stored.journal.truncate(journal_len);
+                    stored.flags.truncate(flags_len);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
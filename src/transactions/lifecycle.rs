@@ -0,0 +1,193 @@
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+
+use crate::{clients::ClientId, fees::Fee, ledger::JournalEntry, risk::Flag};
+
+use super::{Position, Transaction, TransactionError, TransactionType};
+
+// Where a stored transaction is in the dispute process. Resolved and charged back
+// transactions are final, so a transaction can be disputed at most once. A
+// rejected transaction is only kept so its id can't be used again, it never
+// moved any funds, so there is nothing to dispute.
+//
+//     Processed -> Disputed -> Resolved
+//                           -> ChargedBack
+//     Rejected
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DisputeState {
+    #[default]
+    Processed,
+    Disputed,
+    Resolved,
+    ChargedBack,
+    Rejected,
+}
+
+impl DisputeState {
+    pub fn can_move_to(self, next: DisputeState) -> bool {
+        matches!(
+            (self, next),
+            (DisputeState::Processed, DisputeState::Disputed)
+                | (DisputeState::Disputed, DisputeState::Resolved)
+                | (DisputeState::Disputed, DisputeState::ChargedBack)
+        )
+    }
+}
+
+impl Display for DisputeState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DisputeState::Processed => "processed",
+            DisputeState::Disputed => "disputed",
+            DisputeState::Resolved => "resolved",
+            DisputeState::ChargedBack => "charged back",
+            DisputeState::Rejected => "rejected",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+// One step in the history of a transaction, along with the row that caused it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateChange {
+    pub from: DisputeState,
+    pub to: DisputeState,
+    #[serde(rename = "type")]
+    pub cause: TransactionType,
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+    // Only known when the row came from a file
+    pub position: Option<Position>,
+}
+
+// A transaction as kept by the `TransactionManager`: the row that was read, and
+// what has happened to it since. For administrative transactions this (with the
+// operator in the row) is the audit record of who changed the account.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredTransaction {
+    pub transaction: Transaction,
+    // Where the row was read from, the row itself does not keep this when serialized
+    #[serde(default)]
+    pub position: Option<Position>,
+    #[serde(default)]
+    pub state: DisputeState,
+    #[serde(default)]
+    pub history: Vec<StateChange>,
+    // Only set for conversions, see `Conversion`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversion: Option<Conversion>,
+    // The fee charged for the transaction, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee: Option<Fee>,
+    // What the transaction, and then its disputes, resolves and chargebacks, did to
+    // the accounts, in the order they happened
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub journal: Vec<JournalEntry>,
+    // The risk rules that flagged the transaction, and then its disputes, resolves
+    // and chargebacks
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flags: Vec<Flag>,
+}
+
+// How a conversion was done. The converted amount is rounded to the precision of
+// the balances, and what was rounded off is kept here so the books still add up.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Conversion {
+    pub rate: Decimal,
+    // What was credited in the target currency
+    pub credited: Decimal,
+    // The exact converted amount minus `credited`, this can be negative
+    pub remainder: Decimal,
+}
+
+impl StoredTransaction {
+    pub fn new(transaction: Transaction) -> Self {
+        Self {
+            position: transaction.position,
+            transaction: Transaction {
+                raw: None,
+                ..transaction
+            },
+            state: DisputeState::default(),
+            history: Vec::new(),
+            conversion: None,
+            fee: None,
+            journal: Vec::new(),
+            flags: Vec::new(),
+        }
+    }
+
+    // A transaction that was rejected, stored so its id stays taken
+    pub fn rejected(transaction: Transaction) -> Self {
+        Self {
+            state: DisputeState::Rejected,
+            ..Self::new(transaction)
+        }
+    }
+
+    // Whether the transaction was made by `at`, going by its first journal entry
+    pub fn is_before(&self, at: Option<DateTime<Utc>>) -> bool {
+        self.journal.first().is_none_or(|entry| entry.is_before(at))
+    }
+
+    pub fn check_move(&self, next: DisputeState) -> Result<(), TransactionError> {
+        if !self.state.can_move_to(next) {
+            return Err(TransactionError::InvalidStateChange(
+                self.transaction.tx_id,
+                self.state,
+                next,
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Moves to the next state and records `cause` as the row that did it
+    pub fn move_to(
+        &mut self,
+        next: DisputeState,
+        cause: &CauseRow,
+    ) -> Result<(), TransactionError> {
+        self.check_move(next)?;
+
+        self.history.push(StateChange {
+            from: self.state,
+            to: next,
+            cause: cause.tx_type,
+            client_id: cause.client_id,
+            position: cause.position,
+        });
+        self.state = next;
+
+        Ok(())
+    }
+
+    // Undoes the last `move_to`, for when the operation that made it is reverted
+    pub fn undo_move(&mut self) {
+        if let Some(change) = self.history.pop() {
+            self.state = change.from;
+        }
+    }
+}
+
+// The parts of a dispute, resolve, or chargeback row that go into the history
+#[derive(Debug, Clone, Copy)]
+pub struct CauseRow {
+    pub tx_type: TransactionType,
+    pub client_id: ClientId,
+    pub position: Option<Position>,
+}
+
+impl From<&Transaction> for CauseRow {
+    fn from(transaction: &Transaction) -> Self {
+        Self {
+            tx_type: transaction.tx_type,
+            client_id: transaction.client_id,
+            position: transaction.position,
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use rust_decimal::Decimal;
+
+use crate::{
+    clients::{ClientId, ClientList},
+    currency::Currency,
+    ledger::{Account, Line},
+    policy::Policy,
+    transactions::{manager::TransactionManager, Transaction, TransactionError},
+};
+
+use super::TransactionOp;
+
+// A manual correction of the available funds, a negative amount takes funds away.
+// Unlike a deposit or withdrawal this works on locked accounts, since those are
+// usually the ones being investigated.
+#[derive(Debug)]
+pub struct Adjustment {
+    client_id: ClientId,
+    amount: Decimal,
+    currency: Currency,
+    allow_negative_balance: bool,
+}
+
+impl Adjustment {
+    pub fn new(transaction: &Transaction, policy: &Policy) -> Result<Self, TransactionError> {
+        let amount = transaction.amount.ok_or(TransactionError::MissingAmount)?;
+
+        Ok(Self {
+            client_id: transaction.client_id,
+            amount,
+            currency: transaction.currency(),
+            allow_negative_balance: policy.allow_negative_balance(),
+        })
+    }
+}
+
+impl TransactionOp for Adjustment {
+    fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    fn validate(
+        &self,
+        clients: &ClientList,
+        _transactions: &TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        if self.amount == Decimal::from(0) {
+            return Err(TransactionError::InvalidAmount);
+        }
+
+        if client.closed {
+            return Err(TransactionError::ClosedClient(self.client_id));
+        }
+
+        if !self.allow_negative_balance
+            && client.spendable(self.currency) + self.amount < Decimal::from(0)
+        {
+            return Err(TransactionError::InsufficientFunds(self.client_id));
+        }
+
+        Ok(())
+    }
+
+    fn apply(
+        &self,
+        clients: &mut ClientList,
+        _transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let balance = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?
+            .balance_mut(self.currency);
+
+        balance.available += self.amount;
+        balance.total += self.amount;
+
+        Ok(())
+    }
+
+    fn revert(
+        &self,
+        clients: &mut ClientList,
+        _transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let balance = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?
+            .balance_mut(self.currency);
+
+        balance.available -= self.amount;
+        balance.total -= self.amount;
+
+        Ok(())
+    }
+
+    // A negative amount turns the lines around
+    fn lines(&self, _transactions: &TransactionManager) -> Vec<(Currency, Line)> {
+        vec![
+            (
+                self.currency,
+                Line::debit(Account::Adjustments, self.amount),
+            ),
+            (
+                self.currency,
+                Line::credit(Account::Available(self.client_id), self.amount),
+            ),
+        ]
+    }
+}
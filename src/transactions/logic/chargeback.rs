@@ -1,32 +1,60 @@
 use crate::{
     clients::{ClientId, ClientList},
+    currency::Currency,
+    ledger::{Account, Line},
+    policy::Policy,
     transactions::{
-        manager::TransactionManager, Transaction, TransactionError, TransactionId, TransactionType,
+        lifecycle::{CauseRow, DisputeState},
+        manager::TransactionManager,
+        Transaction, TransactionError, TransactionId, TransactionType,
     },
 };
 
+use super::TransactionOp;
+
 #[derive(Debug)]
-pub struct Chargeback {
+pub struct Chargeback<'a> {
     tx_id: TransactionId,
     client_id: ClientId,
+    cause: CauseRow,
+    // Only set when the row names a currency, which has to be the one of the transaction
+    currency: Option<Currency>,
+    policy: &'a Policy,
 }
 
-impl Chargeback {
-    pub fn new(transaction: &Transaction) -> Result<Self, TransactionError> {
+impl<'a> Chargeback<'a> {
+    pub fn new(transaction: &Transaction, policy: &'a Policy) -> Result<Self, TransactionError> {
         Ok(Self {
             tx_id: transaction.tx_id,
             client_id: transaction.client_id,
+            cause: CauseRow::from(transaction),
+            currency: transaction.currency,
+            policy,
         })
     }
+}
+
+impl TransactionOp for Chargeback<'_> {
+    fn client_id(&self) -> ClientId {
+        self.client_id
+    }
 
-    pub fn process(
+    // Charging back a transfer returns the funds to the client that sent them
+    fn counterparty_id(&self, transactions: &TransactionManager) -> Option<ClientId> {
+        transactions
+            .get(&self.tx_id)
+            .map(|stored| &stored.transaction)
+            .filter(|transaction| transaction.tx_type == TransactionType::Transfer)
+            .map(|transaction| transaction.client_id)
+    }
+
+    fn validate(
         &self,
-        clients: &mut ClientList,
-        transactions: &mut TransactionManager,
+        clients: &ClientList,
+        transactions: &TransactionManager,
     ) -> Result<(), TransactionError> {
-        // Doing the sanity checks before making any changes
         let client = clients
-            .get_client_mut(&self.client_id)
+            .get_client(&self.client_id)
             // Client is actually needed here
             .ok_or(TransactionError::MissingClient(self.client_id))?;
 
@@ -34,41 +62,153 @@ impl Chargeback {
             return Err(TransactionError::LockedClient(self.client_id));
         }
 
-        // Making the changes
-        let transaction = transactions
+        let stored = transactions
+            .get(&self.tx_id)
+            .ok_or(TransactionError::MissingTransactionId)?;
+        let transaction = &stored.transaction;
+
+        // A client can only act on its own transactions
+        if transaction.disputing_client_id() != self.client_id {
+            return Err(TransactionError::ClientMismatch(self.tx_id, self.client_id));
+        }
+
+        // The funds are held in the currency they were moved in
+        if self
+            .currency
+            .is_some_and(|currency| currency != transaction.currency())
+        {
+            return Err(TransactionError::CurrencyMismatch(self.tx_id));
+        }
+
+        // Like the dispute, and resolve, only what can be disputed can be charged back
+        if !self.policy.is_disputable(transaction) {
+            return Err(TransactionError::NotDisputable(self.tx_id));
+        }
+
+        // Only an open dispute can be charged back
+        stored.check_move(DisputeState::ChargedBack)?;
+
+        let chargeback_amount = transaction.amount.ok_or(TransactionError::InvalidAmount)?;
+
+        if client.balance(transaction.currency()).held < chargeback_amount {
+            return Err(TransactionError::InsufficientFunds(self.client_id));
+        }
+
+        Ok(())
+    }
+
+    fn apply(
+        &self,
+        clients: &mut ClientList,
+        transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        let stored = transactions
             .get_mut(&self.tx_id)
             .ok_or(TransactionError::MissingTransactionId)?;
+        let transaction = stored.transaction.clone();
 
         let chargeback_amount = transaction.amount.ok_or(TransactionError::InvalidAmount)?;
 
-        // Like the dispute, and resolve, only deposits can be charged back
-        if transaction.tx_type == TransactionType::Deposit {
-            if client.held < chargeback_amount {
-                return Err(TransactionError::InsufficientFunds(self.client_id));
-            }
+        stored.move_to(DisputeState::ChargedBack, &self.cause)?;
 
-            if !transaction.in_dispute {
-                return Err(TransactionError::InvalidTransaction);
+        let balance = client.balance_mut(transaction.currency());
+        match transaction.tx_type {
+            // The withdrawal is reversed, the provisional credit becomes real
+            TransactionType::Withdrawal => {
+                balance.held -= chargeback_amount;
+                balance.available += chargeback_amount;
             }
+            // Chargeback the amount
+            _ => {
+                balance.held -= chargeback_amount;
+                balance.total -= chargeback_amount;
+            }
+        }
+        // Chargeback locks the client account, unless the policy says otherwise
+        client.locked = self.policy.chargeback_locks_account();
+
+        if transaction.tx_type == TransactionType::Transfer {
+            let sender = clients
+                .get_client_mut(&transaction.client_id)
+                .ok_or(TransactionError::MissingClient(transaction.client_id))?
+                .balance_mut(transaction.currency());
+
+            sender.available += chargeback_amount;
+            sender.total += chargeback_amount;
+        }
+
+        Ok(())
+    }
 
-            // Chargeback the amount and lock the account
-            client.held -= chargeback_amount;
-            client.total -= chargeback_amount;
-            // Chargeback locks the client account
-            client.locked = true;
-            transaction.in_dispute = false;
-
-            // Reverting the changes if the transaction is incorrect
-            if !client.is_valid() {
-                client.held += chargeback_amount;
-                client.total += chargeback_amount;
-                client.locked = false;
-                transaction.in_dispute = true;
-
-                return Err(TransactionError::RevertInvalidTransaction);
+    fn revert(
+        &self,
+        clients: &mut ClientList,
+        transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        let stored = transactions
+            .get_mut(&self.tx_id)
+            .ok_or(TransactionError::MissingTransactionId)?;
+        let transaction = stored.transaction.clone();
+
+        let chargeback_amount = transaction.amount.ok_or(TransactionError::InvalidAmount)?;
+
+        let balance = client.balance_mut(transaction.currency());
+        match transaction.tx_type {
+            TransactionType::Withdrawal => {
+                balance.held += chargeback_amount;
+                balance.available -= chargeback_amount;
+            }
+            _ => {
+                balance.held += chargeback_amount;
+                balance.total += chargeback_amount;
             }
         }
+        // The client could not have been locked before, validate() checks that
+        client.locked = false;
+        stored.undo_move();
+
+        if transaction.tx_type == TransactionType::Transfer {
+            let sender = clients
+                .get_client_mut(&transaction.client_id)
+                .ok_or(TransactionError::MissingClient(transaction.client_id))?
+                .balance_mut(transaction.currency());
+
+            sender.available -= chargeback_amount;
+            sender.total -= chargeback_amount;
+        }
 
         Ok(())
     }
+
+    // The held funds are taken back by the card network, unless they go back to the
+    // sender of a transfer, or to the client for a withdrawal that is reversed
+    fn lines(&self, transactions: &TransactionManager) -> Vec<(Currency, Line)> {
+        let Some(transaction) = transactions
+            .get(&self.tx_id)
+            .map(|stored| &stored.transaction)
+        else {
+            return Vec::new();
+        };
+        let currency = transaction.currency();
+        let amount = transaction.amount.unwrap_or_default();
+
+        let to = match transaction.tx_type {
+            TransactionType::Withdrawal => Account::Available(self.client_id),
+            TransactionType::Transfer => Account::Available(transaction.client_id),
+            _ => Account::ChargebackLoss,
+        };
+
+        vec![
+            (currency, Line::debit(Account::Held(self.client_id), amount)),
+            (currency, Line::credit(to, amount)),
+        ]
+    }
 }
@@ -0,0 +1,81 @@
+use crate::{
+    clients::{ClientId, ClientList},
+    transactions::{manager::TransactionManager, Transaction, TransactionError},
+};
+
+use super::TransactionOp;
+
+// Closes an account for good. The funds in every currency have to be paid out first, and a
+// locked account has to be unlocked, so the lock is not lost on a revert.
+#[derive(Debug)]
+pub struct Close {
+    client_id: ClientId,
+}
+
+impl Close {
+    pub fn new(transaction: &Transaction) -> Result<Self, TransactionError> {
+        Ok(Self {
+            client_id: transaction.client_id,
+        })
+    }
+}
+
+impl TransactionOp for Close {
+    fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    fn validate(
+        &self,
+        clients: &ClientList,
+        _transactions: &TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        if client.closed {
+            return Err(TransactionError::ClosedClient(self.client_id));
+        }
+
+        if client.locked {
+            return Err(TransactionError::LockedClient(self.client_id));
+        }
+
+        if !client.is_empty() {
+            return Err(TransactionError::NonZeroBalance(self.client_id));
+        }
+
+        Ok(())
+    }
+
+    fn apply(
+        &self,
+        clients: &mut ClientList,
+        _transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        client.closed = true;
+        client.locked = true;
+
+        Ok(())
+    }
+
+    fn revert(
+        &self,
+        clients: &mut ClientList,
+        _transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        client.closed = false;
+        client.locked = false;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use std::cell::RefCell;
+
+use crate::{
+    clients::{Balance, Client, ClientId, ClientList},
+    currency::Currency,
+    ledger::{Account, Line},
+    policy::Policy,
+    round_amount_down,
+    transactions::{
+        lifecycle::{Conversion, StoredTransaction},
+        manager::TransactionManager,
+        Transaction, TransactionError,
+    },
+};
+
+use super::TransactionOp;
+
+// Moves funds from one currency of a client to another at the rate in the policy's
+// rate table. The amount is in the currency being converted from. The converted
+// amount is rounded down, and what is rounded off goes to the house account.
+#[derive(Debug)]
+pub struct Convert {
+    client_id: ClientId,
+    house_account: ClientId,
+    amount: Decimal,
+    from: Currency,
+    to: Currency,
+    conversion: Conversion,
+    // The day the client's daily and monthly limits in `from` count the conversion
+    // for, see `Limits`
+    day: Option<NaiveDate>,
+    // The balances `apply` added, which `revert` takes out again
+    created: RefCell<Vec<(ClientId, Currency)>>,
+}
+
+impl Convert {
+    pub fn new(transaction: &Transaction, policy: &Policy) -> Result<Self, TransactionError> {
+        let amount = transaction.amount.ok_or(TransactionError::MissingAmount)?;
+        let from = transaction.currency();
+        let to = transaction
+            .to_currency
+            .ok_or(TransactionError::MissingTargetCurrency)?;
+
+        // The policy can't have rates without one, see `Policy::check`
+        let house_account = policy
+            .house_account()
+            .ok_or(TransactionError::InvalidTransaction)?;
+
+        // Rows without a timestamp use the latest rate
+        let rate = policy
+            .rates()
+            .rate(from, to, transaction.timestamp)
+            .ok_or(TransactionError::MissingRate(from, to))?;
+
+        let converted = amount
+            .checked_mul(rate)
+            .ok_or(TransactionError::InvalidAmount)?;
+        let credited = round_amount_down(converted);
+
+        Ok(Self {
+            client_id: transaction.client_id,
+            house_account,
+            amount,
+            from,
+            to,
+            conversion: Conversion {
+                rate,
+                credited,
+                remainder: converted - credited,
+            },
+            day: transaction.timestamp.map(|at| at.date_naive()),
+            created: RefCell::new(Vec::new()),
+        })
+    }
+
+    // The balance in the currency, remembering it was added if the client had none
+    fn balance_mut<'c>(&self, client: &'c mut Client, currency: Currency) -> &'c mut Balance {
+        if !client.balances.contains_key(&currency) {
+            self.created.borrow_mut().push((client.id, currency));
+        }
+
+        client.balance_mut(currency)
+    }
+}
+
+impl TransactionOp for Convert {
+    fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    // The house gets what the rounding took off
+    fn counterparty_id(&self, _transactions: &TransactionManager) -> Option<ClientId> {
+        Some(self.house_account)
+    }
+
+    fn validate(
+        &self,
+        clients: &ClientList,
+        _transactions: &TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        // A conversion that rounds to nothing would take funds and give none back
+        if self.amount <= Decimal::from(0) || self.conversion.credited <= Decimal::from(0) {
+            return Err(TransactionError::InvalidAmount);
+        }
+
+        if self.from == self.to {
+            return Err(TransactionError::InvalidTransaction);
+        }
+
+        if client.locked {
+            return Err(TransactionError::LockedClient(self.client_id));
+        }
+
+        if client.spendable(self.from) < self.amount {
+            return Err(TransactionError::InsufficientFunds(self.client_id));
+        }
+
+        if !client.limits(self.from).allows(self.amount, self.day) {
+            return Err(TransactionError::LimitExceeded(self.client_id));
+        }
+
+        Ok(())
+    }
+
+    fn apply(
+        &self,
+        clients: &mut ClientList,
+        _transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        // What leaves the `from` currency counts against its limits like a withdrawal
+        if let Some(limits) = client.limits.get_mut(&self.from) {
+            limits.spend(self.amount, self.day);
+        }
+
+        let from_balance = self.balance_mut(client, self.from);
+        from_balance.available -= self.amount;
+        from_balance.total -= self.amount;
+
+        let to_balance = self.balance_mut(client, self.to);
+        to_balance.available += self.conversion.credited;
+        to_balance.total += self.conversion.credited;
+
+        if self.conversion.remainder != Decimal::from(0) {
+            let house = clients
+                .get_client_mut(&self.house_account)
+                .ok_or(TransactionError::MissingClient(self.house_account))?;
+
+            let house_balance = self.balance_mut(house, self.to);
+            house_balance.available += self.conversion.remainder;
+            house_balance.total += self.conversion.remainder;
+        }
+
+        Ok(())
+    }
+
+    fn revert(
+        &self,
+        clients: &mut ClientList,
+        _transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        if self.conversion.remainder != Decimal::from(0) {
+            let house = clients
+                .get_client_mut(&self.house_account)
+                .ok_or(TransactionError::MissingClient(self.house_account))?;
+
+            let house_balance = house.balance_mut(self.to);
+            house_balance.available -= self.conversion.remainder;
+            house_balance.total -= self.conversion.remainder;
+        }
+
+        let client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        let to_balance = client.balance_mut(self.to);
+        to_balance.available -= self.conversion.credited;
+        to_balance.total -= self.conversion.credited;
+
+        let from_balance = client.balance_mut(self.from);
+        from_balance.available += self.amount;
+        from_balance.total += self.amount;
+
+        if let Some(limits) = client.limits.get_mut(&self.from) {
+            limits.unspend(self.amount, self.day);
+        }
+
+        // A currency the client did not have before should not show up in the output
+        for (client_id, currency) in self.created.take() {
+            let Some(client) = clients.get_client_mut(&client_id) else {
+                continue;
+            };
+
+            if client.balance(currency) == Balance::default() {
+                client.balances.remove(&currency);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn record(&self, stored: &mut StoredTransaction) {
+        stored.conversion = Some(self.conversion);
+    }
+
+    // The exchange buys the funds in one currency and pays them out in the other,
+    // the remainder going to the house
+    fn lines(&self, _transactions: &TransactionManager) -> Vec<(Currency, Line)> {
+        let Conversion {
+            credited,
+            remainder,
+            ..
+        } = self.conversion;
+
+        vec![
+            (
+                self.from,
+                Line::debit(Account::Available(self.client_id), self.amount),
+            ),
+            (self.from, Line::credit(Account::Exchange, self.amount)),
+            (
+                self.to,
+                Line::debit(Account::Exchange, credited + remainder),
+            ),
+            (
+                self.to,
+                Line::credit(Account::Available(self.client_id), credited),
+            ),
+            (
+                self.to,
+                Line::credit(Account::Available(self.house_account), remainder),
+            ),
+        ]
+    }
+}
@@ -2,13 +2,18 @@ use rust_decimal::Decimal;
 
 use crate::{
     clients::{ClientId, ClientList},
-    transactions::{Transaction, TransactionError},
+    currency::Currency,
+    ledger::{Account, Line},
+    transactions::{manager::TransactionManager, Transaction, TransactionError},
 };
 
+use super::TransactionOp;
+
 #[derive(Debug)]
 pub struct Deposit {
     client_id: ClientId,
     amount: Decimal,
+    currency: Currency,
 }
 
 impl Deposit {
@@ -18,15 +23,30 @@ impl Deposit {
         Ok(Self {
             client_id: transaction.client_id,
             amount,
+            currency: transaction.currency(),
         })
     }
+}
 
-    pub fn process(&self, clients: &mut ClientList) -> Result<(), TransactionError> {
-        // Doing the sanity checks before making any changes
-        let client = clients.get_or_create_client(&self.client_id);
+impl TransactionOp for Deposit {
+    fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    fn creates_client(&self) -> bool {
+        true
+    }
 
-        let deposit_amount = self.amount;
-        if deposit_amount < Decimal::from(0) {
+    fn validate(
+        &self,
+        clients: &ClientList,
+        _transactions: &TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        if self.amount < Decimal::from(0) {
             return Err(TransactionError::InvalidAmount);
         }
 
@@ -34,17 +54,49 @@ impl Deposit {
             return Err(TransactionError::LockedClient(self.client_id));
         }
 
-        // Making the changes
-        client.available += deposit_amount;
-        client.total += deposit_amount;
+        Ok(())
+    }
 
-        // Reverting the changes if the transaction is incorrect
-        if !client.is_valid() {
-            client.available -= deposit_amount;
-            client.total -= deposit_amount;
-            return Err(TransactionError::RevertInvalidTransaction);
-        }
+    fn apply(
+        &self,
+        clients: &mut ClientList,
+        _transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let balance = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?
+            .balance_mut(self.currency);
+
+        balance.available += self.amount;
+        balance.total += self.amount;
+
+        Ok(())
+    }
+
+    fn revert(
+        &self,
+        clients: &mut ClientList,
+        _transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let balance = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?
+            .balance_mut(self.currency);
+
+        balance.available -= self.amount;
+        balance.total -= self.amount;
 
         Ok(())
     }
+
+    // The funds come in through the house's cash
+    fn lines(&self, _transactions: &TransactionManager) -> Vec<(Currency, Line)> {
+        vec![
+            (self.currency, Line::debit(Account::HouseCash, self.amount)),
+            (
+                self.currency,
+                Line::credit(Account::Available(self.client_id), self.amount),
+            ),
+        ]
+    }
 }
@@ -1,32 +1,56 @@
+use chrono::{DateTime, Utc};
+
 use crate::{
     clients::{ClientId, ClientList},
+    currency::Currency,
+    ledger::{Account, Line},
+    policy::Policy,
     transactions::{
-        manager::TransactionManager, Transaction, TransactionError, TransactionId, TransactionType,
+        lifecycle::{CauseRow, DisputeState},
+        manager::TransactionManager,
+        Transaction, TransactionError, TransactionId, TransactionType,
     },
 };
 
+use super::TransactionOp;
+
 #[derive(Debug)]
-pub struct Dispute {
+pub struct Dispute<'a> {
     tx_id: TransactionId,
     client_id: ClientId,
+    cause: CauseRow,
+    // Only set when the row names a currency, which has to be the one of the transaction
+    currency: Option<Currency>,
+    // When the dispute was made, the policy's dispute window counts up to this
+    at: Option<DateTime<Utc>>,
+    policy: &'a Policy,
 }
 
-impl Dispute {
-    pub fn new(transaction: &Transaction) -> Result<Self, TransactionError> {
+impl<'a> Dispute<'a> {
+    pub fn new(transaction: &Transaction, policy: &'a Policy) -> Result<Self, TransactionError> {
         Ok(Self {
             tx_id: transaction.tx_id,
             client_id: transaction.client_id,
+            cause: CauseRow::from(transaction),
+            currency: transaction.currency,
+            at: transaction.timestamp,
+            policy,
         })
     }
+}
+
+impl TransactionOp for Dispute<'_> {
+    fn client_id(&self) -> ClientId {
+        self.client_id
+    }
 
-    pub fn process(
+    fn validate(
         &self,
-        clients: &mut ClientList,
-        transactions: &mut TransactionManager,
+        clients: &ClientList,
+        transactions: &TransactionManager,
     ) -> Result<(), TransactionError> {
-        // Doing the sanity checks before making any changes
         let client = clients
-            .get_client_mut(&self.client_id)
+            .get_client(&self.client_id)
             // Client is actually needed here
             .ok_or(TransactionError::MissingClient(self.client_id))?;
 
@@ -34,34 +58,158 @@ impl Dispute {
             return Err(TransactionError::LockedClient(self.client_id));
         }
 
-        // Making the changes
-        let transaction = transactions
-            .get_mut(&self.tx_id)
+        let stored = transactions
+            .get(&self.tx_id)
             .ok_or(TransactionError::MissingTransactionId)?;
+        let transaction = &stored.transaction;
+
+        // A client can only act on its own transactions
+        if transaction.disputing_client_id() != self.client_id {
+            return Err(TransactionError::ClientMismatch(self.tx_id, self.client_id));
+        }
+
+        // The funds are held in the currency they were moved in
+        if self
+            .currency
+            .is_some_and(|currency| currency != transaction.currency())
+        {
+            return Err(TransactionError::CurrencyMismatch(self.tx_id));
+        }
+
+        if !self.policy.is_disputable(transaction) {
+            return Err(TransactionError::NotDisputable(self.tx_id));
+        }
+
+        // There is no telling how old a transaction without a timestamp is, so the
+        // window only applies to the ones with one. A dispute of one of those needs a
+        // timestamp too, leaving it out can't get around the window.
+        if let Some(timestamp) = transaction.timestamp {
+            let window = self.policy.dispute_window();
+            match self.at {
+                Some(at) if at < timestamp => {
+                    return Err(TransactionError::DisputeBeforeTransaction(self.tx_id))
+                }
+                Some(at) if window.is_some_and(|window| at - timestamp > window) => {
+                    return Err(TransactionError::DisputeWindowExpired(self.tx_id))
+                }
+                None if window.is_some() => {
+                    return Err(TransactionError::MissingTimestamp(self.tx_id))
+                }
+                _ => (),
+            }
+        }
+
+        // A transaction can only be disputed once
+        stored.check_move(DisputeState::Disputed)?;
 
         let dispute_amount = transaction.amount.ok_or(TransactionError::InvalidAmount)?;
 
-        // It does not make sense to dispute anything other than a deposit
-        if transaction.tx_type == TransactionType::Deposit {
-            if client.available < dispute_amount {
-                return Err(TransactionError::InsufficientFunds(self.client_id));
+        // Holding a credit needs the funds (or the overdraft) to still be there, unless
+        // the policy allows going negative. A disputed withdrawal adds a provisional credit instead.
+        if transaction.tx_type != TransactionType::Withdrawal
+            && !self.policy.allow_negative_balance()
+            && client.spendable(transaction.currency()) < dispute_amount
+        {
+            return Err(TransactionError::InsufficientFunds(self.client_id));
+        }
+
+        Ok(())
+    }
+
+    fn apply(
+        &self,
+        clients: &mut ClientList,
+        transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        let stored = transactions
+            .get_mut(&self.tx_id)
+            .ok_or(TransactionError::MissingTransactionId)?;
+
+        let dispute_amount = stored
+            .transaction
+            .amount
+            .ok_or(TransactionError::InvalidAmount)?;
+
+        stored.move_to(DisputeState::Disputed, &self.cause)?;
+
+        let balance = client.balance_mut(stored.transaction.currency());
+        match stored.transaction.tx_type {
+            // The withdrawn funds are held as a provisional credit until the dispute ends
+            TransactionType::Withdrawal => {
+                balance.held += dispute_amount;
+                balance.total += dispute_amount;
+            }
+            _ => {
+                balance.available -= dispute_amount;
+                balance.held += dispute_amount;
             }
+        }
 
-            client.available -= dispute_amount;
-            client.held += dispute_amount;
+        Ok(())
+    }
+
+    fn revert(
+        &self,
+        clients: &mut ClientList,
+        transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
 
-            transaction.in_dispute = true;
+        let stored = transactions
+            .get_mut(&self.tx_id)
+            .ok_or(TransactionError::MissingTransactionId)?;
 
-            // Reverting the changes if the transaction is incorrect
-            if !client.is_valid() {
-                client.available += dispute_amount;
-                client.held -= dispute_amount;
-                transaction.in_dispute = false;
+        let dispute_amount = stored
+            .transaction
+            .amount
+            .ok_or(TransactionError::InvalidAmount)?;
 
-                return Err(TransactionError::RevertInvalidTransaction);
+        let balance = client.balance_mut(stored.transaction.currency());
+        match stored.transaction.tx_type {
+            TransactionType::Withdrawal => {
+                balance.held -= dispute_amount;
+                balance.total -= dispute_amount;
+            }
+            _ => {
+                balance.available += dispute_amount;
+                balance.held -= dispute_amount;
             }
         }
 
+        stored.undo_move();
+
         Ok(())
     }
+
+    // The held funds come out of the available ones, or for a withdrawal, out of
+    // the house's cash as a provisional credit
+    fn lines(&self, transactions: &TransactionManager) -> Vec<(Currency, Line)> {
+        let Some(transaction) = transactions
+            .get(&self.tx_id)
+            .map(|stored| &stored.transaction)
+        else {
+            return Vec::new();
+        };
+        let currency = transaction.currency();
+        let amount = transaction.amount.unwrap_or_default();
+
+        let from = match transaction.tx_type {
+            TransactionType::Withdrawal => Account::HouseCash,
+            _ => Account::Available(self.client_id),
+        };
+
+        vec![
+            (currency, Line::debit(from, amount)),
+            (
+                currency,
+                Line::credit(Account::Held(self.client_id), amount),
+            ),
+        ]
+    }
 }
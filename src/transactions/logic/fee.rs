@@ -0,0 +1,122 @@
+use crate::{
+    clients::{ClientId, ClientList},
+    currency::Currency,
+    fees::Fee,
+    ledger::{Account, Line},
+    transactions::{lifecycle::StoredTransaction, manager::TransactionManager, TransactionError},
+};
+
+use super::TransactionOp;
+
+// Charges a fee along with another operation. The fee is taken from the available
+// funds the client has after the operation, so a deposit can pay for itself, and
+// either both happen or neither does.
+pub struct WithFee<'a> {
+    operation: Box<dyn TransactionOp + 'a>,
+    fee: Fee,
+}
+
+impl<'a> WithFee<'a> {
+    pub fn new(operation: Box<dyn TransactionOp + 'a>, fee: Fee) -> Self {
+        Self { operation, fee }
+    }
+
+    fn move_fee(
+        &self,
+        clients: &mut ClientList,
+        from: ClientId,
+        to: ClientId,
+    ) -> Result<(), TransactionError> {
+        let from_balance = clients
+            .get_client_mut(&from)
+            .ok_or(TransactionError::MissingClient(from))?
+            .balance_mut(self.fee.currency);
+
+        from_balance.available -= self.fee.amount;
+        from_balance.total -= self.fee.amount;
+
+        let to_balance = clients
+            .get_client_mut(&to)
+            .ok_or(TransactionError::MissingClient(to))?
+            .balance_mut(self.fee.currency);
+
+        to_balance.available += self.fee.amount;
+        to_balance.total += self.fee.amount;
+
+        Ok(())
+    }
+}
+
+impl TransactionOp for WithFee<'_> {
+    fn client_id(&self) -> ClientId {
+        self.operation.client_id()
+    }
+
+    fn creates_client(&self) -> bool {
+        self.operation.creates_client()
+    }
+
+    fn counterparty_id(&self, transactions: &TransactionManager) -> Option<ClientId> {
+        self.operation.counterparty_id(transactions)
+    }
+
+    fn fee_account(&self) -> Option<ClientId> {
+        Some(self.fee.house_account)
+    }
+
+    fn validate(
+        &self,
+        clients: &ClientList,
+        transactions: &TransactionManager,
+    ) -> Result<(), TransactionError> {
+        self.operation.validate(clients, transactions)
+    }
+
+    fn apply(
+        &self,
+        clients: &mut ClientList,
+        transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        self.operation.apply(clients, transactions)?;
+
+        let spendable = clients
+            .get_client(&self.fee.client_id)
+            .map(|client| client.spendable(self.fee.currency));
+
+        // `execute` only reverts once everything is applied, so this undoes the operation itself
+        if spendable.is_none_or(|spendable| spendable < self.fee.amount) {
+            self.operation.revert(clients, transactions)?;
+            return Err(TransactionError::InsufficientFunds(self.fee.client_id));
+        }
+
+        self.move_fee(clients, self.fee.client_id, self.fee.house_account)
+    }
+
+    fn revert(
+        &self,
+        clients: &mut ClientList,
+        transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        self.move_fee(clients, self.fee.house_account, self.fee.client_id)?;
+        self.operation.revert(clients, transactions)
+    }
+
+    fn record(&self, stored: &mut StoredTransaction) {
+        self.operation.record(stored);
+        stored.fee = Some(self.fee);
+    }
+
+    // The fee goes from the client to the house account, on top of the operation's lines
+    fn lines(&self, transactions: &TransactionManager) -> Vec<(Currency, Line)> {
+        let mut lines = self.operation.lines(transactions);
+        lines.push((
+            self.fee.currency,
+            Line::debit(Account::Available(self.fee.client_id), self.fee.amount),
+        ));
+        lines.push((
+            self.fee.currency,
+            Line::credit(Account::Available(self.fee.house_account), self.fee.amount),
+        ));
+        lines
+    }
+}
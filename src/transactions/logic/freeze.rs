@@ -0,0 +1,70 @@
+use crate::{
+    clients::{ClientId, ClientList},
+    transactions::{manager::TransactionManager, Transaction, TransactionError},
+};
+
+use super::TransactionOp;
+
+// Locks an account by hand, it stays locked until an unlock
+#[derive(Debug)]
+pub struct Freeze {
+    client_id: ClientId,
+}
+
+impl Freeze {
+    pub fn new(transaction: &Transaction) -> Result<Self, TransactionError> {
+        Ok(Self {
+            client_id: transaction.client_id,
+        })
+    }
+}
+
+impl TransactionOp for Freeze {
+    fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    fn validate(
+        &self,
+        clients: &ClientList,
+        _transactions: &TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        if client.locked {
+            return Err(TransactionError::LockedClient(self.client_id));
+        }
+
+        Ok(())
+    }
+
+    fn apply(
+        &self,
+        clients: &mut ClientList,
+        _transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        client.locked = true;
+
+        Ok(())
+    }
+
+    fn revert(
+        &self,
+        clients: &mut ClientList,
+        _transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        client.locked = false;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use rust_decimal::Decimal;
+use std::cell::Cell;
+
+use crate::{
+    clients::{ClientId, ClientList},
+    currency::Currency,
+    limits::{LimitKind, Limits},
+    transactions::{manager::TransactionManager, Transaction, TransactionError},
+};
+
+use super::TransactionOp;
+
+// Sets one of a client's limits in the row's currency, an empty amount removes it.
+// An overdraft can't be lowered below what the client already owes, the client
+// would no longer be valid.
+#[derive(Debug)]
+pub struct Limit {
+    client_id: ClientId,
+    kind: LimitKind,
+    amount: Option<Decimal>,
+    currency: Currency,
+    // The limits before `apply`, so `revert` can put them back
+    previous: Cell<Option<Limits>>,
+}
+
+impl Limit {
+    pub fn new(transaction: &Transaction) -> Result<Self, TransactionError> {
+        let kind = transaction.limit.ok_or(TransactionError::MissingLimit)?;
+
+        Ok(Self {
+            client_id: transaction.client_id,
+            kind,
+            amount: transaction.amount,
+            currency: transaction.currency(),
+            previous: Cell::new(None),
+        })
+    }
+}
+
+impl TransactionOp for Limit {
+    fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    // A credit line can be set up before the first deposit
+    fn creates_client(&self) -> bool {
+        true
+    }
+
+    fn validate(
+        &self,
+        clients: &ClientList,
+        _transactions: &TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        if self.amount.is_some_and(|amount| amount < Decimal::from(0)) {
+            return Err(TransactionError::InvalidAmount);
+        }
+
+        if client.closed {
+            return Err(TransactionError::ClosedClient(self.client_id));
+        }
+
+        Ok(())
+    }
+
+    fn apply(
+        &self,
+        clients: &mut ClientList,
+        _transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        self.previous
+            .set(client.limits.get(&self.currency).copied());
+
+        let limits = client.limits_mut(self.currency);
+        match self.kind {
+            LimitKind::Overdraft => limits.overdraft = self.amount.unwrap_or_default(),
+            LimitKind::Daily => limits.daily = self.amount,
+            LimitKind::Monthly => limits.monthly = self.amount,
+        }
+
+        Ok(())
+    }
+
+    fn revert(
+        &self,
+        clients: &mut ClientList,
+        _transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        match self.previous.take() {
+            Some(limits) => client.limits.insert(self.currency, limits),
+            None => client.limits.remove(&self.currency),
+        };
+
+        Ok(())
+    }
+}
@@ -1,5 +1,240 @@
+use chrono::{DateTime, Utc};
+
+use crate::{
+    clients::{ClientId, ClientList},
+    currency::Currency,
+    ledger::{journal_entries, BalanceSnapshot, JournalEntry, Line},
+    policy::Policy,
+    transactions::{
+        lifecycle::StoredTransaction, manager::TransactionManager, Transaction, TransactionError,
+        TransactionType,
+    },
+};
+
+pub mod adjustment;
 pub mod chargeback;
+pub mod close;
+pub mod convert;
 pub mod deposit;
 pub mod dispute;
+pub mod fee;
+pub mod freeze;
+pub mod limit;
 pub mod resolve;
+pub mod transfer;
+pub mod unlock;
 pub mod withdrawal;
+
+use adjustment::Adjustment;
+use chargeback::Chargeback;
+use close::Close;
+use convert::Convert;
+use deposit::Deposit;
+use dispute::Dispute;
+use fee::WithFee;
+use freeze::Freeze;
+use limit::Limit;
+use resolve::Resolve;
+use transfer::Transfer;
+use unlock::Unlock;
+use withdrawal::Withdrawal;
+
+// Every transaction kind is split into three steps so that the rollback logic
+// only has to live in one place (see `execute` below). `validate` must not change
+// anything, `apply` makes the changes, and `revert` undoes exactly what `apply` did.
+pub trait TransactionOp {
+    // The client whose balances are changed by this operation
+    fn client_id(&self) -> ClientId;
+
+    // Deposits (and withdrawals, unless the policy says otherwise) create the client
+    // if it does not exist yet, everything else needs an existing client
+    fn creates_client(&self) -> bool {
+        false
+    }
+
+    // A second client changed by this operation, e.g. the receiving side of a transfer.
+    // It is created if it does not exist yet and has to stay valid like the first one.
+    fn counterparty_id(&self, _transactions: &TransactionManager) -> Option<ClientId> {
+        None
+    }
+
+    // The house account a fee for this operation is credited to. Like the
+    // counterparty it is created if needed and has to stay valid.
+    fn fee_account(&self) -> Option<ClientId> {
+        None
+    }
+
+    // Every client the operation can change, its own first, which are checked after
+    // it is applied.
+    // A storage backend loads these before running it, along with the stored
+    // transaction with the id of the row, which is the only one an operation uses.
+    fn involved(&self, transactions: &TransactionManager) -> Vec<ClientId> {
+        std::iter::once(self.client_id())
+            .chain(self.counterparty_id(transactions))
+            .chain(self.fee_account())
+            .collect()
+    }
+
+    fn validate(
+        &self,
+        clients: &ClientList,
+        transactions: &TransactionManager,
+    ) -> Result<(), TransactionError>;
+
+    fn apply(
+        &self,
+        clients: &mut ClientList,
+        transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError>;
+
+    fn revert(
+        &self,
+        clients: &mut ClientList,
+        transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError>;
+
+    // Adds what the operation worked out to the record of a stored transaction,
+    // this is only called when the operation succeeded
+    fn record(&self, _stored: &mut StoredTransaction) {}
+
+    // The debits and credits of what the operation did, by currency, this is only
+    // called once it is applied. The lines of every currency have to add up to zero,
+    // and the ones for clients to what their balances changed by (see `execute`).
+    fn lines(&self, _transactions: &TransactionManager) -> Vec<(Currency, Line)> {
+        Vec::new()
+    }
+}
+
+// Builds the operation that corresponds to the transaction type, following the policy's rules
+pub fn operation_for<'a>(
+    transaction: &Transaction,
+    policy: &'a Policy,
+) -> Result<Box<dyn TransactionOp + 'a>, TransactionError> {
+    transaction.check_row()?;
+
+    // Administrative transactions are only accepted from an operator the policy trusts
+    if transaction.tx_type.is_admin() && !policy.is_authorized(transaction) {
+        return Err(TransactionError::Unauthorized(transaction.tx_id));
+    }
+
+    let operation: Box<dyn TransactionOp + 'a> = match transaction.tx_type {
+        TransactionType::Deposit => Box::new(Deposit::new(transaction)?),
+        TransactionType::Withdrawal => Box::new(Withdrawal::new(transaction, policy)?),
+        TransactionType::Dispute => Box::new(Dispute::new(transaction, policy)?),
+        TransactionType::Resolve => Box::new(Resolve::new(transaction, policy)?),
+        TransactionType::Chargeback => Box::new(Chargeback::new(transaction, policy)?),
+        TransactionType::Transfer => Box::new(Transfer::new(transaction)?),
+        TransactionType::Convert => Box::new(Convert::new(transaction, policy)?),
+        TransactionType::Unlock => Box::new(Unlock::new(transaction)?),
+        TransactionType::Freeze => Box::new(Freeze::new(transaction)?),
+        TransactionType::Close => Box::new(Close::new(transaction)?),
+        TransactionType::Adjustment => Box::new(Adjustment::new(transaction, policy)?),
+        TransactionType::Limit => Box::new(Limit::new(transaction)?),
+        // An end of day is not an operation on one client (see `interest::end_of_day`),
+        // and interest is only ever paid by one
+        TransactionType::Eod | TransactionType::Interest => {
+            return Err(TransactionError::InvalidTransaction)
+        }
+    };
+
+    // Fees are charged on top of whatever the transaction does
+    match policy.fee_for(transaction) {
+        Some(fee) => Ok(Box::new(WithFee::new(operation, fee))),
+        None => Ok(operation),
+    }
+}
+
+// A new client starts out with the limits the policy has for it. Returns whether
+// the client had to be created.
+fn create_client(clients: &mut ClientList, client_id: ClientId, policy: &Policy) -> bool {
+    if clients.get_client(&client_id).is_some() {
+        return false;
+    }
+
+    let client = clients.get_or_create_client(&client_id);
+    for entry in policy.limits_for(client_id) {
+        entry.apply(client);
+    }
+
+    true
+}
+
+// Drives an operation through validate -> apply -> (revert if the client ends up invalid).
+// This is the single place where changes get rolled back, and where the journal
+// entries for what the operation changed are made, at the time of its row (`at`).
+// An operation whose entries don't balance, or don't match what it did to the
+// balances, is rolled back too.
+pub fn execute(
+    operation: &dyn TransactionOp,
+    clients: &mut ClientList,
+    transactions: &mut TransactionManager,
+    policy: &Policy,
+    at: Option<DateTime<Utc>>,
+) -> Result<Vec<JournalEntry>, TransactionError> {
+    let client_id = operation.client_id();
+    let involved = operation.involved(transactions);
+
+    // The client is created even if the operation fails, so it shows up in the output
+    if operation.creates_client() {
+        create_client(clients, client_id, policy);
+    }
+
+    // The others, after the operation's own client, are created if needed, and
+    // removed again if the operation fails
+    let created: Vec<ClientId> = involved
+        .iter()
+        .skip(1)
+        .copied()
+        .filter(|other_id| create_client(clients, *other_id, policy))
+        .collect();
+
+    let result = run(operation, clients, transactions, policy, at, &involved);
+    if result.is_err() {
+        for other_id in created {
+            clients.remove_client(&other_id);
+        }
+    }
+
+    result
+}
+
+fn run(
+    operation: &dyn TransactionOp,
+    clients: &mut ClientList,
+    transactions: &mut TransactionManager,
+    policy: &Policy,
+    at: Option<DateTime<Utc>>,
+    involved: &[ClientId],
+) -> Result<Vec<JournalEntry>, TransactionError> {
+    let before = BalanceSnapshot::take(clients, involved.iter().copied());
+
+    // Doing the sanity checks before making any changes
+    operation.validate(clients, transactions)?;
+
+    // Making the changes
+    operation.apply(clients, transactions)?;
+
+    // Reverting the changes if the transaction is incorrect
+    let is_valid = involved.iter().all(|id| {
+        clients.get_client(id).is_some_and(|client| {
+            if policy.allow_negative_balance() {
+                client.is_balanced()
+            } else {
+                client.is_valid()
+            }
+        })
+    });
+
+    if !is_valid {
+        operation.revert(clients, transactions)?;
+        return Err(TransactionError::RevertInvalidTransaction);
+    }
+
+    let entries = journal_entries(operation.lines(transactions), at)
+        .and_then(|entries| before.check(clients, &entries).map(|()| entries));
+    if entries.is_err() {
+        operation.revert(clients, transactions)?;
+    }
+
+    entries
+}
@@ -1,32 +1,51 @@
 use crate::{
     clients::{ClientId, ClientList},
+    currency::Currency,
+    ledger::{Account, Line},
+    policy::Policy,
     transactions::{
-        manager::TransactionManager, Transaction, TransactionError, TransactionId, TransactionType,
+        lifecycle::{CauseRow, DisputeState},
+        manager::TransactionManager,
+        Transaction, TransactionError, TransactionId, TransactionType,
     },
 };
 
+use super::TransactionOp;
+
 #[derive(Debug)]
-pub struct Resolve {
+pub struct Resolve<'a> {
     tx_id: TransactionId,
     client_id: ClientId,
+    cause: CauseRow,
+    // Only set when the row names a currency, which has to be the one of the transaction
+    currency: Option<Currency>,
+    policy: &'a Policy,
 }
 
-impl Resolve {
-    pub fn new(transaction: &Transaction) -> Result<Self, TransactionError> {
+impl<'a> Resolve<'a> {
+    pub fn new(transaction: &Transaction, policy: &'a Policy) -> Result<Self, TransactionError> {
         Ok(Self {
             tx_id: transaction.tx_id,
             client_id: transaction.client_id,
+            cause: CauseRow::from(transaction),
+            currency: transaction.currency,
+            policy,
         })
     }
+}
+
+impl TransactionOp for Resolve<'_> {
+    fn client_id(&self) -> ClientId {
+        self.client_id
+    }
 
-    pub fn process(
+    fn validate(
         &self,
-        clients: &mut ClientList,
-        transactions: &mut TransactionManager,
+        clients: &ClientList,
+        transactions: &TransactionManager,
     ) -> Result<(), TransactionError> {
-        // Doing the sanity checks before making any changes
         let client = clients
-            .get_client_mut(&self.client_id)
+            .get_client(&self.client_id)
             // Client is actually needed here
             .ok_or(TransactionError::MissingClient(self.client_id))?;
 
@@ -34,38 +53,132 @@ impl Resolve {
             return Err(TransactionError::LockedClient(self.client_id));
         }
 
-        // Making the changes
-        let transaction = transactions
-            .get_mut(&self.tx_id)
+        let stored = transactions
+            .get(&self.tx_id)
             .ok_or(TransactionError::MissingTransactionId)?;
+        let transaction = &stored.transaction;
+
+        // A client can only act on its own transactions
+        if transaction.disputing_client_id() != self.client_id {
+            return Err(TransactionError::ClientMismatch(self.tx_id, self.client_id));
+        }
+
+        // The funds are held in the currency they were moved in
+        if self
+            .currency
+            .is_some_and(|currency| currency != transaction.currency())
+        {
+            return Err(TransactionError::CurrencyMismatch(self.tx_id));
+        }
+
+        // Only what can be disputed can be resolved
+        if !self.policy.is_disputable(transaction) {
+            return Err(TransactionError::NotDisputable(self.tx_id));
+        }
+
+        // Only an open dispute can be resolved
+        stored.check_move(DisputeState::Resolved)?;
 
         let resolve_amount = transaction.amount.ok_or(TransactionError::InvalidAmount)?;
 
-        // Only deposits can be disputed, only deposits can be resolved
-        if transaction.tx_type == TransactionType::Deposit {
-            if client.held < resolve_amount {
-                return Err(TransactionError::InsufficientFunds(self.client_id));
-            }
+        if client.balance(transaction.currency()).held < resolve_amount {
+            return Err(TransactionError::InsufficientFunds(self.client_id));
+        }
+
+        Ok(())
+    }
+
+    fn apply(
+        &self,
+        clients: &mut ClientList,
+        transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        let stored = transactions
+            .get_mut(&self.tx_id)
+            .ok_or(TransactionError::MissingTransactionId)?;
 
-            if !transaction.in_dispute {
-                return Err(TransactionError::InvalidTransaction);
+        let resolve_amount = stored
+            .transaction
+            .amount
+            .ok_or(TransactionError::InvalidAmount)?;
+
+        stored.move_to(DisputeState::Resolved, &self.cause)?;
+
+        let balance = client.balance_mut(stored.transaction.currency());
+        match stored.transaction.tx_type {
+            // The withdrawal stands, so the provisional credit is taken back
+            TransactionType::Withdrawal => {
+                balance.held -= resolve_amount;
+                balance.total -= resolve_amount;
+            }
+            // restore the amount
+            _ => {
+                balance.available += resolve_amount;
+                balance.held -= resolve_amount;
             }
+        }
 
-            // restore the amount and remove the dispute flag
-            client.available += resolve_amount;
-            client.held -= resolve_amount;
-            transaction.in_dispute = false;
+        Ok(())
+    }
 
-            // Reverting the changes if the transaction is incorrect
-            if !client.is_valid() {
-                client.available -= resolve_amount;
-                client.held += resolve_amount;
-                transaction.in_dispute = true;
+    fn revert(
+        &self,
+        clients: &mut ClientList,
+        transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        let stored = transactions
+            .get_mut(&self.tx_id)
+            .ok_or(TransactionError::MissingTransactionId)?;
+
+        let resolve_amount = stored
+            .transaction
+            .amount
+            .ok_or(TransactionError::InvalidAmount)?;
 
-                return Err(TransactionError::RevertInvalidTransaction);
+        let balance = client.balance_mut(stored.transaction.currency());
+        match stored.transaction.tx_type {
+            TransactionType::Withdrawal => {
+                balance.held += resolve_amount;
+                balance.total += resolve_amount;
+            }
+            _ => {
+                balance.available -= resolve_amount;
+                balance.held += resolve_amount;
             }
         }
 
+        stored.undo_move();
+
         Ok(())
     }
+
+    // The held funds go back where the dispute took them from
+    fn lines(&self, transactions: &TransactionManager) -> Vec<(Currency, Line)> {
+        let Some(transaction) = transactions
+            .get(&self.tx_id)
+            .map(|stored| &stored.transaction)
+        else {
+            return Vec::new();
+        };
+        let currency = transaction.currency();
+        let amount = transaction.amount.unwrap_or_default();
+
+        let to = match transaction.tx_type {
+            TransactionType::Withdrawal => Account::HouseCash,
+            _ => Account::Available(self.client_id),
+        };
+
+        vec![
+            (currency, Line::debit(Account::Held(self.client_id), amount)),
+            (currency, Line::credit(to, amount)),
+        ]
+    }
 }
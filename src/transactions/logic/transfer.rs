@@ -0,0 +1,166 @@
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+
+use crate::{
+    clients::{ClientId, ClientList},
+    currency::Currency,
+    ledger::{Account, Line},
+    transactions::{manager::TransactionManager, Transaction, TransactionError},
+};
+
+use super::TransactionOp;
+
+// Moves funds from one client to another, this is a withdrawal and a deposit
+// that either both happen or neither does
+#[derive(Debug)]
+pub struct Transfer {
+    client_id: ClientId,
+    to_client_id: ClientId,
+    amount: Decimal,
+    // Both sides of the transfer are in this currency
+    currency: Currency,
+    // The day the sender's daily and monthly limits count the transfer for, see `Limits`
+    day: Option<NaiveDate>,
+}
+
+impl Transfer {
+    pub fn new(transaction: &Transaction) -> Result<Self, TransactionError> {
+        let amount = transaction.amount.ok_or(TransactionError::MissingAmount)?;
+        let to_client_id = transaction
+            .to_client_id
+            .ok_or(TransactionError::MissingDestination)?;
+
+        Ok(Self {
+            client_id: transaction.client_id,
+            to_client_id,
+            amount,
+            currency: transaction.currency(),
+            day: transaction.timestamp.map(|at| at.date_naive()),
+        })
+    }
+}
+
+impl TransactionOp for Transfer {
+    fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    fn counterparty_id(&self, _transactions: &TransactionManager) -> Option<ClientId> {
+        Some(self.to_client_id)
+    }
+
+    fn validate(
+        &self,
+        clients: &ClientList,
+        _transactions: &TransactionManager,
+    ) -> Result<(), TransactionError> {
+        // The source needs funds, so unlike a withdrawal it is not created here
+        let from_client = clients
+            .get_client(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        let to_client = clients
+            .get_client(&self.to_client_id)
+            .ok_or(TransactionError::MissingClient(self.to_client_id))?;
+
+        if self.amount < Decimal::from(0) {
+            return Err(TransactionError::InvalidAmount);
+        }
+
+        if self.client_id == self.to_client_id {
+            return Err(TransactionError::InvalidTransaction);
+        }
+
+        if from_client.locked {
+            return Err(TransactionError::LockedClient(self.client_id));
+        }
+
+        if to_client.locked {
+            return Err(TransactionError::LockedClient(self.to_client_id));
+        }
+
+        if from_client.spendable(self.currency) < self.amount {
+            return Err(TransactionError::InsufficientFunds(self.client_id));
+        }
+
+        if !from_client
+            .limits(self.currency)
+            .allows(self.amount, self.day)
+        {
+            return Err(TransactionError::LimitExceeded(self.client_id));
+        }
+
+        Ok(())
+    }
+
+    fn apply(
+        &self,
+        clients: &mut ClientList,
+        _transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let from_client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        // The transfer counts against the sender's limits like a withdrawal
+        if let Some(limits) = from_client.limits.get_mut(&self.currency) {
+            limits.spend(self.amount, self.day);
+        }
+
+        let from_balance = from_client.balance_mut(self.currency);
+        from_balance.available -= self.amount;
+        from_balance.total -= self.amount;
+
+        let to_balance = clients
+            .get_client_mut(&self.to_client_id)
+            .ok_or(TransactionError::MissingClient(self.to_client_id))?
+            .balance_mut(self.currency);
+
+        to_balance.available += self.amount;
+        to_balance.total += self.amount;
+
+        Ok(())
+    }
+
+    fn revert(
+        &self,
+        clients: &mut ClientList,
+        _transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let to_balance = clients
+            .get_client_mut(&self.to_client_id)
+            .ok_or(TransactionError::MissingClient(self.to_client_id))?
+            .balance_mut(self.currency);
+
+        to_balance.available -= self.amount;
+        to_balance.total -= self.amount;
+
+        let from_client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        if let Some(limits) = from_client.limits.get_mut(&self.currency) {
+            limits.unspend(self.amount, self.day);
+        }
+
+        let from_balance = from_client.balance_mut(self.currency);
+        from_balance.available += self.amount;
+        from_balance.total += self.amount;
+
+        Ok(())
+    }
+
+    // The funds never leave the house, they go from one client account to the other
+    fn lines(&self, _transactions: &TransactionManager) -> Vec<(Currency, Line)> {
+        vec![
+            (
+                self.currency,
+                Line::debit(Account::Available(self.client_id), self.amount),
+            ),
+            (
+                self.currency,
+                Line::credit(Account::Available(self.to_client_id), self.amount),
+            ),
+        ]
+    }
+}
@@ -0,0 +1,74 @@
+use crate::{
+    clients::{ClientId, ClientList},
+    transactions::{manager::TransactionManager, Transaction, TransactionError},
+};
+
+use super::TransactionOp;
+
+// Reinstates a locked account, e.g. once a chargeback investigation is closed
+#[derive(Debug)]
+pub struct Unlock {
+    client_id: ClientId,
+}
+
+impl Unlock {
+    pub fn new(transaction: &Transaction) -> Result<Self, TransactionError> {
+        Ok(Self {
+            client_id: transaction.client_id,
+        })
+    }
+}
+
+impl TransactionOp for Unlock {
+    fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    fn validate(
+        &self,
+        clients: &ClientList,
+        _transactions: &TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        if client.closed {
+            return Err(TransactionError::ClosedClient(self.client_id));
+        }
+
+        if !client.locked {
+            return Err(TransactionError::InvalidTransaction);
+        }
+
+        Ok(())
+    }
+
+    fn apply(
+        &self,
+        clients: &mut ClientList,
+        _transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        client.locked = false;
+
+        Ok(())
+    }
+
+    fn revert(
+        &self,
+        clients: &mut ClientList,
+        _transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        client.locked = true;
+
+        Ok(())
+    }
+}
@@ -1,32 +1,59 @@
+use chrono::NaiveDate;
 use rust_decimal::Decimal;
 
 use crate::{
     clients::{ClientId, ClientList},
-    transactions::{Transaction, TransactionError},
+    currency::Currency,
+    ledger::{Account, Line},
+    policy::Policy,
+    transactions::{manager::TransactionManager, Transaction, TransactionError},
 };
 
+use super::TransactionOp;
+
 #[derive(Debug)]
 pub struct Withdrawal {
     client_id: ClientId,
     amount: Decimal,
+    currency: Currency,
+    creates_client: bool,
+    // The day the daily and monthly limits count the withdrawal for, see `Limits`
+    day: Option<NaiveDate>,
 }
 
 impl Withdrawal {
-    pub fn new(transaction: &Transaction) -> Result<Self, TransactionError> {
+    pub fn new(transaction: &Transaction, policy: &Policy) -> Result<Self, TransactionError> {
         let amount = transaction.amount.ok_or(TransactionError::MissingAmount)?;
 
         Ok(Self {
             client_id: transaction.client_id,
             amount,
+            currency: transaction.currency(),
+            creates_client: policy.withdrawal_creates_client(),
+            day: transaction.timestamp.map(|at| at.date_naive()),
         })
     }
+}
+
+impl TransactionOp for Withdrawal {
+    fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    fn creates_client(&self) -> bool {
+        self.creates_client
+    }
 
-    pub fn process(&self, clients: &mut ClientList) -> Result<(), TransactionError> {
-        // Doing the sanity checks before making any changes
-        let client = clients.get_or_create_client(&self.client_id);
+    fn validate(
+        &self,
+        clients: &ClientList,
+        _transactions: &TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
 
-        let withdrawal_amount = self.amount;
-        if withdrawal_amount < Decimal::from(0) {
+        if self.amount < Decimal::from(0) {
             return Err(TransactionError::InvalidAmount);
         }
 
@@ -34,21 +61,66 @@ impl Withdrawal {
             return Err(TransactionError::LockedClient(self.client_id));
         }
 
-        if client.available < withdrawal_amount {
+        if client.spendable(self.currency) < self.amount {
             return Err(TransactionError::InsufficientFunds(self.client_id));
         }
 
-        // Making the changes
-        client.available -= withdrawal_amount;
-        client.total -= withdrawal_amount;
+        if !client.limits(self.currency).allows(self.amount, self.day) {
+            return Err(TransactionError::LimitExceeded(self.client_id));
+        }
+
+        Ok(())
+    }
+
+    fn apply(
+        &self,
+        clients: &mut ClientList,
+        _transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
 
-        // Reverting the changes if the transaction is incorrect
-        if !client.is_valid() {
-            client.available += withdrawal_amount;
-            client.total += withdrawal_amount;
-            return Err(TransactionError::RevertInvalidTransaction);
+        // Only a currency with limits keeps count of what was spent
+        if let Some(limits) = client.limits.get_mut(&self.currency) {
+            limits.spend(self.amount, self.day);
         }
 
+        let balance = client.balance_mut(self.currency);
+        balance.available -= self.amount;
+        balance.total -= self.amount;
+
+        Ok(())
+    }
+
+    fn revert(
+        &self,
+        clients: &mut ClientList,
+        _transactions: &mut TransactionManager,
+    ) -> Result<(), TransactionError> {
+        let client = clients
+            .get_client_mut(&self.client_id)
+            .ok_or(TransactionError::MissingClient(self.client_id))?;
+
+        if let Some(limits) = client.limits.get_mut(&self.currency) {
+            limits.unspend(self.amount, self.day);
+        }
+
+        let balance = client.balance_mut(self.currency);
+        balance.available += self.amount;
+        balance.total += self.amount;
+
         Ok(())
     }
+
+    // The funds go out through the house's cash
+    fn lines(&self, _transactions: &TransactionManager) -> Vec<(Currency, Line)> {
+        vec![
+            (
+                self.currency,
+                Line::debit(Account::Available(self.client_id), self.amount),
+            ),
+            (self.currency, Line::credit(Account::HouseCash, self.amount)),
+        ]
+    }
 }
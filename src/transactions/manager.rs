@@ -1,29 +1,388 @@
-use crate::transactions::{Transaction, TransactionId};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use crate::{
+    errors::TpsError,
+    interest::{Posting, PostingId},
+    transactions::{
+        lifecycle::{DisputeState, StoredTransaction},
+        spill::{SpillEntries, SpillStore},
+        Transaction, TransactionError, TransactionId,
+    },
+};
+use lru::LruCache;
+use serde::{
+    de::{MapAccess, Visitor},
+    ser::{self, SerializeMap},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    fmt,
+    iter::Peekable,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-// This is very similar to the ClientList struct in clients.rs
-#[derive(Debug, Serialize, Deserialize, Default)]
-pub struct TransactionManager(HashMap<TransactionId, Transaction>);
+// Gives every spill file made by this process a different name
+static SPILL_FILES: AtomicUsize = AtomicUsize::new(0);
+
+// How much of the stored transactions the manager keeps, by default it keeps all
+// of them in memory forever
+#[derive(Debug, Clone, Default)]
+pub struct StoreLimits {
+    // The most transactions kept in memory, the least recently used ones past this
+    // are moved to the spill file
+    pub memory_capacity: Option<usize>,
+    // Where the spill file goes, a temporary file is used if this is not set
+    pub spill_path: Option<PathBuf>,
+    // Only this many of the most recent stored transactions can be disputed, older
+    // ones are dropped unless they are in an open dispute. This counts rows, unlike
+    // the policy's `dispute_window_days`, which goes by the time of the rows.
+    pub dispute_horizon_rows: Option<usize>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    // The order the transaction was stored in, used for the dispute horizon
+    seq: u64,
+    stored: StoredTransaction,
+}
+
+// This started out very similar to the ClientList struct in clients.rs, but the
+// transactions outgrow memory on large inputs. They are kept in two tiers, the most
+// recently used ones in memory and the rest in a spill file on disk.
+//
+// Lookups only look at memory, so `load` has to be called before working with a
+// transaction, and `trim` afterwards to get back within the limits.
+#[derive(Debug)]
+pub struct TransactionManager {
+    memory: LruCache<TransactionId, Entry>,
+    spill: Option<SpillStore>,
+    limits: StoreLimits,
+    // Oldest first, only kept when there is a dispute horizon
+    order: VecDeque<(u64, TransactionId)>,
+    next_seq: u64,
+    // The interest paid by the end of day rows, in the order it was paid. These
+    // aren't rows, so they are never disputed or spilled, and their ids are
+    // numbered apart from the rows' (see `insert_posting`).
+    postings: Vec<Posting>,
+    // How many payments were kept, taking them out doesn't start the count over
+    posting_count: u32,
+    // Whether the dispute horizon dropped a transaction, and with it its journal
+    // entries, so the journal no longer adds up to the balances
+    journal_truncated: bool,
+}
+
+impl Default for TransactionManager {
+    fn default() -> Self {
+        Self {
+            // The capacity is enforced by `trim`, so `load` can go over it for a moment
+            memory: LruCache::unbounded(),
+            spill: None,
+            limits: StoreLimits::default(),
+            order: VecDeque::new(),
+            next_seq: 0,
+            postings: Vec::new(),
+            posting_count: 0,
+            journal_truncated: false,
+        }
+    }
+}
 
 impl TransactionManager {
     pub fn new() -> Self {
         Self::default()
     }
 
+    // Applies the limits to the transactions stored so far and to every one stored after
+    pub fn set_limits(&mut self, limits: StoreLimits) -> Result<(), TpsError> {
+        if limits.memory_capacity.is_some() && self.spill.is_none() {
+            let path = limits.spill_path.clone().unwrap_or_else(|| {
+                std::env::temp_dir().join(format!(
+                    "tps-spill-{}-{}.sqlite",
+                    std::process::id(),
+                    SPILL_FILES.fetch_add(1, Ordering::Relaxed)
+                ))
+            });
+
+            self.spill = Some(SpillStore::create(&path)?);
+        }
+
+        if limits.dispute_horizon_rows.is_some() && self.limits.dispute_horizon_rows.is_none() {
+            let mut entries: Vec<(u64, TransactionId)> = self
+                .memory
+                .iter()
+                .map(|(tx_id, entry)| (entry.seq, *tx_id))
+                .collect();
+            entries.sort_unstable();
+            self.order = entries.into();
+        }
+
+        self.limits = limits;
+        self.trim()?;
+
+        Ok(())
+    }
+
+    // Stores a newly processed transaction, with no dispute history yet
     pub fn insert(&mut self, transaction: Transaction) {
-        self.0.insert(transaction.tx_id, transaction);
+        self.insert_stored(StoredTransaction::new(transaction));
+    }
+
+    // Stores a transaction along with its dispute state, e.g. when moving it between managers
+    pub fn insert_stored(&mut self, stored: StoredTransaction) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let tx_id = stored.transaction.tx_id;
+        if self.limits.dispute_horizon_rows.is_some() {
+            self.order.push_back((seq, tx_id));
+        }
+
+        self.memory.put(tx_id, Entry { seq, stored });
+    }
+
+    // Keeps the payment as the next one, which is what numbers it. Payments are
+    // always kept whole and in the order they were paid, so one that is moved
+    // between managers or loaded back gets the id it had.
+    pub fn insert_posting(&mut self, mut posting: Posting) {
+        self.posting_count += 1;
+        posting.id = PostingId::from(self.posting_count);
+        self.postings.push(posting);
+    }
+
+    pub fn journal_truncated(&self) -> bool {
+        self.journal_truncated
+    }
+
+    // Records that the journal is missing entries, e.g. in the state a snapshot
+    // taken with a dispute horizon was resumed from
+    pub fn mark_journal_truncated(&mut self) {
+        self.journal_truncated = true;
+    }
+
+    pub fn postings(&self) -> &[Posting] {
+        &self.postings
+    }
+
+    // Moves the interest payments out, e.g. to save them or to move them between managers
+    pub fn take_postings(&mut self) -> Vec<Posting> {
+        std::mem::take(&mut self.postings)
+    }
+
+    pub fn remove(&mut self, tx_id: &TransactionId) -> Option<StoredTransaction> {
+        self.memory.pop(tx_id).map(|entry| entry.stored)
     }
 
     pub fn contains(&self, tx_id: &TransactionId) -> bool {
-        self.0.contains_key(tx_id)
+        self.memory.contains(tx_id)
     }
 
-    pub fn get(&self, tx_id: &TransactionId) -> Option<&Transaction> {
-        self.0.get(tx_id)
+    pub fn get(&self, tx_id: &TransactionId) -> Option<&StoredTransaction> {
+        self.memory.peek(tx_id).map(|entry| &entry.stored)
+    }
+
+    pub fn get_mut(&mut self, tx_id: &TransactionId) -> Option<&mut StoredTransaction> {
+        self.memory.get_mut(tx_id).map(|entry| &mut entry.stored)
+    }
+
+    // Brings the transaction back into memory if it was spilled to disk
+    pub fn load(&mut self, tx_id: &TransactionId) -> Result<(), TransactionError> {
+        if self.memory.contains(tx_id) {
+            return Ok(());
+        }
+
+        let Some(spill) = &self.spill else {
+            return Ok(());
+        };
+
+        if let Some((seq, stored)) = spill.take(*tx_id)? {
+            self.memory.put(*tx_id, Entry { seq, stored });
+        }
+
+        Ok(())
     }
 
-    pub fn get_mut(&mut self, tx_id: &TransactionId) -> Option<&mut Transaction> {
-        self.0.get_mut(tx_id)
+    // Drops the transactions that fell out of the dispute horizon, then spills the
+    // least recently used ones until memory is back within its capacity
+    pub fn trim(&mut self) -> Result<(), TransactionError> {
+        self.expire()?;
+
+        let (Some(capacity), Some(spill)) = (self.limits.memory_capacity, &self.spill) else {
+            return Ok(());
+        };
+
+        if self.memory.len() <= capacity {
+            return Ok(());
+        }
+
+        let memory = &mut self.memory;
+        spill.batch(|spill| {
+            while memory.len() > capacity {
+                let Some((tx_id, entry)) = memory.pop_lru() else {
+                    break;
+                };
+                spill.put(tx_id, entry.seq, &entry.stored)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn expire(&mut self) -> Result<(), TransactionError> {
+        let Some(horizon) = self.limits.dispute_horizon_rows else {
+            return Ok(());
+        };
+
+        let oldest_kept = self.next_seq.saturating_sub(horizon as u64);
+
+        // Transactions in an open dispute can't be dropped without losing the held
+        // funds, so they go to the back to be checked again later. Every entry is
+        // looked at once at most, so this ends even if all of them are disputed.
+        for _ in 0..self.order.len() {
+            let Some(&(seq, tx_id)) = self.order.front() else {
+                break;
+            };
+
+            if seq >= oldest_kept {
+                break;
+            }
+            self.order.pop_front();
+
+            let dropped = match self.memory.peek(&tx_id) {
+                Some(entry) if entry.seq == seq => {
+                    let dropped = entry.stored.state != DisputeState::Disputed;
+                    if dropped {
+                        self.memory.pop(&tx_id);
+                    }
+                    dropped
+                }
+                // The transaction was removed, or moved and stored again under a new seq
+                Some(_) => true,
+                None => match &self.spill {
+                    Some(spill) => spill.expire(tx_id, seq)?,
+                    None => true,
+                },
+            };
+
+            if dropped {
+                self.journal_truncated = true;
+            } else {
+                self.order.push_back((seq, tx_id));
+            }
+        }
+
+        Ok(())
+    }
+
+    // The transactions in memory, in the order they were stored
+    fn memory_entries(&self) -> Vec<(u64, &StoredTransaction)> {
+        let mut entries: Vec<(u64, &StoredTransaction)> = self
+            .memory
+            .iter()
+            .map(|(_, entry)| (entry.seq, &entry.stored))
+            .collect();
+        entries.sort_unstable_by_key(|(seq, _)| *seq);
+        entries
+    }
+}
+
+// The transactions in memory and in the spill file, merged back into the order
+// they were stored in. Both are already in that order on their own.
+struct InOrder<M: Iterator, S: Iterator> {
+    memory: Peekable<M>,
+    spilled: Peekable<S>,
+}
+
+impl<T, M, S> Iterator for InOrder<M, S>
+where
+    M: Iterator<Item = (u64, T)>,
+    S: Iterator<Item = Result<(u64, T), TransactionError>>,
+{
+    type Item = Result<T, TransactionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let from_spill = match (self.memory.peek(), self.spilled.peek()) {
+            (Some((seq, _)), Some(Ok((spilled_seq, _)))) => spilled_seq < seq,
+            (_, Some(_)) => true,
+            (_, None) => false,
+        };
+
+        if from_spill {
+            self.spilled
+                .next()
+                .map(|spilled| spilled.map(|(_, stored)| stored))
+        } else {
+            self.memory.next().map(|(_, stored)| Ok(stored))
+        }
+    }
+}
+
+// Moves the transactions out in the order they were stored. The spill file is read
+// back as the iterator goes, so a failing file shows up as an error item.
+impl IntoIterator for TransactionManager {
+    type Item = Result<StoredTransaction, TransactionError>;
+    type IntoIter = Box<dyn Iterator<Item = Self::Item>>;
+
+    fn into_iter(mut self) -> Self::IntoIter {
+        let mut entries = Vec::with_capacity(self.memory.len());
+        while let Some((_, entry)) = self.memory.pop_lru() {
+            entries.push((entry.seq, entry.stored));
+        }
+        entries.sort_unstable_by_key(|(seq, _)| *seq);
+
+        Box::new(InOrder {
+            memory: entries.into_iter().peekable(),
+            spilled: SpillEntries::new(self.spill.take()).peekable(),
+        })
+    }
+}
+
+// Serialized as a map of id to transaction like before the spill file, in the
+// order the transactions were stored so the dispute horizon survives a snapshot
+impl Serialize for TransactionManager {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let memory = self
+            .memory_entries()
+            .into_iter()
+            .map(|(seq, stored)| (seq, Cow::Borrowed(stored)));
+        let spilled = SpillEntries::new(self.spill.as_ref())
+            .map(|spilled| spilled.map(|(seq, stored)| (seq, Cow::Owned(stored))));
+        let entries = InOrder {
+            memory: memory.peekable(),
+            spilled: spilled.peekable(),
+        };
+
+        let mut map = serializer.serialize_map(None)?;
+        for stored in entries {
+            let stored = stored.map_err(ser::Error::custom)?;
+            map.serialize_entry(&stored.transaction.tx_id, &stored)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for TransactionManager {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ManagerVisitor;
+
+        impl<'de> Visitor<'de> for ManagerVisitor {
+            type Value = TransactionManager;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a map of transaction ids to stored transactions")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut manager = TransactionManager::new();
+                while let Some((_, stored)) =
+                    map.next_entry::<TransactionId, StoredTransaction>()?
+                {
+                    manager.insert_stored(stored);
+                }
+                Ok(manager)
+            }
+        }
+
+        deserializer.deserialize_map(ManagerVisitor)
     }
 }
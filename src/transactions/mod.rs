@@ -1,21 +1,62 @@
-use crate::clients::ClientId;
+use crate::{clients::ClientId, currency::Currency, limits::LimitKind};
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use lifecycle::DisputeState;
 use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
 use thiserror::Error;
 
+pub mod batch;
+pub mod lifecycle;
 pub mod logic;
 pub mod manager;
+pub mod parallel;
 pub mod process;
+pub mod spill;
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct TransactionId(u32);
 
+impl Display for TransactionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 impl From<u32> for TransactionId {
     fn from(id: u32) -> Self {
         Self(id)
     }
 }
 
+impl From<TransactionId> for u32 {
+    fn from(id: TransactionId) -> Self {
+        id.0
+    }
+}
+
+// The support staff member that made an administrative transaction
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OperatorId(u32);
+
+impl Display for OperatorId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<u32> for OperatorId {
+    fn from(id: u32) -> Self {
+        Self(id)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
@@ -24,19 +65,306 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    Transfer,
+    // Moves funds between two currencies of the same client
+    Convert,
+    // Accrues interest for every client, see `interest::end_of_day`
+    Eod,
+    // Interest paid to a client, only an end of day pays it (see `interest::Posting`)
+    Interest,
+    // Administrative transactions, these need an operator
+    Unlock,
+    Freeze,
+    Close,
+    Adjustment,
+    Limit,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+impl TransactionType {
+    // Transactions that move money are kept around so they can be disputed later,
+    // and administrative ones are kept as the record of who changed an account
+    pub fn is_stored(&self) -> bool {
+        matches!(
+            self,
+            TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Transfer
+                | TransactionType::Convert
+                | TransactionType::Unlock
+                | TransactionType::Freeze
+                | TransactionType::Close
+                | TransactionType::Adjustment
+                | TransactionType::Limit
+        )
+    }
+
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            TransactionType::Unlock
+                | TransactionType::Freeze
+                | TransactionType::Close
+                | TransactionType::Adjustment
+                | TransactionType::Limit
+        )
+    }
+}
+
+// A transaction as the engine works with it. It is read and written as a
+// `TransactionRow`, which is where the names of the columns are.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "TransactionRow", into = "TransactionRow")]
 pub struct Transaction {
-    #[serde(rename = "type")]
     pub tx_type: TransactionType,
-    #[serde(rename = "client")]
     pub client_id: ClientId,
-    #[serde(rename = "tx")]
     pub tx_id: TransactionId,
     pub amount: Option<Decimal>, // using this Decimal type allows for desired precision
-    #[serde(default)] // useful for seeing disputes, defaults to false
-    pub in_dispute: bool,
+    // Only used by transfers, this is the client receiving the funds
+    pub to_client_id: Option<ClientId>,
+    // Only used by administrative transactions, this is who authorized it
+    pub operator_id: Option<OperatorId>,
+    // Rows without one are in the default currency, see `currency()`
+    pub currency: Option<Currency>,
+    // Only used by conversions, this is the currency the funds are converted to
+    pub to_currency: Option<Currency>,
+    // Only used by limit transactions, this is the limit being set
+    pub limit: Option<LimitKind>,
+    // Only used by end of day rows, this is the day interest is accrued up to
+    pub date: Option<NaiveDate>,
+    // When the transaction happened, in RFC 3339 (`2024-05-01T12:30:00Z`). Rows
+    // without one have no time, nothing goes by the clock of the machine.
+    pub timestamp: Option<DateTime<Utc>>,
+    // A timestamp that couldn't be read, the row is rejected for it (see
+    // `check_row`) rather than stopping the input
+    pub invalid_timestamp: Option<String>,
+    // Why the row couldn't be read at all, in which case the fields above are only
+    // placeholders (see `unreadable`) and the row is rejected for it too
+    pub unreadable: Option<String>,
+    // Where the transaction was read from, this is not part of the input itself
+    pub position: Option<Position>,
+    // The row exactly as it was in the input, for the reject report. It is dropped
+    // once the transaction is stored.
+    pub raw: Option<String>,
+}
+
+// The columns of a transaction, with the timestamp as it was given
+#[derive(Serialize, Deserialize)]
+struct TransactionRow {
+    #[serde(rename = "type")]
+    tx_type: TransactionType,
+    #[serde(rename = "client")]
+    client_id: ClientId,
+    #[serde(rename = "tx")]
+    tx_id: TransactionId,
+    #[serde(default, deserialize_with = "deserialize_amount")]
+    amount: Option<Decimal>,
+    #[serde(rename = "to", default)]
+    to_client_id: Option<ClientId>,
+    #[serde(rename = "operator", default)]
+    operator_id: Option<OperatorId>,
+    #[serde(default)]
+    currency: Option<Currency>,
+    #[serde(rename = "to_currency", default)]
+    to_currency: Option<Currency>,
+    #[serde(default)]
+    limit: Option<LimitKind>,
+    #[serde(default)]
+    date: Option<NaiveDate>,
+    #[serde(default)]
+    timestamp: Option<String>,
+}
+
+// Timestamps can be in any offset, they are kept in UTC. An empty column is no timestamp.
+impl From<TransactionRow> for Transaction {
+    fn from(row: TransactionRow) -> Self {
+        let (timestamp, invalid_timestamp) = match row.timestamp.filter(|value| !value.is_empty()) {
+            None => (None, None),
+            Some(value) => match DateTime::parse_from_rfc3339(&value) {
+                Ok(timestamp) => (Some(timestamp.with_timezone(&Utc)), None),
+                Err(_) => (None, Some(value)),
+            },
+        };
+
+        Self {
+            tx_type: row.tx_type,
+            client_id: row.client_id,
+            tx_id: row.tx_id,
+            amount: row.amount,
+            to_client_id: row.to_client_id,
+            operator_id: row.operator_id,
+            currency: row.currency,
+            to_currency: row.to_currency,
+            limit: row.limit,
+            date: row.date,
+            timestamp,
+            invalid_timestamp,
+            unreadable: None,
+            position: None,
+            raw: None,
+        }
+    }
+}
+
+// A timestamp that couldn't be read is written back as it was, so the row is
+// rejected the same way when it is read again (e.g. from the write-ahead log)
+impl From<Transaction> for TransactionRow {
+    fn from(transaction: Transaction) -> Self {
+        let timestamp = transaction.invalid_timestamp.or_else(|| {
+            transaction
+                .timestamp
+                .map(|timestamp| timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+        });
+
+        Self {
+            tx_type: transaction.tx_type,
+            client_id: transaction.client_id,
+            tx_id: transaction.tx_id,
+            amount: transaction.amount,
+            to_client_id: transaction.to_client_id,
+            operator_id: transaction.operator_id,
+            currency: transaction.currency,
+            to_currency: transaction.to_currency,
+            limit: transaction.limit,
+            date: transaction.date,
+            timestamp,
+        }
+    }
+}
+
+// The location of a row in the input, used to point at rejected rows
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: u64,
+    pub byte: u64,
+}
+
+impl Transaction {
+    // An end of day row, the client and id of which are not used
+    pub fn end_of_day(date: NaiveDate) -> Self {
+        Self {
+            tx_type: TransactionType::Eod,
+            client_id: ClientId::from(0),
+            tx_id: TransactionId(0),
+            amount: None,
+            to_client_id: None,
+            operator_id: None,
+            currency: None,
+            to_currency: None,
+            limit: None,
+            date: Some(date),
+            timestamp: None,
+            invalid_timestamp: None,
+            unreadable: None,
+            position: None,
+            raw: None,
+        }
+    }
+
+    // A row of the input that couldn't be read, e.g. one with an amount that isn't
+    // a number. It is passed along like any other so it is rejected in order, and
+    // nothing but `check_row` looks at its other fields.
+    pub fn unreadable(reason: String) -> Self {
+        Self {
+            tx_type: TransactionType::Deposit,
+            client_id: ClientId::from(0),
+            tx_id: TransactionId(0),
+            amount: None,
+            to_client_id: None,
+            operator_id: None,
+            currency: None,
+            to_currency: None,
+            limit: None,
+            date: None,
+            timestamp: None,
+            invalid_timestamp: None,
+            unreadable: Some(reason),
+            position: None,
+            raw: None,
+        }
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency.unwrap_or_default()
+    }
+
+    // A row that couldn't be read is rejected, and so is one with a timestamp that
+    // couldn't be, there is no telling when it happened
+    pub fn check_row(&self) -> Result<(), TransactionError> {
+        if let Some(reason) = &self.unreadable {
+            return Err(TransactionError::UnreadableRow(reason.clone()));
+        }
+
+        match &self.invalid_timestamp {
+            Some(value) => Err(TransactionError::InvalidTimestamp(value.clone())),
+            None => Ok(()),
+        }
+    }
+
+    // The client that received the funds of this transaction, and so is the only one
+    // that can dispute it. For everything but transfers this is the transaction's client.
+    pub fn disputing_client_id(&self) -> ClientId {
+        match self.tx_type {
+            TransactionType::Transfer => self.to_client_id.unwrap_or(self.client_id),
+            _ => self.client_id,
+        }
+    }
+}
+
+// Amounts can be strings, which keep every digit, or JSON numbers. A number has been
+// through a float by the time it gets here, which is exact up to 15 significant digits.
+fn deserialize_amount<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Decimal>, D::Error> {
+    struct AmountVisitor;
+
+    impl<'de> Visitor<'de> for AmountVisitor {
+        type Value = Option<Decimal>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("an amount as a string or a number")
+        }
+
+        fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D: Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_any(self)
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+            Ok(Some(Decimal::from(value)))
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<Self::Value, E> {
+            Ok(Some(Decimal::from(value)))
+        }
+
+        // The shortest text that gives back the same float, so 0.1 stays 0.1
+        fn visit_f64<E: de::Error>(self, value: f64) -> Result<Self::Value, E> {
+            self.visit_str(&value.to_string())
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            if value.is_empty() {
+                return Ok(None);
+            }
+
+            Decimal::from_str(value)
+                .or_else(|_| Decimal::from_scientific(value))
+                .map(Some)
+                .map_err(|_| E::custom(format!("invalid amount {value}")))
+        }
+    }
+
+    deserializer.deserialize_option(AmountVisitor)
 }
 
 #[derive(Error, Debug)]
@@ -64,4 +392,117 @@ pub enum TransactionError {
 
     #[error("Insufficient funds for client {0}")]
     InsufficientFunds(ClientId),
+
+    #[error("Transaction is missing a destination client")]
+    MissingDestination,
+
+    #[error("Duplicate transaction id {0}")]
+    DuplicateTransactionId(TransactionId),
+
+    #[error("Transaction {0} can't be disputed")]
+    NotDisputable(TransactionId),
+
+    #[error("Transaction {0} does not belong to client {1}")]
+    ClientMismatch(TransactionId, ClientId),
+
+    #[error("Transaction {0} is too old to be disputed")]
+    DisputeWindowExpired(TransactionId),
+
+    #[error("Transaction {0} can't go from {1} to {2}")]
+    InvalidStateChange(TransactionId, DisputeState, DisputeState),
+
+    #[error("Transaction {0} is not authorized")]
+    Unauthorized(TransactionId),
+
+    #[error("Client {0} is closed")]
+    ClosedClient(ClientId),
+
+    #[error("Client {0} still has funds")]
+    NonZeroBalance(ClientId),
+
+    #[error("Transaction {0} is in a different currency")]
+    CurrencyMismatch(TransactionId),
+
+    #[error("Transaction is missing a currency to convert to")]
+    MissingTargetCurrency,
+
+    #[error("No rate to convert {0} to {1}")]
+    MissingRate(Currency, Currency),
+
+    #[error("Client {0} is over a daily or monthly limit")]
+    LimitExceeded(ClientId),
+
+    #[error("Transaction is missing the limit to set")]
+    MissingLimit,
+
+    #[error("Transaction {0} was rejected by risk rule {1}")]
+    RiskRejected(TransactionId, String),
+
+    #[error("The journal entry in {0} does not add up to zero")]
+    UnbalancedEntry(Currency),
+
+    #[error("The journal does not match the {1} balances of client {0}")]
+    JournalMismatch(ClientId, Currency),
+
+    #[error("Row can't be read: {0}")]
+    UnreadableRow(String),
+
+    #[error("Invalid timestamp {0}, expected RFC 3339 like 2024-05-01T12:30:00Z")]
+    InvalidTimestamp(String),
+
+    #[error("Transaction {0} is disputed before it happened")]
+    DisputeBeforeTransaction(TransactionId),
+
+    #[error("Transaction {0} can only be disputed with a timestamp")]
+    MissingTimestamp(TransactionId),
+
+    #[error("End of day is missing its date")]
+    MissingDate,
+
+    #[error("End of day skips {0}, every day needs one")]
+    MissedEndOfDay(NaiveDate),
+
+    // The on-disk transaction store failed, unlike the others this stops processing
+    #[error("Transaction store error: {0}")]
+    Storage(String),
+}
+
+impl TransactionError {
+    // A stable identifier for the error, so callers don't have to match on the message
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransactionError::MissingTransactionId => "missing_transaction",
+            TransactionError::LockedClient(_) => "locked_client",
+            TransactionError::MissingClient(_) => "missing_client",
+            TransactionError::MissingAmount => "missing_amount",
+            TransactionError::InvalidAmount => "invalid_amount",
+            TransactionError::InvalidTransaction => "invalid_transaction",
+            TransactionError::RevertInvalidTransaction => "reverted",
+            TransactionError::InsufficientFunds(_) => "insufficient_funds",
+            TransactionError::MissingDestination => "missing_destination",
+            TransactionError::DuplicateTransactionId(_) => "duplicate_transaction",
+            TransactionError::NotDisputable(_) => "not_disputable",
+            TransactionError::ClientMismatch(..) => "client_mismatch",
+            TransactionError::DisputeWindowExpired(_) => "dispute_window_expired",
+            TransactionError::InvalidStateChange(..) => "invalid_state_change",
+            TransactionError::Unauthorized(_) => "unauthorized",
+            TransactionError::ClosedClient(_) => "closed_client",
+            TransactionError::NonZeroBalance(_) => "non_zero_balance",
+            TransactionError::CurrencyMismatch(_) => "currency_mismatch",
+            TransactionError::MissingTargetCurrency => "missing_target_currency",
+            TransactionError::MissingRate(..) => "missing_rate",
+            TransactionError::RiskRejected(..) => "risk_rejected",
+            TransactionError::LimitExceeded(_) => "limit_exceeded",
+            TransactionError::MissingLimit => "missing_limit",
+            TransactionError::UnbalancedEntry(_) => "unbalanced_entry",
+            TransactionError::JournalMismatch(..) => "journal_mismatch",
+            TransactionError::UnreadableRow(_) => "invalid_row",
+            TransactionError::InvalidTimestamp(_) => "invalid_timestamp",
+            TransactionError::DisputeBeforeTransaction(_) => "dispute_before_transaction",
+            TransactionError::MissingTimestamp(_) => "missing_timestamp",
+            TransactionError::MissingDate => "missing_date",
+            TransactionError::MissedEndOfDay(_) => "missed_end_of_day",
+            TransactionError::Storage(_) => "storage_error",
+        }
+    }
 }
@@ -0,0 +1,490 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{mpsc, Arc, Mutex, MutexGuard},
+    thread,
+};
+
+use crate::{
+    clients::{Client, ClientId, ClientList},
+    errors::TpsError,
+    interest::{accrue, as_of, check_end_of_day, paid_at, Posting},
+    policy::Policy,
+    rejects::{RejectSink, StderrRejects},
+};
+
+use super::{
+    manager::TransactionManager,
+    process::{process_transaction, process_transactions_reporting},
+    Transaction, TransactionError, TransactionId, TransactionType,
+};
+
+// The clients in a shard along with the transactions they can dispute
+#[derive(Default)]
+struct Shard {
+    clients: ClientList,
+    transactions: TransactionManager,
+}
+
+// A row sent to a worker. Duplicates are found by the coordinator, but they are
+// still passed along so the rejection is reported in order with the client's state
+enum Job {
+    Process(Transaction),
+    Reject(Transaction, TransactionError),
+}
+
+enum Message {
+    Process(Vec<Job>),
+    // Answered once everything sent before it has been processed
+    Barrier(mpsc::Sender<()>),
+}
+
+struct Worker {
+    sender: mpsc::Sender<Message>,
+    handle: thread::JoinHandle<Result<(), TpsError>>,
+}
+
+// Processes transactions on several threads. Every client belongs to exactly one
+// shard, and every shard is owned by one worker thread that processes its
+// transactions in the order they were read, so the results for each client are
+// the same as with `process_transactions`.
+//
+// Transfers between clients in different shards (and chargebacks of those transfers,
+// which credit the sender), and fees for a house account in another shard, can't be
+// handled by a single worker. For those all of
+// the workers are drained first and the operation is then run on the coordinating
+// thread, so they act as a barrier. This is slow, but expected to be rare. An end of
+// day is a barrier too, since it pays interest to the clients of every shard.
+pub struct ShardedProcessor {
+    shards: Vec<Arc<Mutex<Shard>>>,
+    workers: Vec<Worker>,
+    // Duplicate ids have to be found across all shards, so they are tracked here
+    seen_ids: HashSet<TransactionId>,
+    // Transfers that crossed shards, mapping to their (sender, receiver)
+    cross_shard_transfers: HashMap<TransactionId, (ClientId, ClientId)>,
+    // The interest paid by the end of day rows, these aren't kept by any one shard
+    postings: Vec<Posting>,
+    // Carried over from the state the processor started with
+    journal_truncated: bool,
+    policy: Policy,
+    rejects: SharedRejects,
+}
+
+// Lets every worker report to the same sink
+#[derive(Clone)]
+struct SharedRejects(Arc<Mutex<Box<dyn RejectSink + Send>>>);
+
+impl SharedRejects {
+    fn lock(&self) -> MutexGuard<'_, Box<dyn RejectSink + Send>> {
+        self.0.lock().expect("reject sink lock poisoned")
+    }
+}
+
+impl RejectSink for SharedRejects {
+    fn reject(
+        &mut self,
+        transaction: &Transaction,
+        error: &TransactionError,
+        client: Option<&Client>,
+    ) -> Result<(), TpsError> {
+        self.lock().reject(transaction, error, client)
+    }
+
+    fn flush(&mut self) -> Result<(), TpsError> {
+        self.lock().flush()
+    }
+}
+
+impl ShardedProcessor {
+    pub fn new(threads: usize) -> Result<Self, TpsError> {
+        Self::with_state(threads, ClientList::new(), TransactionManager::new())
+    }
+
+    // Splits an existing state (e.g. from a snapshot) across the shards
+    pub fn with_state(
+        threads: usize,
+        clients: ClientList,
+        transactions: TransactionManager,
+    ) -> Result<Self, TpsError> {
+        Self::with_reject_sink(
+            threads,
+            clients,
+            transactions,
+            Policy::default(),
+            Box::new(StderrRejects),
+        )
+    }
+
+    // Like `with_state`, but with the rules of `policy`, and with the rejected
+    // transactions from every shard going to `rejects`
+    pub fn with_reject_sink(
+        threads: usize,
+        clients: ClientList,
+        mut transactions: TransactionManager,
+        policy: Policy,
+        rejects: Box<dyn RejectSink + Send>,
+    ) -> Result<Self, TpsError> {
+        let threads = threads.max(1);
+        let mut shards: Vec<Shard> = (0..threads).map(|_| Shard::default()).collect();
+        let mut seen_ids = HashSet::new();
+        let mut cross_shard_transfers = HashMap::new();
+        let postings = transactions.take_postings();
+        let journal_truncated = transactions.journal_truncated();
+
+        for client in clients {
+            shards[shard_index(threads, client.id)]
+                .clients
+                .insert_client(client);
+        }
+
+        for stored in transactions {
+            let stored = stored?;
+            let transaction = &stored.transaction;
+            seen_ids.insert(transaction.tx_id);
+
+            let receiver = transaction.disputing_client_id();
+            if shard_index(threads, transaction.client_id) != shard_index(threads, receiver) {
+                cross_shard_transfers.insert(transaction.tx_id, (transaction.client_id, receiver));
+            }
+
+            shards[shard_index(threads, receiver)]
+                .transactions
+                .insert_stored(stored);
+        }
+
+        let shards: Vec<Arc<Mutex<Shard>>> = shards
+            .into_iter()
+            .map(|shard| Arc::new(Mutex::new(shard)))
+            .collect();
+
+        let rejects = SharedRejects(Arc::new(Mutex::new(rejects)));
+
+        let workers = shards
+            .iter()
+            .map(|shard| spawn_worker(Arc::clone(shard), policy.clone(), rejects.clone()))
+            .collect();
+
+        Ok(Self {
+            shards,
+            workers,
+            seen_ids,
+            cross_shard_transfers,
+            postings,
+            journal_truncated,
+            policy,
+            rejects,
+        })
+    }
+
+    pub fn process(&mut self, transactions: Vec<Transaction>) -> Result<(), TpsError> {
+        let mut batches: Vec<Vec<Job>> = (0..self.shards.len()).map(|_| Vec::new()).collect();
+
+        for transaction in transactions {
+            // A row that couldn't be read has no id or client to go by
+            if let Err(error) = transaction.check_row() {
+                batches[self.shard_for(transaction.client_id)]
+                    .push(Job::Reject(transaction, error));
+                continue;
+            }
+
+            if transaction.tx_type.is_stored() && !self.seen_ids.insert(transaction.tx_id) {
+                let error = TransactionError::DuplicateTransactionId(transaction.tx_id);
+                batches[self.shard_for(transaction.client_id)]
+                    .push(Job::Reject(transaction, error));
+                continue;
+            }
+
+            if transaction.tx_type == TransactionType::Eod {
+                self.dispatch(&mut batches)?;
+                self.wait_idle()?;
+                self.end_of_day(&transaction)?;
+                continue;
+            }
+
+            match self.cross_shard_clients(&transaction) {
+                Some(client_ids) => {
+                    self.dispatch(&mut batches)?;
+                    self.wait_idle()?;
+                    self.process_cross_shard(transaction, client_ids)?;
+                }
+                None => {
+                    batches[self.shard_for(transaction.client_id)].push(Job::Process(transaction))
+                }
+            }
+        }
+
+        self.dispatch(&mut batches)
+    }
+
+    // Waits for every worker to finish and puts the shards back together
+    pub fn finish(mut self) -> Result<(ClientList, TransactionManager), TpsError> {
+        self.join_workers()?;
+        self.rejects.flush()?;
+
+        let mut clients = ClientList::new();
+        let mut transactions = TransactionManager::new();
+        if self.journal_truncated {
+            transactions.mark_journal_truncated();
+        }
+
+        for posting in self.postings.drain(..) {
+            transactions.insert_posting(posting);
+        }
+
+        for shard in self.shards.drain(..) {
+            // The workers are gone, so this is the last reference to the shard
+            let shard = std::mem::take(&mut *lock(&shard));
+
+            for client in shard.clients {
+                clients.insert_client(client);
+            }
+
+            for stored in shard.transactions {
+                transactions.insert_stored(stored?);
+            }
+        }
+
+        Ok((clients, transactions))
+    }
+
+    fn shard_for(&self, client_id: ClientId) -> usize {
+        shard_index(self.shards.len(), client_id)
+    }
+
+    // The clients of an operation that spans shards, or None if a single worker can handle it
+    fn cross_shard_clients(&mut self, transaction: &Transaction) -> Option<Vec<ClientId>> {
+        let mut client_ids = vec![transaction.client_id];
+
+        match transaction.tx_type {
+            TransactionType::Transfer => {
+                if let Some(receiver) = transaction.to_client_id {
+                    if self.shard_for(transaction.client_id) != self.shard_for(receiver) {
+                        self.cross_shard_transfers
+                            .insert(transaction.tx_id, (transaction.client_id, receiver));
+                    }
+                    client_ids.push(receiver);
+                }
+            }
+            // Only a chargeback changes the sender of a transfer, and only when it comes
+            // from the receiver, otherwise the receiver's worker rejects it as usual
+            TransactionType::Chargeback => {
+                if let Some(&(sender, receiver)) =
+                    self.cross_shard_transfers.get(&transaction.tx_id)
+                {
+                    if receiver == transaction.client_id {
+                        client_ids.push(sender);
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        // A fee goes to the house account, which may be in yet another shard
+        client_ids.extend(
+            self.policy
+                .fee_for(transaction)
+                .map(|fee| fee.house_account),
+        );
+
+        let shard = self.shard_for(transaction.client_id);
+        client_ids
+            .iter()
+            .any(|client_id| self.shard_for(*client_id) != shard)
+            .then_some(client_ids)
+    }
+
+    fn dispatch(&mut self, batches: &mut [Vec<Job>]) -> Result<(), TpsError> {
+        for (index, batch) in batches.iter_mut().enumerate() {
+            if batch.is_empty() {
+                continue;
+            }
+
+            let message = Message::Process(std::mem::take(batch));
+            if self.workers[index].sender.send(message).is_err() {
+                return Err(self.stopped_worker_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn wait_idle(&mut self) -> Result<(), TpsError> {
+        let (reply_sender, reply_receiver) = mpsc::channel();
+
+        for worker in &self.workers {
+            if worker
+                .sender
+                .send(Message::Barrier(reply_sender.clone()))
+                .is_err()
+            {
+                return Err(self.stopped_worker_error());
+            }
+        }
+
+        for _ in 0..self.workers.len() {
+            if reply_receiver.recv().is_err() {
+                return Err(self.stopped_worker_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    // All workers are idle here, so the clients involved (and the stored transaction
+    // for a chargeback) are moved out of their shards, processed, and moved back
+    fn process_cross_shard(
+        &mut self,
+        transaction: Transaction,
+        client_ids: Vec<ClientId>,
+    ) -> Result<(), TpsError> {
+        let mut clients = ClientList::new();
+        let mut transactions = TransactionManager::new();
+
+        for client_id in client_ids {
+            let mut shard = lock(&self.shards[self.shard_for(client_id)]);
+
+            if let Some(client) = shard.clients.remove_client(&client_id) {
+                clients.insert_client(client);
+            }
+
+            shard.transactions.load(&transaction.tx_id)?;
+            if let Some(stored) = shard.transactions.remove(&transaction.tx_id) {
+                transactions.insert_stored(stored);
+            }
+        }
+
+        process_transactions_reporting(
+            vec![transaction],
+            &mut clients,
+            &mut transactions,
+            &self.policy,
+            &mut self.rejects,
+        )?;
+
+        for client in clients {
+            lock(&self.shards[self.shard_for(client.id)])
+                .clients
+                .insert_client(client);
+        }
+
+        for stored in transactions {
+            let stored = stored?;
+            lock(&self.shards[self.shard_for(stored.transaction.disputing_client_id())])
+                .transactions
+                .insert_stored(stored);
+        }
+
+        Ok(())
+    }
+
+    // All workers are idle here. The end of day is checked against every shard
+    // before any of them pays, so a refused one pays no one, then every shard
+    // accrues the interest of its own clients.
+    fn end_of_day(&mut self, transaction: &Transaction) -> Result<(), TpsError> {
+        let checked = as_of(transaction).and_then(|as_of| {
+            for shard in &self.shards {
+                check_end_of_day(&lock(shard).clients, &self.policy, as_of)?;
+            }
+            Ok(as_of)
+        });
+
+        let as_of = match checked {
+            Ok(as_of) => as_of,
+            Err(error) => return self.rejects.reject(transaction, &error, None),
+        };
+
+        let at = paid_at(transaction, as_of);
+        let mut postings = Vec::new();
+        for shard in &self.shards {
+            postings.extend(accrue(&mut lock(shard).clients, &self.policy, as_of, at));
+        }
+
+        // The same order as without threads
+        postings.sort_unstable_by_key(|posting| (posting.client_id, posting.currency));
+        self.postings.extend(postings);
+
+        Ok(())
+    }
+
+    fn join_workers(&mut self) -> Result<(), TpsError> {
+        let mut result = Ok(());
+
+        for worker in self.workers.drain(..) {
+            // Closing the channel is what stops the worker
+            drop(worker.sender);
+
+            let worker_result = worker.handle.join().unwrap_or(Err(TpsError::WorkerStopped));
+            if result.is_ok() {
+                result = worker_result;
+            }
+        }
+
+        result
+    }
+
+    // A worker only stops early when processing failed, so its result holds the reason
+    fn stopped_worker_error(&mut self) -> TpsError {
+        match self.join_workers() {
+            Err(err) => err,
+            Ok(()) => TpsError::WorkerStopped,
+        }
+    }
+}
+
+fn shard_index(shards: usize, client_id: ClientId) -> usize {
+    usize::from(u16::from(client_id)) % shards
+}
+
+// A poisoned lock means a worker panicked in the middle of a change, the state
+// can't be trusted anymore so there is no point in recovering from it
+fn lock(shard: &Mutex<Shard>) -> MutexGuard<'_, Shard> {
+    shard.lock().expect("shard lock poisoned")
+}
+
+fn spawn_worker(shard: Arc<Mutex<Shard>>, policy: Policy, mut rejects: SharedRejects) -> Worker {
+    let (sender, receiver) = mpsc::channel();
+
+    let handle = thread::spawn(move || {
+        for message in receiver {
+            match message {
+                Message::Process(jobs) => {
+                    let mut shard = lock(&shard);
+                    let Shard {
+                        clients,
+                        transactions: transaction_manager,
+                    } = &mut *shard;
+
+                    for job in jobs {
+                        let (transaction, error) = match job {
+                            Job::Process(transaction) => {
+                                match process_transaction(
+                                    &transaction,
+                                    clients,
+                                    transaction_manager,
+                                    &policy,
+                                ) {
+                                    Ok(()) => continue,
+                                    Err(error @ TransactionError::Storage(_)) => {
+                                        return Err(error.into())
+                                    }
+                                    Err(error) => (transaction, error),
+                                }
+                            }
+                            Job::Reject(transaction, error) => (transaction, error),
+                        };
+
+                        let client = clients.get_client(&transaction.client_id);
+                        rejects.reject(&transaction, &error, client)?;
+                    }
+                }
+                Message::Barrier(reply) => {
+                    // The coordinator only stops listening when it is failing already
+                    let _ = reply.send(());
+                }
+            }
+        }
+
+        Ok(())
+    });
+
+    Worker { sender, handle }
+}
@@ -1,12 +1,17 @@
-use crate::{clients::ClientList, errors::TpsError};
+use crate::{
+    clients::ClientList,
+    errors::TpsError,
+    interest,
+    policy::Policy,
+    rejects::{RejectSink, StderrRejects},
+    risk,
+};
 
 use super::{
-    logic::{
-        chargeback::Chargeback, deposit::Deposit, dispute::Dispute, resolve::Resolve,
-        withdrawal::Withdrawal,
-    },
+    lifecycle::StoredTransaction,
+    logic::{execute, operation_for},
     manager::TransactionManager,
-    Transaction, TransactionType,
+    Transaction, TransactionError, TransactionType,
 };
 
 pub fn process_transactions(
@@ -14,46 +19,120 @@ pub fn process_transactions(
     clients: &mut ClientList,
     transaction_manager: &mut TransactionManager,
 ) -> Result<(), TpsError> {
-    for transaction in transactions {
-        let operation_result = match &transaction.tx_type {
-            TransactionType::Deposit | TransactionType::Withdrawal
-                if transaction_manager.contains(&transaction.tx_id) =>
-            {
-                eprintln!(
-                    "Duplicate transaction id found: {:?}, skipping",
-                    transaction.tx_id
-                );
-                Ok(())
-            }
-
-            TransactionType::Deposit => Deposit::new(&transaction)?.process(clients),
-            TransactionType::Withdrawal => Withdrawal::new(&transaction)?.process(clients),
-            TransactionType::Dispute => {
-                Dispute::new(&transaction)?.process(clients, transaction_manager)
-            }
-            TransactionType::Resolve => {
-                Resolve::new(&transaction)?.process(clients, transaction_manager)
-            }
-            TransactionType::Chargeback => {
-                Chargeback::new(&transaction)?.process(clients, transaction_manager)
-            }
-        };
+    process_transactions_reporting(
+        transactions,
+        clients,
+        transaction_manager,
+        &Policy::default(),
+        &mut StderrRejects,
+    )
+}
 
-        match operation_result {
+// Like `process_transactions`, but with the rules of `policy`, and every rejected
+// transaction is handed to `rejects`
+pub fn process_transactions_reporting(
+    transactions: Vec<Transaction>,
+    clients: &mut ClientList,
+    transaction_manager: &mut TransactionManager,
+    policy: &Policy,
+    rejects: &mut dyn RejectSink,
+) -> Result<(), TpsError> {
+    for transaction in transactions {
+        match process_transaction(&transaction, clients, transaction_manager, policy) {
             Ok(_) => (),
-            // All errors can continue processing
+            // The transaction store is broken, so nothing after this can be trusted
+            Err(e @ TransactionError::Storage(_)) => return Err(e.into()),
+            // All other errors can continue processing
             Err(e) => {
-                eprintln!("Error processing transaction: {transaction:?}, error: {e}");
+                rejects.reject(&transaction, &e, clients.get_client(&transaction.client_id))?;
             }
         };
+    }
+
+    Ok(())
+}
+
+// Processes a single transaction, returning why it was rejected if it was
+pub fn process_transaction(
+    transaction: &Transaction,
+    clients: &mut ClientList,
+    transaction_manager: &mut TransactionManager,
+    policy: &Policy,
+) -> Result<(), TransactionError> {
+    // Every operation works on the transaction with this id, whether it is stored
+    // now or looked up for a dispute, so it has to be in memory
+    transaction_manager.load(&transaction.tx_id)?;
+
+    let result = process_loaded(transaction, clients, transaction_manager, policy);
+    transaction_manager.trim()?;
+    result
+}
+
+fn process_loaded(
+    transaction: &Transaction,
+    clients: &mut ClientList,
+    transaction_manager: &mut TransactionManager,
+    policy: &Policy,
+) -> Result<(), TransactionError> {
+    // Nothing else about a row that couldn't be read can be trusted, not even its id
+    transaction.check_row()?;
+
+    if transaction.tx_type.is_stored() && transaction_manager.contains(&transaction.tx_id) {
+        // The original transaction is kept, storing this one would replace
+        // it along with its dispute state
+        return Err(TransactionError::DuplicateTransactionId(transaction.tx_id));
+    }
+
+    // This touches every client rather than the one in the row
+    if transaction.tx_type == TransactionType::Eod {
+        return interest::end_of_day(transaction, clients, transaction_manager, policy);
+    }
 
-        // only store the transaction if it is a deposit or withdrawal
-        if transaction.tx_type == TransactionType::Deposit
-            || transaction.tx_type == TransactionType::Withdrawal
-        {
-            transaction_manager.insert(transaction);
+    let result = process_operation(transaction, clients, transaction_manager, policy);
+
+    // A rejected transaction is still stored, whatever rejected it, so its id can't
+    // be used again. It is stored as rejected, so it can't be disputed either.
+    if let Err(err) = &result {
+        if transaction.tx_type.is_stored() && !matches!(err, TransactionError::Storage(_)) {
+            transaction_manager.insert_stored(StoredTransaction::rejected(transaction.clone()));
         }
     }
 
+    result
+}
+
+fn process_operation(
+    transaction: &Transaction,
+    clients: &mut ClientList,
+    transaction_manager: &mut TransactionManager,
+    policy: &Policy,
+) -> Result<(), TransactionError> {
+    // The risk rules see the transaction before anything is changed
+    let flags = risk::screen(policy.risk_rules(), transaction, clients)?;
+
+    let operation = operation_for(transaction, policy)?;
+    let journal = execute(
+        operation.as_ref(),
+        clients,
+        transaction_manager,
+        policy,
+        transaction.timestamp,
+    )?;
+
+    risk::record(policy.risk_rules(), transaction, clients);
+
+    // only store the transactions that move money
+    if transaction.tx_type.is_stored() {
+        let mut stored = StoredTransaction::new(transaction.clone());
+        operation.record(&mut stored);
+        stored.journal = journal;
+        stored.flags = flags;
+        transaction_manager.insert_stored(stored);
+    } else if let Some(stored) = transaction_manager.get_mut(&transaction.tx_id) {
+        // Disputes, resolves and chargebacks are kept with the transaction they are about
+        stored.journal.extend(journal);
+        stored.flags.extend(flags);
+    }
+
     Ok(())
 }
@@ -0,0 +1,234 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    borrow::Borrow,
+    collections::VecDeque,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    lifecycle::{DisputeState, StoredTransaction},
+    TransactionError, TransactionId,
+};
+
+// The on-disk tier of the `TransactionManager`. This is a scratch file that only
+// lives as long as the manager, so it skips the journal and fsyncs entirely.
+#[derive(Debug)]
+pub struct SpillStore {
+    connection: Connection,
+    path: PathBuf,
+}
+
+impl SpillStore {
+    // Starts from an empty file, anything left at `path` by an earlier run is replaced
+    pub fn create(path: &Path) -> rusqlite::Result<Self> {
+        let _ = std::fs::remove_file(path);
+
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "PRAGMA journal_mode = OFF;
+             PRAGMA synchronous = OFF;
+             CREATE TABLE spill (
+                 tx INTEGER PRIMARY KEY,
+                 seq INTEGER NOT NULL,
+                 disputed INTEGER NOT NULL,
+                 stored BLOB NOT NULL
+             );",
+        )?;
+
+        Ok(Self {
+            connection,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn put(
+        &self,
+        tx_id: TransactionId,
+        seq: u64,
+        stored: &StoredTransaction,
+    ) -> Result<(), TransactionError> {
+        let bytes = serde_json::to_vec(stored).map_err(storage_error)?;
+
+        self.connection
+            .prepare_cached(
+                "INSERT OR REPLACE INTO spill (tx, seq, disputed, stored) VALUES (?1, ?2, ?3, ?4)",
+            )
+            .and_then(|mut statement| {
+                statement.execute(params![
+                    u32::from(tx_id),
+                    seq,
+                    stored.state == DisputeState::Disputed,
+                    bytes
+                ])
+            })
+            .map_err(storage_error)?;
+
+        Ok(())
+    }
+
+    // Removes the transaction from the file and returns it along with its sequence number
+    pub fn take(
+        &self,
+        tx_id: TransactionId,
+    ) -> Result<Option<(u64, StoredTransaction)>, TransactionError> {
+        let row: Option<(u64, Vec<u8>)> = self
+            .connection
+            .prepare_cached("SELECT seq, stored FROM spill WHERE tx = ?1")
+            .and_then(|mut statement| {
+                statement
+                    .query_row(params![u32::from(tx_id)], |row| {
+                        Ok((row.get(0)?, row.get(1)?))
+                    })
+                    .optional()
+            })
+            .map_err(storage_error)?;
+
+        let Some((seq, bytes)) = row else {
+            return Ok(None);
+        };
+
+        self.connection
+            .prepare_cached("DELETE FROM spill WHERE tx = ?1")
+            .and_then(|mut statement| statement.execute(params![u32::from(tx_id)]))
+            .map_err(storage_error)?;
+
+        let stored = serde_json::from_slice(&bytes).map_err(storage_error)?;
+        Ok(Some((seq, stored)))
+    }
+
+    // Drops the transaction stored under `seq` unless it is in an open dispute.
+    // Returns false if it was kept.
+    pub fn expire(&self, tx_id: TransactionId, seq: u64) -> Result<bool, TransactionError> {
+        let disputed: Option<bool> = self
+            .connection
+            .prepare_cached("SELECT disputed FROM spill WHERE tx = ?1 AND seq = ?2")
+            .and_then(|mut statement| {
+                statement
+                    .query_row(params![u32::from(tx_id), seq], |row| row.get(0))
+                    .optional()
+            })
+            .map_err(storage_error)?;
+
+        match disputed {
+            Some(true) => Ok(false),
+            Some(false) => {
+                self.connection
+                    .prepare_cached("DELETE FROM spill WHERE tx = ?1")
+                    .and_then(|mut statement| statement.execute(params![u32::from(tx_id)]))
+                    .map_err(storage_error)?;
+                Ok(true)
+            }
+            // It was loaded back or removed since, so there is nothing to drop here
+            None => Ok(true),
+        }
+    }
+
+    // Reads the transactions stored after `after` (or from the start), in the order
+    // they were stored, at most `limit` of them
+    fn page(
+        &self,
+        after: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<(u64, StoredTransaction)>, TransactionError> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT seq, stored FROM spill WHERE seq > ?1 ORDER BY seq LIMIT ?2")
+            .map_err(storage_error)?;
+
+        let after = after.map_or(-1, |seq| seq as i64);
+        let rows = statement
+            .query_map(params![after, limit as i64], |row| {
+                Ok((row.get(0)?, row.get::<_, Vec<u8>>(1)?))
+            })
+            .map_err(storage_error)?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            let (seq, bytes) = row.map_err(storage_error)?;
+            entries.push((seq, serde_json::from_slice(&bytes).map_err(storage_error)?));
+        }
+
+        Ok(entries)
+    }
+
+    // Runs `f` in a single transaction on the file, which is much faster for many writes
+    pub fn batch<T>(
+        &self,
+        f: impl FnOnce(&Self) -> Result<T, TransactionError>,
+    ) -> Result<T, TransactionError> {
+        self.connection
+            .execute_batch("BEGIN")
+            .map_err(storage_error)?;
+
+        match f(self) {
+            Ok(value) => {
+                self.connection
+                    .execute_batch("COMMIT")
+                    .map_err(storage_error)?;
+                Ok(value)
+            }
+            Err(err) => {
+                let _ = self.connection.execute_batch("ROLLBACK");
+                Err(err)
+            }
+        }
+    }
+}
+
+// Reads back everything in the file in the order it was stored, a page at a time
+// so the file never has to be in memory at once. Without a file there is nothing.
+#[derive(Debug)]
+pub struct SpillEntries<S> {
+    spill: Option<S>,
+    after: Option<u64>,
+    page: VecDeque<(u64, StoredTransaction)>,
+    done: bool,
+}
+
+impl<S: Borrow<SpillStore>> SpillEntries<S> {
+    const PAGE_SIZE: usize = 1024;
+
+    pub fn new(spill: Option<S>) -> Self {
+        Self {
+            spill,
+            after: None,
+            page: VecDeque::new(),
+            done: false,
+        }
+    }
+}
+
+impl<S: Borrow<SpillStore>> Iterator for SpillEntries<S> {
+    type Item = Result<(u64, StoredTransaction), TransactionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() && !self.done {
+            let spill = self.spill.as_ref()?.borrow();
+            match spill.page(self.after, Self::PAGE_SIZE) {
+                Ok(page) => {
+                    self.done = page.len() < Self::PAGE_SIZE;
+                    self.page = page.into();
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+
+        let (seq, stored) = self.page.pop_front()?;
+        self.after = Some(seq);
+        Some(Ok((seq, stored)))
+    }
+}
+
+impl Drop for SpillStore {
+    fn drop(&mut self) {
+        // The file is useless once the manager is gone
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn storage_error(err: impl std::fmt::Display) -> TransactionError {
+    TransactionError::Storage(err.to_string())
+}
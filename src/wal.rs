@@ -0,0 +1,237 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+};
+
+use crate::{
+    clients::ClientList,
+    errors::TpsError,
+    policy::Policy,
+    rejects::StderrRejects,
+    transactions::{
+        manager::TransactionManager, process::process_transactions_reporting, Position, Transaction,
+    },
+};
+
+// An append-only log of every transaction read from the input. Transactions are
+// written (and synced to disk) before they are processed, so after a crash the
+// state can be rebuilt by replaying the log, and the input resumes after the
+// last logged row. Processing is deterministic, so replaying rows that failed
+// the first time fails them again the same way.
+//
+// The logged rows are the fingerprint of the input they came from: the rows
+// skipped on resume have to be the same ones, or the input is refused. Once a
+// run finishes cleanly the log is emptied with `finish`.
+//
+// Every row is a line of JSON with the position the row had in the input, which
+// the row itself does not keep when serialized, so a replayed row ends up at the
+// same place in the history and audit records as the first time.
+pub struct WriteAheadLog {
+    path: String,
+    writer: BufWriter<File>,
+    entries: usize,
+    // The input rows still to be skipped, and the fingerprints of the logged rows
+    // and of the input rows skipped so far
+    to_skip: usize,
+    logged: Fingerprint,
+    skipped: Fingerprint,
+}
+
+// FNV-1a over the rows, which unlike the std hashers is the same in every build
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Fingerprint(u64);
+
+impl Default for Fingerprint {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Fingerprint {
+    fn add(&mut self, transaction: &Transaction) -> Result<(), TpsError> {
+        for byte in serde_json::to_vec(&LoggedRow::from(transaction))? {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+
+        Ok(())
+    }
+}
+
+impl WriteAheadLog {
+    pub fn open(path: &str) -> Result<Self, TpsError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+
+        // A crash in the middle of a write can leave a partial last line behind,
+        // it was never processed so it is dropped
+        let complete_len = complete_len(&mut file)?;
+        file.set_len(complete_len)?;
+
+        Ok(Self {
+            path: path.to_string(),
+            writer: BufWriter::new(file),
+            entries: 0,
+            to_skip: 0,
+            logged: Fingerprint::default(),
+            skipped: Fingerprint::default(),
+        })
+    }
+
+    // Re-applies every logged transaction and returns how many there were,
+    // which is also how many input rows have already been consumed. The policy
+    // has to be the one the rows were first processed with to get the same state.
+    pub fn replay(
+        &mut self,
+        chunk_size: usize,
+        clients: &mut ClientList,
+        transaction_manager: &mut TransactionManager,
+        policy: &Policy,
+    ) -> Result<usize, TpsError> {
+        let mut entries = 0;
+        let mut logged = Fingerprint::default();
+        let mut lines = BufReader::new(File::open(&self.path)?).lines();
+
+        loop {
+            let mut chunk = Vec::with_capacity(chunk_size);
+            for line in lines.by_ref().take(chunk_size) {
+                let row: LoggedRow = serde_json::from_str(&line?)?;
+                chunk.push(row.into_transaction());
+            }
+            if chunk.is_empty() {
+                break;
+            }
+
+            entries += chunk.len();
+            for transaction in &chunk {
+                logged.add(transaction)?;
+            }
+            process_transactions_reporting(
+                chunk,
+                clients,
+                transaction_manager,
+                policy,
+                &mut StderrRejects,
+            )?;
+        }
+
+        self.entries = entries;
+        self.to_skip = entries;
+        self.logged = logged;
+        Ok(entries)
+    }
+
+    // Drops the input rows the log already holds from the front of `chunk`. They
+    // were replayed, and have to be the rows that were logged.
+    pub fn skip_logged(&mut self, chunk: &mut Vec<Transaction>) -> Result<(), TpsError> {
+        let skipped = self.to_skip.min(chunk.len());
+        for transaction in chunk.drain(..skipped) {
+            self.skipped.add(&transaction)?;
+        }
+        self.to_skip -= skipped;
+
+        if skipped > 0 && self.to_skip == 0 && self.skipped != self.logged {
+            return Err(TpsError::WalMismatch);
+        }
+
+        Ok(())
+    }
+
+    // Empties the log once every row of the input is processed. A log that still
+    // had rows to skip was written for a longer input.
+    pub fn finish(self) -> Result<(), TpsError> {
+        if self.to_skip > 0 {
+            return Err(TpsError::WalMismatch);
+        }
+
+        self.writer.into_inner().map_err(|err| err.into_error())?;
+        File::create(&self.path)?;
+
+        Ok(())
+    }
+
+    // Makes the transactions durable, this has to happen before they are processed
+    pub fn append(&mut self, transactions: &[Transaction]) -> Result<(), TpsError> {
+        for transaction in transactions {
+            serde_json::to_writer(&mut self.writer, &LoggedRow::from(transaction))?;
+            self.writer.write_all(b"\n")?;
+        }
+
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.entries += transactions.len();
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+}
+
+// A row as it is logged, along with where it was read from. A row that couldn't
+// be read is logged with why and as it was, so it is rejected again on replay.
+#[derive(Serialize, Deserialize)]
+struct LoggedRow {
+    transaction: Transaction,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    position: Option<Position>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unreadable: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    row: Option<String>,
+}
+
+impl LoggedRow {
+    fn into_transaction(self) -> Transaction {
+        Transaction {
+            position: self.position,
+            unreadable: self.unreadable,
+            raw: self.row,
+            ..self.transaction
+        }
+    }
+}
+
+impl From<&Transaction> for LoggedRow {
+    fn from(transaction: &Transaction) -> Self {
+        let unreadable = transaction.unreadable.clone();
+
+        Self {
+            transaction: transaction.clone(),
+            position: transaction.position,
+            row: unreadable.as_ref().and(transaction.raw.clone()),
+            unreadable,
+        }
+    }
+}
+
+// Finds the end of the last complete line by scanning backwards from the end of the file
+fn complete_len(file: &mut File) -> io::Result<u64> {
+    let mut end = file.metadata()?.len();
+    let mut buf = [0u8; 4096];
+
+    while end > 0 {
+        let start = end.saturating_sub(buf.len() as u64);
+        let block = &mut buf[..(end - start) as usize];
+
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(block)?;
+
+        if let Some(pos) = block.iter().rposition(|byte| *byte == b'\n') {
+            return Ok(start + pos as u64 + 1);
+        }
+
+        end = start;
+    }
+
+    Ok(0)
+}
@@ -1,6 +1,36 @@
 use tps2::{
     clients::{self},
-    read_whole_csv, transactions, CsvChunkedReader,
+    currency::Currency,
+    errors::TpsError,
+    fees::{write_fee_report, FeeRule},
+    formats::{Format, JsonLinesChunkedReader},
+    interest::write_interest_report,
+    ledger::{write_trial_balance, Account, Ledger, Line},
+    limits::{apply_limits, apply_limits_to_storage, LimitEntry},
+    policy::Policy,
+    rates::RateTable,
+    read_whole_csv,
+    rejects::{RejectSink, RejectWriter, StderrRejects},
+    risk::write_review_report,
+    server::{http, tcp, EngineHandle},
+    snapshot::{save_snapshot, Snapshot},
+    storage::{process_with_storage, SqliteStorage, Storage},
+    transactions::{
+        self,
+        lifecycle::DisputeState,
+        logic::{execute, TransactionOp},
+        manager::StoreLimits,
+        parallel::ShardedProcessor,
+        OperatorId, TransactionId,
+    },
+    wal::WriteAheadLog,
+    CsvChunkedReader,
+};
+
+use std::io::Write;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
 };
 
 use rust_decimal::Decimal;
@@ -101,14 +131,14 @@ fn advanced_dispute() {
 
     // Cases covered here:
     // - duplicate transaction ids
-    // - dispute on withdrawals (ignored)
+    // - dispute and resolve of a withdrawal, rejected since withdrawals aren't disputable by default
     // - withdrawal without enough $
-    // - locked client
+    // - disputing again after a resolve (rejected, so the chargeback is too)
     // - not enough funds to dispute
 
     // check here the values in the client pool
     let expected_result = r#"client, available, held, total, locked
-1, 0.3000, 0.0000, 0.3000, true
+1, 3.3000, 0.0000, 3.3000, false
 2, 1.1250, 0.0000, 1.1250, false
 "#;
 
@@ -257,41 +287,2309 @@ fn many_transactions_many_accounts() {
     assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
 }
 
-// This is a helper struct to make it easier to compare the client records
-#[derive(Debug, Copy, Clone, Serialize, Deserialize, Eq, PartialEq)]
-pub struct Client {
-    pub client: u16,
-    pub available: Decimal,
-    pub held: Decimal,
-    pub total: Decimal,
-    pub locked: bool,
+#[cfg(test)]
+#[test]
+fn batch_rolls_back_on_failure() {
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+
+    let setup = read_whole_csv("tests/t6_transactions.csv").unwrap();
+    transactions::process::process_transactions(setup, &mut clients, &mut transactions).unwrap();
+
+    // The last withdrawal does not have enough funds, so nothing in the batch should land
+    let batch = read_whole_csv("tests/t7_transactions.csv").unwrap();
+    let result = transactions::batch::process_batch(
+        &batch,
+        &mut clients,
+        &mut transactions,
+        &Policy::default(),
+    );
+    assert!(matches!(
+        result,
+        Err(transactions::TransactionError::InsufficientFunds(_))
+    ));
+
+    let expected_result = r#"client, available, held, total, locked
+1, 5.0000, 0.0000, 5.0000, false
+2, 3.0000, 0.0000, 3.0000, false
+"#;
+
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+    assert!(clients.get_client(&clients::ClientId::from(3)).is_none());
+    let stored = transactions.get(&TransactionId::from(1)).unwrap();
+    assert_eq!(stored.state, DisputeState::Processed);
+    assert!(stored.history.is_empty());
+    assert!(!transactions.contains(&TransactionId::from(10)));
+    assert!(!transactions.contains(&TransactionId::from(11)));
 }
 
-// Parses the expected and result CSVs into a Vec of Client records and compares them
-fn parse_client_records(csv_text: &str) -> Result<Vec<Client>, csv::Error> {
-    let mut rdr = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .from_reader(csv_text.as_bytes());
+#[cfg(test)]
+#[test]
+fn batch_applies_all() {
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
 
-    let mut records = vec![];
-    for result in rdr.deserialize() {
-        let record: Client = result?;
-        records.push(record);
+    let setup = read_whole_csv("tests/t6_transactions.csv").unwrap();
+    transactions::process::process_transactions(setup, &mut clients, &mut transactions).unwrap();
+
+    // Same batch without the failing withdrawal
+    let mut batch = read_whole_csv("tests/t7_transactions.csv").unwrap();
+    batch.pop();
+    transactions::batch::process_batch(&batch, &mut clients, &mut transactions, &Policy::default())
+        .unwrap();
+
+    let expected_result = r#"client, available, held, total, locked
+1, 0.0000, 5.0000, 5.0000, false
+2, 2.0000, 0.0000, 2.0000, false
+3, 2.0000, 0.0000, 2.0000, false
+"#;
+
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+    assert_eq!(
+        transactions.get(&TransactionId::from(1)).unwrap().state,
+        DisputeState::Disputed
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn transfers() {
+    let input_csv_filename = "tests/t8_transactions.csv";
+
+    let csv_content = read_whole_csv(input_csv_filename).unwrap();
+
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+
+    //process the transactions
+    transactions::process::process_transactions(csv_content, &mut clients, &mut transactions)
+        .unwrap();
+
+    // Cases covered here:
+    // - transfer without enough $
+    // - transfer to a new client
+    // - dispute of a transfer by the receiving client
+    // - dispute of a transfer by the sending client (ignored)
+    // - chargeback of a transfer returns the funds to the sender
+    // - transfer to a locked client
+    // - failed transfer to a new client, which is not created
+
+    let expected_result = r#"client, available, held, total, locked
+1, 6.0000, 0.0000, 6.0000, false
+2, 1.0000, 4.0000, 5.0000, false
+3, 0.0000, 0.0000, 0.0000, true
+"#;
+
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+}
+
+#[test]
+fn transfer_chargeback() {
+    let policy = Policy::default();
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let mut rejects = Codes::default();
+
+    transactions::process::process_transactions_reporting(
+        read_whole_csv("tests/t24_transactions.csv").unwrap(),
+        &mut clients,
+        &mut transactions,
+        &policy,
+        &mut rejects,
+    )
+    .unwrap();
+
+    // Only the receiving client can act on the transfer
+    assert_eq!(
+        rejects.0,
+        vec![
+            (4, "client_mismatch"),
+            (6, "client_mismatch"),
+            (7, "client_mismatch")
+        ]
+    );
+
+    // The receiver's share is held, the sender is untouched
+    let open_dispute = r#"client, available, held, total, locked
+1, 6.0000, 0.0000, 6.0000, false
+2, 0.0000, 4.0000, 4.0000, false
+"#;
+    assert_clients_equal_ignore_order(&clients.to_string(), open_dispute);
+
+    let rows = |csv: &str| -> Vec<transactions::Transaction> {
+        CsvChunkedReader::from_reader(csv.as_bytes(), 10)
+            .flat_map(Result::unwrap)
+            .collect()
+    };
+
+    // A rolled back chargeback leaves both sides as they were
+    let batch = rows("type,client,tx,amount\nchargeback,2,2,\nwithdrawal,1,3,100\n");
+    assert!(
+        transactions::batch::process_batch(&batch, &mut clients, &mut transactions, &policy)
+            .is_err()
+    );
+    assert_clients_equal_ignore_order(&clients.to_string(), open_dispute);
+
+    // The held funds leave the receiver and go back to the sender's available funds
+    transactions::process::process_transactions_reporting(
+        rows("type,client,tx,amount\nchargeback,2,2,\n"),
+        &mut clients,
+        &mut transactions,
+        &policy,
+        &mut rejects,
+    )
+    .unwrap();
+    let expected_result = r#"client, available, held, total, locked
+1, 10.0000, 0.0000, 10.0000, false
+2, 0.0000, 0.0000, 0.0000, true
+"#;
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+
+    let stored: Vec<_> = transactions.into_iter().map(Result::unwrap).collect();
+    let ledger = Ledger::from_transactions(&stored, &[]);
+    assert!(ledger.is_balanced());
+    assert!(ledger.reconcile(&clients).is_empty());
+}
+
+#[cfg(test)]
+#[test]
+fn wal_resumes_after_crash() {
+    let wal_path = std::env::temp_dir().join(format!("tps2_wal_{}.log", std::process::id()));
+    let wal_path = wal_path.to_str().unwrap();
+    let _ = std::fs::remove_file(wal_path);
+
+    let csv_content = read_whole_csv("tests/t0_transactions.csv").unwrap();
+    let (first_half, second_half) = csv_content.split_at(6);
+
+    // Process part of the input, then "crash" in the middle of writing the next row
+    {
+        let mut clients = clients::ClientList::new();
+        let mut transactions = transactions::manager::TransactionManager::new();
+        let mut wal = WriteAheadLog::open(wal_path).unwrap();
+
+        wal.append(first_half).unwrap();
+        transactions::process::process_transactions(
+            first_half.to_vec(),
+            &mut clients,
+            &mut transactions,
+        )
+        .unwrap();
+
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(wal_path)
+            .unwrap();
+        file.write_all(br#"{"transaction":{"type":"deposit","client":4"#)
+            .unwrap();
     }
-    Ok(records)
+
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let mut wal = WriteAheadLog::open(wal_path).unwrap();
+
+    let replayed = wal
+        .replay(2, &mut clients, &mut transactions, &Policy::default())
+        .unwrap();
+    assert_eq!(replayed, first_half.len());
+
+    // The replayed rows keep where they were in the input, not in the log
+    let disputed = transactions.get(&TransactionId::from(3)).unwrap();
+    assert_eq!(disputed.position, first_half[2].position);
+    assert_eq!(disputed.history[0].position, first_half[5].position);
+
+    // The input is read again from the start, the logged rows are skipped
+    let mut rest = csv_content.clone();
+    wal.skip_logged(&mut rest).unwrap();
+    assert_eq!(rest.len(), second_half.len());
+
+    wal.append(&rest).unwrap();
+    transactions::process::process_transactions(rest, &mut clients, &mut transactions).unwrap();
+
+    let expected_result = r#"client, available, held, total, locked
+1, 2.5000, 0.0000, 2.5000, false
+2, 0.0234, 0.0000, 0.0234, true
+3, 0.0000, 0.0000, 0.0000, false
+"#;
+
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+    // The torn row was never processed
+    assert!(clients.get_client(&clients::ClientId::from(4)).is_none());
+
+    // Another input can't be resumed from the log
+    let mut wal = WriteAheadLog::open(wal_path).unwrap();
+    wal.replay(
+        2,
+        &mut clients::ClientList::new(),
+        &mut transactions::manager::TransactionManager::new(),
+        &Policy::default(),
+    )
+    .unwrap();
+    let mut other = read_whole_csv("tests/t3_transactions.csv").unwrap();
+    assert!(matches!(
+        wal.skip_logged(&mut other),
+        Err(TpsError::WalMismatch)
+    ));
+
+    // Neither can one that ends before the logged rows do
+    let mut wal = WriteAheadLog::open(wal_path).unwrap();
+    wal.replay(
+        2,
+        &mut clients::ClientList::new(),
+        &mut transactions::manager::TransactionManager::new(),
+        &Policy::default(),
+    )
+    .unwrap();
+    let mut short = first_half.to_vec();
+    wal.skip_logged(&mut short).unwrap();
+    assert!(matches!(wal.finish(), Err(TpsError::WalMismatch)));
+
+    // A clean finish empties the log, so the next run starts over
+    let mut wal = WriteAheadLog::open(wal_path).unwrap();
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    wal.replay(2, &mut clients, &mut transactions, &Policy::default())
+        .unwrap();
+    let mut input = csv_content.clone();
+    wal.skip_logged(&mut input).unwrap();
+    wal.finish().unwrap();
+    assert!(WriteAheadLog::open(wal_path)
+        .unwrap()
+        .replay(2, &mut clients, &mut transactions, &Policy::default())
+        .is_ok_and(|replayed| replayed == 0));
+
+    std::fs::remove_file(wal_path).unwrap();
 }
 
-// Since order does not matter, we need to sort the records by client ID and then compare
-fn assert_clients_equal_ignore_order(actual: &str, expected: &str) {
-    let mut actual_records = parse_client_records(actual).expect("Failed to parse 'actual' CSV");
-    let mut expected_records =
-        parse_client_records(expected).expect("Failed to parse 'expected' CSV");
+#[cfg(test)]
+#[test]
+fn snapshot_resume() {
+    let snapshot_path =
+        std::env::temp_dir().join(format!("tps2_snapshot_{}.json", std::process::id()));
+    let snapshot_path = snapshot_path.to_str().unwrap();
 
-    // Sort by client ID to ignore row order
-    actual_records.sort_by_key(|r| r.client);
-    expected_records.sort_by_key(|r| r.client);
+    // Day one leaves a deposit in dispute
+    {
+        let csv_content = read_whole_csv("tests/t2_transactions.csv").unwrap();
 
-    for (actual, expected) in actual_records.iter().zip(expected_records.iter()) {
-        assert_eq!(actual, expected);
+        let mut clients = clients::ClientList::new();
+        let mut transactions = transactions::manager::TransactionManager::new();
+
+        transactions::process::process_transactions(csv_content, &mut clients, &mut transactions)
+            .unwrap();
+
+        save_snapshot(snapshot_path, &clients, &transactions).unwrap();
+    }
+
+    // Day two resolves it on top of the saved state
+    let Snapshot {
+        mut clients,
+        mut transactions,
+        ..
+    } = Snapshot::load(snapshot_path).unwrap();
+    assert_eq!(
+        transactions.get(&TransactionId::from(1)).unwrap().state,
+        DisputeState::Disputed
+    );
+
+    let csv_content = read_whole_csv("tests/t9_transactions.csv").unwrap();
+    transactions::process::process_transactions(csv_content, &mut clients, &mut transactions)
+        .unwrap();
+
+    let expected_result = r#"client, available, held, total, locked
+1, 1.5000, 0.0000, 1.5000, false
+2, 3.0000, 0.0000, 3.0000, false
+"#;
+
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+
+    std::fs::remove_file(snapshot_path).unwrap();
+}
+
+#[cfg(test)]
+#[test]
+fn snapshot_upgrade() {
+    // Written before disputes had a state, with the flat balances of one currency
+    let Snapshot {
+        mut clients,
+        mut transactions,
+        ..
+    } = Snapshot::load("tests/t26_snapshot.json").unwrap();
+    assert_eq!(
+        transactions.get(&TransactionId::from(2)).unwrap().state,
+        DisputeState::Disputed
+    );
+    assert_eq!(
+        transactions.get(&TransactionId::from(1)).unwrap().state,
+        DisputeState::Processed
+    );
+
+    // The open dispute can still be resolved
+    let csv_content = CsvChunkedReader::from_reader("type,client,tx\nresolve,1,2\n".as_bytes(), 10)
+        .flat_map(Result::unwrap)
+        .collect();
+    transactions::process::process_transactions(csv_content, &mut clients, &mut transactions)
+        .unwrap();
+
+    let expected_result = r#"client, available, held, total, locked
+1, 15.0000, 0.0000, 15.0000, false
+2, 2.0000, 0.0000, 2.0000, false
+"#;
+
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+}
+
+#[cfg(test)]
+#[test]
+fn sharded_matches_serial() {
+    for input_csv_filename in [
+        "tests/t0_transactions.csv",
+        "tests/t3_transactions.csv",
+        "tests/t5_transactions.csv",
+        "tests/t8_transactions.csv",
+    ] {
+        let mut clients = clients::ClientList::new();
+        let mut transactions = transactions::manager::TransactionManager::new();
+
+        let csv_content = read_whole_csv(input_csv_filename).unwrap();
+        transactions::process::process_transactions(csv_content, &mut clients, &mut transactions)
+            .unwrap();
+
+        // Small chunks so the work is spread over many messages
+        let mut sharded = ShardedProcessor::new(4).unwrap();
+        for chunk in CsvChunkedReader::new(input_csv_filename, 7).unwrap() {
+            sharded.process(chunk.unwrap()).unwrap();
+        }
+        let (sharded_clients, _) = sharded.finish().unwrap();
+
+        let mut expected: Vec<String> = clients.to_string().lines().map(String::from).collect();
+        let mut actual: Vec<String> = sharded_clients
+            .to_string()
+            .lines()
+            .map(String::from)
+            .collect();
+        expected.sort();
+        actual.sort();
+
+        assert_eq!(actual, expected, "{input_csv_filename}");
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn tcp_concurrent_connections() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let engine = EngineHandle::spawn(
+        clients::ClientList::new(),
+        transactions::manager::TransactionManager::new(),
+        Policy::default(),
+    );
+    tokio::spawn(tcp::serve(listener, engine.clone()));
+
+    // Every connection works on its own client, so the result does not depend on how they interleave
+    let connections: Vec<_> = (1..=3u16)
+        .map(|client| {
+            tokio::spawn(async move {
+                let stream = TcpStream::connect(address).await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+
+                let tx = u32::from(client) * 10;
+                let rows = format!(
+                    "type, client, tx, amount\n\
+                     deposit, {client}, {}, 2.0\n\
+                     withdrawal, {client}, {}, 5.0\n\
+                     deposit, not_a_client, {}, 1.0\n\
+                     withdrawal, {client}, {}, 0.5\n",
+                    tx,
+                    tx + 1,
+                    tx + 2,
+                    tx + 3
+                );
+                writer.write_all(rows.as_bytes()).await.unwrap();
+                writer.shutdown().await.unwrap();
+
+                let mut responses = Vec::new();
+                let mut lines = BufReader::new(reader).lines();
+                while let Some(line) = lines.next_line().await.unwrap() {
+                    responses.push(line);
+                }
+                responses
+            })
+        })
+        .collect();
+
+    for connection in connections {
+        let responses = connection.await.unwrap();
+
+        assert_eq!(responses.len(), 4);
+        assert_eq!(responses[0], "2,ok");
+        assert!(responses[1].starts_with("3,rejected,"));
+        assert!(responses[2].starts_with("4,rejected,"));
+        assert_eq!(responses[3], "5,ok");
+    }
+
+    let (clients, _) = engine.shutdown().await.unwrap();
+
+    let expected_result = r#"client, available, held, total, locked
+1, 1.5000, 0.0000, 1.5000, false
+2, 1.5000, 0.0000, 1.5000, false
+3, 1.5000, 0.0000, 1.5000, false
+"#;
+
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn http_api() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let engine = EngineHandle::spawn(
+        clients::ClientList::new(),
+        transactions::manager::TransactionManager::new(),
+        Policy::default(),
+    );
+    tokio::spawn(http::serve(listener, engine.clone()));
+
+    let single = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "2.5"}"#;
+    let (status, body) = http_request(address, "POST", "/transactions", single).await;
+    assert_eq!(status, 200, "{body}");
+
+    // The withdrawal fails, so the deposit in the same batch is rolled back
+    let batch = r#"[
+        {"type": "deposit", "client": 2, "tx": 2, "amount": "1.0"},
+        {"type": "withdrawal", "client": 2, "tx": 3, "amount": "5.0"}
+    ]"#;
+    let (status, body) = http_request(address, "POST", "/transactions", batch).await;
+    assert_eq!(status, 422);
+    assert!(body.contains(r#""code":"insufficient_funds""#), "{body}");
+
+    let dispute = r#"{"type": "dispute", "client": 1, "tx": 1}"#;
+    let (status, _) = http_request(address, "POST", "/transactions", dispute).await;
+    assert_eq!(status, 200);
+
+    let (status, body) = http_request(address, "GET", "/clients/1", "").await;
+    assert_eq!(status, 200);
+    let client: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(client["held"], "2.5");
+
+    let (status, body) = http_request(address, "GET", "/transactions/1", "").await;
+    assert_eq!(status, 200);
+    let transaction: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(transaction["state"], "disputed");
+    assert_eq!(transaction["history"][0]["type"], "dispute");
+
+    let (status, _) = http_request(address, "GET", "/clients/2", "").await;
+    assert_eq!(status, 404);
+
+    let (status, body) = http_request(address, "POST", "/transactions", "{").await;
+    assert_eq!(status, 400);
+    assert!(body.contains(r#""code":"invalid_json""#), "{body}");
+}
+
+// Sends a bare HTTP/1.1 request and returns the status code and body of the response
+async fn http_request(
+    address: std::net::SocketAddr,
+    method: &str,
+    path: &str,
+    body: &str,
+) -> (u16, String) {
+    let mut stream = TcpStream::connect(address).await.unwrap();
+
+    let request = format!(
+        "{method} {path} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+
+    (status, body.to_string())
+}
+
+#[cfg(test)]
+#[test]
+fn read_from_memory() {
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 1.5\nwithdrawal, 1, 2, 0.5\n";
+
+    let chunks: Vec<_> = CsvChunkedReader::from_reader(input.as_bytes(), 1)
+        .map(Result::unwrap)
+        .collect();
+
+    assert_eq!(chunks.len(), 2);
+    assert_eq!(chunks[1][0].tx_id, TransactionId::from(2));
+}
+
+#[cfg(test)]
+#[test]
+fn read_compressed() {
+    let plain = std::fs::read("tests/t0_transactions.csv").unwrap();
+    let expected = read_whole_csv("tests/t0_transactions.csv").unwrap();
+
+    let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gzip.write_all(&plain).unwrap();
+    let zstd = zstd::encode_all(plain.as_slice(), 0).unwrap();
+
+    // The extensions don't say anything, so this relies on the magic bytes
+    for (name, bytes) in [("gzip", gzip.finish().unwrap()), ("zstd", zstd)] {
+        let path = std::env::temp_dir().join(format!("tps2_{}_{name}.csv", std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+
+        let transactions = read_whole_csv(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(transactions.len(), expected.len(), "{name}");
+        for (actual, expected) in transactions.iter().zip(expected.iter()) {
+            assert_eq!(actual.tx_id, expected.tx_id, "{name}");
+            assert_eq!(actual.amount, expected.amount, "{name}");
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn json_lines_format() {
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+
+    for chunk in Format::JsonLines
+        .reader("tests/t10_transactions.jsonl", 2)
+        .unwrap()
+    {
+        transactions::process::process_transactions(
+            chunk.unwrap(),
+            &mut clients,
+            &mut transactions,
+        )
+        .unwrap();
+    }
+
+    // The large amount is a string, it would lose digits if it went through a float.
+    // The small ones are numbers, which are exact at this size.
+    let expected_result = r#"client, available, held, total, locked
+1, 12345678901233.1233, 0.0000, 12345678901233.1233, false
+2, 0.0000, 2.5000, 2.5000, false
+3, 1.0000, 0.0000, 1.0000, false
+"#;
+
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+
+    let mut output = Vec::new();
+    Format::JsonLines
+        .write_clients(&clients, &mut output)
+        .unwrap();
+
+    let mut rows: Vec<serde_json::Value> = String::from_utf8(output)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    rows.sort_by_key(|row| row["client"].as_u64());
+
+    // Without other currencies there is no currency, like before currencies
+    assert_eq!(rows.len(), 3);
+    assert!(rows[0].get("currency").is_none());
+    assert_eq!(rows[0]["available"], "12345678901233.1233");
+    assert_eq!(rows[1]["held"], "2.5000");
+    assert_eq!(rows[2]["locked"], false);
+
+    // Every row knows where it was in the file, the blank line is counted but skipped
+    let read: Vec<transactions::Transaction> = Format::JsonLines
+        .reader("tests/t10_transactions.jsonl", 10)
+        .unwrap()
+        .flat_map(Result::unwrap)
+        .collect();
+    let lines: Vec<u64> = read
+        .iter()
+        .map(|transaction| transaction.position.unwrap().line)
+        .collect();
+    assert_eq!(lines, vec![1, 2, 3, 5, 6]);
+    assert_eq!(
+        read[3].raw.as_deref(),
+        Some(r#"{"type": "dispute", "client": 2, "tx": 2}"#)
+    );
+    assert_eq!(
+        read[3].position.unwrap().byte,
+        std::fs::read_to_string("tests/t10_transactions.jsonl")
+            .unwrap()
+            .find(r#"{"type": "dispute""#)
+            .unwrap() as u64
+    );
+
+    // A line that isn't a transaction is passed on to be rejected, like in a CSV file
+    let input = "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": \"abc\"}\n{\"type\": \"deposit\", \"client\": 1, \"tx\": 2, \"amount\": 1}\n";
+    let read: Vec<transactions::Transaction> =
+        JsonLinesChunkedReader::from_reader(input.as_bytes(), 10)
+            .flat_map(Result::unwrap)
+            .collect();
+    assert_eq!(read.len(), 2);
+    assert!(read[0]
+        .check_row()
+        .is_err_and(|err| err.code() == "invalid_row"));
+    assert_eq!(read[0].position.unwrap().line, 1);
+    assert!(read[1].check_row().is_ok());
+}
+
+#[cfg(test)]
+#[test]
+fn reject_report() {
+    let path = std::env::temp_dir().join(format!("tps2_rejects_{}.csv", std::process::id()));
+    let path = path.to_str().unwrap();
+
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let mut rejects = RejectWriter::create(path, false).unwrap();
+
+    for chunk in CsvChunkedReader::new("tests/t3_transactions.csv", 4).unwrap() {
+        transactions::process::process_transactions_reporting(
+            chunk.unwrap(),
+            &mut clients,
+            &mut transactions,
+            &Policy::default(),
+            &mut rejects,
+        )
+        .unwrap();
+    }
+    rejects.flush().unwrap();
+
+    let mut reader = csv::Reader::from_path(path).unwrap();
+    let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+    std::fs::remove_file(path).unwrap();
+
+    // line, code, and the client's available balance after the rejection
+    let summary: Vec<(&str, &str, &str)> =
+        rows.iter().map(|row| (&row[0], &row[7], &row[9])).collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("3", "insufficient_funds", "1.0000"),
+            ("6", "duplicate_transaction", "1.8000"),
+            ("7", "not_disputable", "1.8000"),
+            ("10", "insufficient_funds", "1.8000"),
+            ("11", "not_disputable", "1.8000"),
+            ("13", "invalid_state_change", "1.3000"),
+            ("14", "invalid_state_change", "1.3000"),
+            ("18", "insufficient_funds", "1.1250"),
+        ]
+    );
+    // The row is last, as it was in the file
+    assert_eq!(&rows[0][rows[0].len() - 1], "withdrawal,1,2,2.0");
+
+    // The sharded processor reports the same rows, in input order per client
+    let jsonl_path = path.replace(".csv", ".jsonl");
+    let rejects = RejectWriter::create(&jsonl_path, false).unwrap();
+    let mut sharded = ShardedProcessor::with_reject_sink(
+        2,
+        clients::ClientList::new(),
+        transactions::manager::TransactionManager::new(),
+        Policy::default(),
+        Box::new(rejects),
+    )
+    .unwrap();
+    sharded
+        .process(read_whole_csv("tests/t3_transactions.csv").unwrap())
+        .unwrap();
+    sharded.finish().unwrap();
+
+    let report = std::fs::read_to_string(&jsonl_path).unwrap();
+    std::fs::remove_file(&jsonl_path).unwrap();
+
+    let mut lines: Vec<u64> = report
+        .lines()
+        .map(|line| {
+            serde_json::from_str::<serde_json::Value>(line).unwrap()["line"]
+                .as_u64()
+                .unwrap()
+        })
+        .collect();
+    lines.sort();
+    assert_eq!(lines, vec![3, 6, 7, 10, 11, 13, 14, 18]);
+
+    // A row that can't be read is reported with its position and as it was, and the
+    // input goes on after it
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let mut rejects = RejectWriter::create(path, false).unwrap();
+    let input = "type,client,tx,amount\ndeposit,1,1,abc\ndeposit,1,2,1.0,2\ndeposit,1,3,2.0\n";
+    for chunk in CsvChunkedReader::from_reader(input.as_bytes(), 10) {
+        transactions::process::process_transactions_reporting(
+            chunk.unwrap(),
+            &mut clients,
+            &mut transactions,
+            &Policy::default(),
+            &mut rejects,
+        )
+        .unwrap();
+    }
+    rejects.flush().unwrap();
+
+    let mut reader = csv::Reader::from_path(path).unwrap();
+    let rows: Vec<csv::StringRecord> = reader.records().map(Result::unwrap).collect();
+    std::fs::remove_file(path).unwrap();
+
+    let summary: Vec<(&str, &str, &str, &str, &str)> = rows
+        .iter()
+        .map(|row| (&row[0], &row[1], &row[3], &row[7], &row[row.len() - 1]))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("2", "22", "", "invalid_row", "deposit,1,1,abc"),
+            ("3", "38", "", "invalid_row", "deposit,1,2,1.0,2"),
+        ]
+    );
+    assert!(rows[0][8].contains("invalid amount abc"));
+    let expected_result = r#"client, available, held, total, locked
+1, 2.0000, 0.0000, 2.0000, false
+"#;
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+}
+
+#[cfg(test)]
+#[test]
+fn policy_rules() {
+    let run = |policy: &Policy| {
+        let mut clients = clients::ClientList::new();
+        let mut transactions = transactions::manager::TransactionManager::new();
+
+        transactions::process::process_transactions_reporting(
+            read_whole_csv("tests/t11_transactions.csv").unwrap(),
+            &mut clients,
+            &mut transactions,
+            policy,
+            &mut StderrRejects,
+        )
+        .unwrap();
+
+        clients
+    };
+
+    // The dispute can't hold spent funds, so the chargeback fails too
+    let clients = run(&Policy::default());
+    let expected_result = r#"client, available, held, total, locked
+1, 7.0000, 0.0000, 7.0000, false
+2, 0.0000, 4.0000, 4.0000, false
+3, 0.0000, 0.0000, 0.0000, false
+"#;
+    assert_eq!(clients.iter().count(), 3);
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+
+    // Client 1 goes negative and stays unlocked, the withdrawal does not create client 3
+    let clients = run(&Policy::load("tests/t11_policy.toml").unwrap());
+    let expected_result = r#"client, available, held, total, locked
+1, -3.0000, 0.0000, -3.0000, false
+2, 0.0000, 4.0000, 4.0000, false
+"#;
+    assert_eq!(clients.iter().count(), 2);
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+
+    // Deposits can't be disputed here, so neither dispute holds anything
+    let policy = Policy::builder()
+        .disputable_types([transactions::TransactionType::Transfer])
+        .build()
+        .unwrap();
+    let clients = run(&policy);
+    let expected_result = r#"client, available, held, total, locked
+1, 7.0000, 0.0000, 7.0000, false
+2, 4.0000, 0.0000, 4.0000, false
+3, 0.0000, 0.0000, 0.0000, false
+"#;
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+
+    assert!(Policy::builder()
+        .disputable_types([transactions::TransactionType::Dispute])
+        .build()
+        .is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn withdrawal_disputes() {
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+
+    // Withdrawals are only disputable when the policy says so
+    let input = read_whole_csv("tests/t12_transactions.csv").unwrap();
+    transactions::process::process_transactions(input, &mut clients, &mut transactions).unwrap();
+    let expected_result = r#"client, available, held, total, locked
+1, 6.0000, 0.0000, 6.0000, false
+2, 0.0000, 0.0000, 0.0000, false
+3, 0.0000, 0.0000, 0.0000, false
+4, 100.0000, 0.0000, 100.0000, false
+"#;
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let policy = Policy::builder()
+        .disputable_types([
+            transactions::TransactionType::Deposit,
+            transactions::TransactionType::Withdrawal,
+        ])
+        .build()
+        .unwrap();
+    transactions::process::process_transactions_reporting(
+        read_whole_csv("tests/t12_transactions.csv").unwrap(),
+        &mut clients,
+        &mut transactions,
+        &policy,
+        &mut StderrRejects,
+    )
+    .unwrap();
+
+    // A disputed withdrawal is held as a provisional credit that can't be spent,
+    // a resolve takes it back and a chargeback returns the funds. A withdrawal that
+    // was rejected never took any funds, so it can't be disputed or charged back.
+    let expected_result = r#"client, available, held, total, locked
+1, 6.0000, 0.0000, 6.0000, false
+2, 0.0000, 5.0000, 5.0000, false
+3, 2.0000, 0.0000, 2.0000, true
+4, 100.0000, 0.0000, 100.0000, false
+"#;
+
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+}
+
+#[cfg(test)]
+#[test]
+fn dispute_lifecycle() {
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+
+    let input = read_whole_csv("tests/t13_transactions.csv").unwrap();
+    transactions::process::process_transactions(input, &mut clients, &mut transactions).unwrap();
+
+    // Resolved and charged back are final, so the second dispute and the last resolve fail
+    let resolved = transactions.get(&TransactionId::from(1)).unwrap();
+    assert_eq!(resolved.state, DisputeState::Resolved);
+    let steps: Vec<_> = resolved
+        .history
+        .iter()
+        .map(|change| (change.from, change.to, change.position.unwrap().line))
+        .collect();
+    assert_eq!(
+        steps,
+        vec![
+            (DisputeState::Processed, DisputeState::Disputed, 3),
+            (DisputeState::Disputed, DisputeState::Resolved, 4),
+        ]
+    );
+
+    let charged_back = transactions.get(&TransactionId::from(2)).unwrap();
+    assert_eq!(charged_back.state, DisputeState::ChargedBack);
+    assert_eq!(charged_back.history.len(), 2);
+    assert_eq!(
+        charged_back.history[1].cause,
+        transactions::TransactionType::Chargeback
+    );
+    assert_eq!(charged_back.history[1].position.unwrap().line, 8);
+
+    let expected_result = r#"client, available, held, total, locked
+1, 5.0000, 0.0000, 5.0000, true
+"#;
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+}
+
+#[cfg(test)]
+#[test]
+fn admin_operations() {
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let mut rejects = Codes::default();
+    let policy = Policy::builder()
+        .admin_operators([OperatorId::from(7)])
+        .build()
+        .unwrap();
+
+    transactions::process::process_transactions_reporting(
+        read_whole_csv("tests/t14_transactions.csv").unwrap(),
+        &mut clients,
+        &mut transactions,
+        &policy,
+        &mut rejects,
+    )
+    .unwrap();
+
+    assert_eq!(
+        rejects.0,
+        vec![
+            (5, "unauthorized"),
+            (9, "insufficient_funds"),
+            (10, "unauthorized"),
+            (14, "locked_client"),
+            (15, "closed_client"),
+            (17, "non_zero_balance"),
+        ]
+    );
+
+    // Client 1 was unlocked after the chargeback, 2 is closed, and 3 is frozen
+    let expected_result = r#"client, available, held, total, locked
+1, 3.5000, 0.0000, 3.5000, false
+2, 0.0000, 0.0000, 0.0000, true
+3, 2.0000, 0.0000, 2.0000, true
+"#;
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+    assert!(
+        clients
+            .get_client(&clients::ClientId::from(2))
+            .unwrap()
+            .closed
+    );
+
+    // The unlock is kept as the record of who reinstated the account
+    let unlock = transactions.get(&TransactionId::from(3)).unwrap();
+    assert_eq!(unlock.transaction.operator_id, Some(OperatorId::from(7)));
+    assert_eq!(unlock.position.unwrap().line, 6);
+
+    // Without `admin_operators` no operator is allowed to change an account
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let mut rejects = Codes::default();
+    transactions::process::process_transactions_reporting(
+        read_whole_csv("tests/t14_transactions.csv").unwrap(),
+        &mut clients,
+        &mut transactions,
+        &Policy::default(),
+        &mut rejects,
+    )
+    .unwrap();
+    assert_eq!(
+        rejects.0,
+        vec![
+            (5, "unauthorized"),
+            (6, "unauthorized"),
+            (7, "locked_client"),
+            (8, "unauthorized"),
+            (9, "unauthorized"),
+            (10, "unauthorized"),
+            (13, "unauthorized"),
+            (15, "unauthorized"),
+            (17, "unauthorized"),
+            (18, "unauthorized"),
+        ]
+    );
+}
+
+#[test]
+fn bounded_store() {
+    let dir = std::env::temp_dir().join(format!("tps2_bounded_store_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let spill_path = dir.join("spill.sqlite");
+
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    transactions
+        .set_limits(StoreLimits {
+            memory_capacity: Some(1),
+            spill_path: Some(spill_path.clone()),
+            dispute_horizon_rows: Some(3),
+        })
+        .unwrap();
+    let mut rejects = Codes::default();
+
+    transactions::process::process_transactions_reporting(
+        read_whole_csv("tests/t15_transactions.csv").unwrap(),
+        &mut clients,
+        &mut transactions,
+        &Policy::default(),
+        &mut rejects,
+    )
+    .unwrap();
+
+    // Transaction 1 was spilled before it was disputed and resolved, 2 fell out of
+    // the window, and 3 was kept past the window because it was in a dispute
+    assert_eq!(rejects.0, vec![(8, "missing_transaction")]);
+
+    let expected_result = r#"client, available, held, total, locked
+1, 15.0000, 0.0000, 15.0000, false
+2, 3.0000, 0.0000, 3.0000, true
+"#;
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+
+    // The journal entries of the dropped transaction went with it, which a snapshot
+    // keeps track of, so the books aren't checked against what is left
+    assert!(transactions.journal_truncated());
+    let snapshot_path = dir.join("snapshot.json");
+    let snapshot_path = snapshot_path.to_str().unwrap();
+    save_snapshot(snapshot_path, &clients, &transactions).unwrap();
+    assert!(Snapshot::load(snapshot_path)
+        .unwrap()
+        .transactions
+        .journal_truncated());
+    std::fs::remove_file(snapshot_path).unwrap();
+
+    // The snapshot holds the spilled transactions along with the ones in memory
+    assert!(spill_path.exists());
+    let json = serde_json::to_string(&transactions).unwrap();
+    drop(transactions);
+    assert!(!spill_path.exists());
+
+    let restored: transactions::manager::TransactionManager = serde_json::from_str(&json).unwrap();
+    let charged_back = restored.get(&TransactionId::from(3)).unwrap();
+    assert_eq!(charged_back.state, DisputeState::ChargedBack);
+    assert!(restored.get(&TransactionId::from(2)).is_none());
+    assert!(restored.get(&TransactionId::from(6)).is_some());
+
+    // The spill file is read back a page at a time, still in the order of the rows
+    let mut transactions = transactions::manager::TransactionManager::new();
+    transactions
+        .set_limits(StoreLimits {
+            memory_capacity: Some(10),
+            spill_path: Some(spill_path.clone()),
+            dispute_horizon_rows: None,
+        })
+        .unwrap();
+    let csv: String = std::iter::once("type,client,tx,amount\n".to_string())
+        .chain((1..=3000).map(|tx| format!("deposit,1,{tx},1.0\n")))
+        .collect();
+    let rows = CsvChunkedReader::from_reader(csv.as_bytes(), 500).flat_map(Result::unwrap);
+    transactions::process::process_transactions(
+        rows.collect(),
+        &mut clients::ClientList::new(),
+        &mut transactions,
+    )
+    .unwrap();
+
+    let ids: Vec<u32> = transactions
+        .into_iter()
+        .map(|stored| u32::from(stored.unwrap().transaction.tx_id))
+        .collect();
+    assert_eq!(ids, (1..=3000).collect::<Vec<_>>());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn sqlite_storage() {
+    let db_path = std::env::temp_dir().join("tps2_sqlite_storage_test.db");
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{suffix}", db_path.display()));
+    }
+
+    let policy = Policy::default();
+    let mut storage = SqliteStorage::open(&db_path).unwrap();
+    process_with_storage(
+        read_whole_csv("tests/t6_transactions.csv").unwrap(),
+        &mut storage,
+        &policy,
+        &mut StderrRejects,
+    )
+    .unwrap();
+    drop(storage);
+
+    // The state outlives the connection, and a failing batch leaves none of its changes
+    let mut storage = SqliteStorage::open(&db_path).unwrap();
+    let batch = read_whole_csv("tests/t7_transactions.csv").unwrap();
+    assert!(matches!(
+        storage.process_batch(&batch, &policy),
+        Err(transactions::TransactionError::InsufficientFunds(_))
+    ));
+    assert!(storage
+        .get_transaction(&TransactionId::from(10))
+        .unwrap()
+        .is_none());
+
+    // Without the failing withdrawal the batch goes through, including the dispute
+    // of a transaction stored by the earlier connection
+    storage.process_batch(&batch[..3], &policy).unwrap();
+    let duplicate = batch[0].clone();
+    assert!(matches!(
+        storage.process(duplicate, &policy),
+        Err(transactions::TransactionError::DuplicateTransactionId(_))
+    ));
+
+    let (clients, transactions) = Box::new(storage).into_state().unwrap();
+    let expected_result = r#"client, available, held, total, locked
+1, 0.0000, 5.0000, 5.0000, false
+2, 2.0000, 0.0000, 2.0000, false
+3, 2.0000, 0.0000, 2.0000, false
+"#;
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+
+    let disputed = transactions.get(&TransactionId::from(1)).unwrap();
+    assert_eq!(disputed.state, DisputeState::Disputed);
+    assert_eq!(disputed.history.len(), 1);
+}
+
+#[test]
+fn sqlite_upgrade() {
+    let db_path =
+        std::env::temp_dir().join(format!("tps2_sqlite_upgrade_{}.db", std::process::id()));
+
+    // A version 2 database, which only had the JSON of a transaction
+    {
+        let connection = rusqlite::Connection::open(&db_path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE clients (id INTEGER PRIMARY KEY, client TEXT NOT NULL);
+                 CREATE TABLE transactions (
+                     seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                     tx INTEGER NOT NULL UNIQUE,
+                     stored TEXT NOT NULL
+                 );
+                 PRAGMA user_version = 2;",
+            )
+            .unwrap();
+
+        let mut clients = clients::ClientList::new();
+        let mut transactions = transactions::manager::TransactionManager::new();
+        transactions::process::process_transactions(
+            read_whole_csv("tests/t24_transactions.csv").unwrap()[..2].to_vec(),
+            &mut clients,
+            &mut transactions,
+        )
+        .unwrap();
+        for client in clients.iter() {
+            connection
+                .execute(
+                    "INSERT INTO clients (id, client) VALUES (?1, ?2)",
+                    rusqlite::params![u16::from(client.id), serde_json::to_string(client).unwrap()],
+                )
+                .unwrap();
+        }
+        for stored in transactions {
+            let stored = stored.unwrap();
+            connection
+                .execute(
+                    "INSERT INTO transactions (tx, stored) VALUES (?1, ?2)",
+                    rusqlite::params![
+                        u32::from(stored.transaction.tx_id),
+                        serde_json::to_string(&stored).unwrap()
+                    ],
+                )
+                .unwrap();
+        }
+    }
+
+    // The upgraded database is used like a new one. The chargeback of the transfer
+    // only names the receiver, the sender is loaded because the operation involves it.
+    let mut storage = SqliteStorage::open(&db_path).unwrap();
+    let rows = "type,client,tx\ndispute,2,2\nchargeback,2,2\n";
+    process_with_storage(
+        CsvChunkedReader::from_reader(rows.as_bytes(), 10)
+            .flat_map(Result::unwrap)
+            .collect(),
+        &mut storage,
+        &Policy::default(),
+        &mut StderrRejects,
+    )
+    .unwrap();
+    drop(storage);
+
+    let connection = rusqlite::Connection::open(&db_path).unwrap();
+    let types: Vec<String> = connection
+        .prepare("SELECT type FROM transactions ORDER BY seq")
+        .unwrap()
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(types, vec!["deposit", "transfer"]);
+    drop(connection);
+
+    let (clients, _) = Box::new(SqliteStorage::open(&db_path).unwrap())
+        .into_state()
+        .unwrap();
+    std::fs::remove_file(&db_path).unwrap();
+
+    let expected_result = r#"client, available, held, total, locked
+1, 10.0000, 0.0000, 10.0000, false
+2, 0.0000, 0.0000, 0.0000, true
+"#;
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+}
+
+#[test]
+fn multi_currency() {
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let mut rejects = Codes::default();
+
+    transactions::process::process_transactions_reporting(
+        read_whole_csv("tests/t16_transactions.csv").unwrap(),
+        &mut clients,
+        &mut transactions,
+        &Policy::default(),
+        &mut rejects,
+    )
+    .unwrap();
+
+    // Euros can't be paid out of dollars, and the EUR deposit can only be disputed in EUR
+    assert_eq!(
+        rejects.0,
+        vec![(5, "insufficient_funds"), (7, "currency_mismatch")]
+    );
+
+    let usd: Currency = "USD".parse().unwrap();
+    let eur: Currency = "eur".parse().unwrap();
+
+    // The chargeback took the euros and locked the whole account
+    let client = clients.get_client(&clients::ClientId::from(1)).unwrap();
+    assert_eq!(client.balance(usd).available, Decimal::from(6));
+    assert_eq!(client.balance(eur).total, Decimal::from(0));
+    assert!(client.locked);
+
+    let client = clients.get_client(&clients::ClientId::from(2)).unwrap();
+    assert_eq!(client.balances.keys().collect::<Vec<_>>(), vec![&eur]);
+    assert_eq!(client.balance(eur).available, Decimal::from(3));
+
+    let output = clients.to_string();
+    assert!(output.starts_with("client, available, held, total, locked, currency\n"));
+    assert!(output.contains("1, 6.0000, 0.0000, 6.0000, true, USD\n"));
+    assert!(output.contains("1, 0.0000, 0.0000, 0.0000, true, EUR\n"));
+    assert!(output.contains("2, 3.0000, 0.0000, 3.0000, false, EUR\n"));
+
+    // Other currencies are nested by currency, a client in the default one only is flat
+    let client = clients.get_client(&clients::ClientId::from(1)).unwrap();
+    let json = serde_json::to_value(client).unwrap();
+    assert_eq!(json["balances"]["USD"]["available"], "6");
+    let restored: clients::Client = serde_json::from_value(json).unwrap();
+    assert_eq!(restored.balances, client.balances);
+
+    let client = clients::Client::new_with_values(3, Decimal::ONE, Decimal::ZERO, Decimal::ONE);
+    let json = serde_json::to_value(&client).unwrap();
+    assert_eq!(json["available"], "1");
+    let restored: clients::Client = serde_json::from_value(json).unwrap();
+    assert_eq!(restored.balances, client.balances);
+}
+
+#[test]
+fn currency_conversion() {
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let mut rejects = Codes::default();
+
+    let rates = RateTable::load("tests/t17_rates.csv").unwrap();
+    let policy = Policy::builder()
+        .house_account(clients::ClientId::from(9000))
+        .build()
+        .unwrap()
+        .with_rates(rates)
+        .unwrap();
+
+    transactions::process::process_transactions_reporting(
+        read_whole_csv("tests/t17_transactions.csv").unwrap(),
+        &mut clients,
+        &mut transactions,
+        &policy,
+        &mut rejects,
+    )
+    .unwrap();
+
+    assert_eq!(
+        rejects.0,
+        vec![
+            (5, "insufficient_funds"),
+            (8, "missing_rate"),
+            (9, "missing_target_currency"),
+            (10, "not_disputable"),
+        ]
+    );
+
+    // The latest EUR rate is used, and the rounded off part is kept with the transaction
+    transactions.load(&TransactionId::from(3)).unwrap();
+    let conversion = transactions
+        .get(&TransactionId::from(3))
+        .unwrap()
+        .conversion
+        .unwrap();
+    assert_eq!(conversion.rate, Decimal::new(10850, 4));
+    assert_eq!(conversion.credited, Decimal::new(13394, 4));
+    assert_eq!(conversion.remainder, Decimal::new(325, 7));
+
+    // JPY to USD only has the other direction, so that rate is inverted
+    let output = clients.to_string();
+    assert!(output.contains("1, 88.7655, 0.0000, 88.7655, false, EUR\n"));
+    assert!(output.contains("1, 51.3700, 0.0000, 51.3700, false, JPY\n"));
+    assert!(output.contains("1, 11.8500, 0.0000, 11.8500, false, USD\n"));
+
+    // What the rounding took off is with the house
+    transactions.load(&TransactionId::from(6)).unwrap();
+    let remainders = conversion.remainder
+        + transactions
+            .get(&TransactionId::from(6))
+            .unwrap()
+            .conversion
+            .unwrap()
+            .remainder;
+    let house = clients.get_client(&clients::ClientId::from(9000)).unwrap();
+    assert_eq!(house.balance(Currency::default()).available, remainders);
+    assert_eq!(house.balances.len(), 1);
+
+    // A failed batch takes back the balances its conversion added
+    let read_batch = |rows: &str| -> Vec<_> {
+        CsvChunkedReader::from_reader(
+            format!("type,client,tx,amount,currency,to_currency\n{rows}").as_bytes(),
+            10,
+        )
+        .flat_map(Result::unwrap)
+        .collect()
+    };
+    let deposit = read_batch("deposit,2,20,10,EUR,\n");
+    transactions::batch::process_batch(&deposit, &mut clients, &mut transactions, &policy).unwrap();
+    let batch = read_batch("convert,2,21,1.0,EUR,USD\nwithdrawal,2,22,100,EUR,\n");
+    assert!(
+        transactions::batch::process_batch(&batch, &mut clients, &mut transactions, &policy)
+            .is_err()
+    );
+    let client = clients.get_client(&clients::ClientId::from(2)).unwrap();
+    assert_eq!(client.balances.len(), 1);
+    assert_eq!(
+        clients
+            .get_client(&clients::ClientId::from(9000))
+            .unwrap()
+            .balance(Currency::default())
+            .available,
+        remainders
+    );
+
+    // Conversions need somewhere to put the rounded off part
+    assert!(Policy::default()
+        .with_rates(RateTable::load("tests/t17_rates.csv").unwrap())
+        .is_err());
+
+    // A conversion stays within the client, so there is nothing to dispute
+    assert!(Policy::builder()
+        .disputable_types([transactions::TransactionType::Convert])
+        .build()
+        .is_err());
+}
+
+#[test]
+fn fees() {
+    let policy = Policy::load("tests/t18_policy.toml").unwrap();
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let mut rejects = Codes::default();
+
+    transactions::process::process_transactions_reporting(
+        read_whole_csv("tests/t18_transactions.csv").unwrap(),
+        &mut clients,
+        &mut transactions,
+        &policy,
+        &mut rejects,
+    )
+    .unwrap();
+
+    // The deposit can't pay for its own fee, and the last withdrawal leaves nothing for it
+    assert_eq!(
+        rejects.0,
+        vec![(6, "insufficient_funds"), (9, "insufficient_funds")]
+    );
+
+    // The fees stay with the house account after the chargeback
+    let expected_result = r#"client, available, held, total, locked
+1, 981.4000, 0.0000, 981.4000, true
+2, 0.0000, 0.0000, 0.0000, false
+100, 9.6000, 0.0000, 9.6000, false
+"#;
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+
+    // The capped fee is recorded with the withdrawal, the rejected deposit has none
+    let fee = transactions
+        .get(&TransactionId::from(4))
+        .unwrap()
+        .fee
+        .unwrap();
+    assert_eq!(fee.amount, Decimal::from(5));
+    assert_eq!(fee.house_account, clients::ClientId::from(100));
+    assert!(transactions
+        .get(&TransactionId::from(5))
+        .unwrap()
+        .fee
+        .is_none());
+
+    let path = std::env::temp_dir().join(format!("tps-fees-{}.csv", std::process::id()));
+    let path = path.to_str().unwrap();
+    write_fee_report(path, transactions.into_iter().map(Result::unwrap)).unwrap();
+    let report = std::fs::read_to_string(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(
+        report,
+        "tx,type,client,fee,house,currency
+1,deposit,1,1.0000,100,USD
+2,deposit,1,2.0000,100,USD
+3,withdrawal,1,0.6000,100,USD
+4,withdrawal,1,5.0000,100,USD
+6,deposit,2,1.0000,100,USD
+"
+    );
+
+    // The house account is in another shard than both clients
+    let mut sharded = ShardedProcessor::with_reject_sink(
+        4,
+        clients::ClientList::new(),
+        transactions::manager::TransactionManager::new(),
+        policy.clone(),
+        Box::new(Codes::default()),
+    )
+    .unwrap();
+    sharded
+        .process(read_whole_csv("tests/t18_transactions.csv").unwrap())
+        .unwrap();
+    let (sharded_clients, _) = sharded.finish().unwrap();
+    assert_clients_equal_ignore_order(&sharded_clients.to_string(), expected_result);
+
+    // Fees need somewhere to go
+    let rule = FeeRule {
+        tx_type: transactions::TransactionType::Deposit,
+        group: None,
+        flat: Decimal::from(1),
+        percent: Decimal::ZERO,
+        tiers: Vec::new(),
+        cap: None,
+    };
+    assert!(Policy::builder().fee(rule.clone()).build().is_err());
+    let policy = Policy::builder()
+        .house_account(clients::ClientId::from(100))
+        .fee(rule)
+        .build()
+        .unwrap();
+
+    // A failed batch takes its fees back too
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let batch = read_whole_csv("tests/t18_transactions.csv").unwrap();
+    assert!(transactions::batch::process_batch(
+        &batch[..5],
+        &mut clients,
+        &mut transactions,
+        &policy
+    )
+    .is_err());
+    assert_eq!(clients.iter().count(), 0);
+}
+
+#[test]
+fn risk_rules() {
+    let policy = Policy::load("tests/t19_policy.toml").unwrap();
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let mut rejects = Codes::default();
+
+    transactions::process::process_transactions_reporting(
+        read_whole_csv("tests/t19_transactions.csv").unwrap(),
+        &mut clients,
+        &mut transactions,
+        &policy,
+        &mut rejects,
+    )
+    .unwrap();
+
+    // The third withdrawal in four rows, and the second dispute within the hour. The
+    // last dispute has no timestamp, so the hour can't be measured and it goes through.
+    assert_eq!(rejects.0, vec![(6, "risk_rejected"), (11, "risk_rejected")]);
+
+    let expected_result = r#"client, available, held, total, locked
+1, 81.0000, 0.0000, 81.0000, false
+2, 20.0000, 20.0000, 40.0000, false
+"#;
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+
+    // Flagged transactions still go through, with every rule that flagged them kept
+    // with the transaction
+    let flags = |tx: u32| -> Vec<(u16, String)> {
+        transactions
+            .get(&TransactionId::from(tx))
+            .unwrap()
+            .flags
+            .iter()
+            .map(|flag| (u16::from(flag.client), flag.rule.clone()))
+            .collect()
+    };
+    assert_eq!(
+        flags(2),
+        vec![
+            (1, "large withdrawal".to_string()),
+            (1, "deposit_then_withdrawal".to_string()),
+        ]
+    );
+    assert_eq!(flags(7), vec![(1, "deposit_then_withdrawal".to_string())]);
+    assert_eq!(flags(9), vec![(2, "deposit_then_withdrawal".to_string())]);
+
+    // The review report has a line per flag
+    let path = std::env::temp_dir().join(format!("tps-review-{}.csv", std::process::id()));
+    let path = path.to_str().unwrap();
+    let stored: Vec<_> = transactions.into_iter().map(Result::unwrap).collect();
+    write_review_report(path, &stored, None).unwrap();
+    let report = std::fs::read_to_string(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(
+        report,
+        "client,tx,type,rule,amount,currency,at
+1,2,withdrawal,large withdrawal,5000.0000,USD,2024-05-01T09:01:00Z
+1,2,withdrawal,deposit_then_withdrawal,5000.0000,USD,2024-05-01T09:01:00Z
+1,7,withdrawal,deposit_then_withdrawal,10.0000,USD,2024-05-01T09:06:00Z
+2,9,withdrawal,deposit_then_withdrawal,10.0000,USD,2024-05-01T09:11:00Z
+"
+    );
+
+    // The history is kept with the clients, so every shard sees the same
+    let mut sharded = ShardedProcessor::with_reject_sink(
+        3,
+        clients::ClientList::new(),
+        transactions::manager::TransactionManager::new(),
+        policy,
+        Box::new(Codes::default()),
+    )
+    .unwrap();
+    for chunk in CsvChunkedReader::new("tests/t19_transactions.csv", 2).unwrap() {
+        sharded.process(chunk.unwrap()).unwrap();
+    }
+    let (sharded_clients, _) = sharded.finish().unwrap();
+    assert_clients_equal_ignore_order(&sharded_clients.to_string(), expected_result);
+
+    // Without rules nothing is kept
+    let client = clients::Client::new(1);
+    assert!(!serde_json::to_string(&client).unwrap().contains("risk"));
+}
+
+#[test]
+fn limits_and_overdrafts() {
+    let limits = LimitEntry::load("tests/t20_limits.csv").unwrap();
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let mut rejects = Codes::default();
+
+    // The file doesn't create clients, they get their limits when they are created
+    apply_limits(&limits, &mut clients);
+    assert_eq!(clients.iter().count(), 0);
+    let policy = Policy::builder()
+        .admin_operators([OperatorId::from(7)])
+        .build()
+        .unwrap()
+        .with_limits(limits.clone());
+
+    transactions::process::process_transactions_reporting(
+        read_whole_csv("tests/t20_transactions.csv").unwrap(),
+        &mut clients,
+        &mut transactions,
+        &policy,
+        &mut rejects,
+    )
+    .unwrap();
+
+    // Past the overdraft, over the daily and then the monthly limit, an overdraft
+    // below what is already owed, and a limit without an operator. Client 5's days
+    // are those of its rows, the row without a timestamp counts for the last one.
+    assert_eq!(
+        rejects.0,
+        vec![
+            (4, "insufficient_funds"),
+            (7, "limit_exceeded"),
+            (10, "limit_exceeded"),
+            (11, "reverted"),
+            (12, "unauthorized"),
+            (17, "limit_exceeded"),
+            (19, "limit_exceeded"),
+        ]
+    );
+
+    let expected_result = r#"client, available, held, total, locked
+1, -80.0000, 0.0000, -80.0000, false
+2, 140.0000, 0.0000, 140.0000, false
+3, -25.0000, 0.0000, -25.0000, false
+5, 145.0000, 0.0000, 145.0000, false
+"#;
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+
+    let usd = Currency::default();
+    let client = clients.get_client(&clients::ClientId::from(1)).unwrap();
+    assert!(client.is_valid());
+    assert_eq!(client.limits(usd).overdraft, Decimal::from(100));
+
+    let client = clients.get_client(&clients::ClientId::from(2)).unwrap();
+    assert_eq!(client.limits(usd).daily, Some(Decimal::from(100)));
+    assert_eq!(client.limits(usd).spent.monthly, Decimal::from(60));
+
+    // A failed batch takes back the month's count even after a new day started
+    let batch: Vec<_> = CsvChunkedReader::from_reader(
+        "type,client,tx,amount,timestamp
+withdrawal,5,21,5,2024-06-01T01:00:00Z
+withdrawal,5,22,5,2024-06-02T01:00:00Z
+withdrawal,5,23,1000,2024-06-02T02:00:00Z
+"
+        .as_bytes(),
+        10,
+    )
+    .flat_map(Result::unwrap)
+    .collect();
+    assert!(
+        transactions::batch::process_batch(&batch, &mut clients, &mut transactions, &policy)
+            .is_err()
+    );
+    let client = clients.get_client(&clients::ClientId::from(5)).unwrap();
+    assert_eq!(client.limits(usd).spent.monthly, Decimal::from(10));
+
+    // Transfers and conversions count against the limits of the funds they take too
+    let policy = Policy::builder()
+        .house_account(clients::ClientId::from(100))
+        .admin_operators([OperatorId::from(7)])
+        .build()
+        .unwrap()
+        .with_rates(RateTable::load("tests/t17_rates.csv").unwrap())
+        .unwrap();
+    let mut rejects = Codes::default();
+    let rows: Vec<_> = CsvChunkedReader::from_reader(
+        "type,client,tx,amount,currency,to_currency,to,operator,limit,timestamp
+deposit,6,31,500,,,,,,2024-05-01T09:00:00Z
+limit,6,32,100,,,,7,daily,
+transfer,6,33,60,,,7,,,2024-05-01T10:00:00Z
+convert,6,34,30,USD,JPY,,,,2024-05-01T11:00:00Z
+transfer,6,35,20,,,7,,,2024-05-01T12:00:00Z
+convert,6,36,20,USD,JPY,,,,2024-05-01T12:00:00Z
+withdrawal,6,37,10,,,,,,2024-05-01T13:00:00Z
+transfer,6,38,20,,,7,,,2024-05-02T09:00:00Z
+"
+        .as_bytes(),
+        10,
+    )
+    .flat_map(Result::unwrap)
+    .collect();
+    transactions::process::process_transactions_reporting(
+        rows,
+        &mut clients,
+        &mut transactions,
+        &policy,
+        &mut rejects,
+    )
+    .unwrap();
+    assert_eq!(
+        rejects.0,
+        vec![(6, "limit_exceeded"), (7, "limit_exceeded")]
+    );
+
+    // A transfer taken back by a failed batch is taken off the count as well
+    let batch: Vec<_> = CsvChunkedReader::from_reader(
+        "type,client,tx,amount,to,timestamp
+transfer,6,39,30,7,2024-05-02T10:00:00Z
+withdrawal,6,40,1000,,2024-05-02T11:00:00Z
+"
+        .as_bytes(),
+        10,
+    )
+    .flat_map(Result::unwrap)
+    .collect();
+    assert!(
+        transactions::batch::process_batch(&batch, &mut clients, &mut transactions, &policy)
+            .is_err()
+    );
+    let client = clients.get_client(&clients::ClientId::from(6)).unwrap();
+    assert_eq!(client.limits(usd).spent.monthly, Decimal::from(120));
+
+    // The same file sets up the clients of a database
+    let db_path = std::env::temp_dir().join("tps2_limits_test.db");
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{suffix}", db_path.display()));
+    }
+    let mut storage = SqliteStorage::open(&db_path).unwrap();
+    process_with_storage(
+        read_whole_csv("tests/t20_transactions.csv").unwrap()[3..4].to_vec(),
+        &mut storage,
+        &Policy::default(),
+        &mut Codes::default(),
+    )
+    .unwrap();
+    apply_limits_to_storage(&limits, &mut storage).unwrap();
+    assert!(storage
+        .get_client(&clients::ClientId::from(1))
+        .unwrap()
+        .is_none());
+    let client = storage
+        .get_client(&clients::ClientId::from(2))
+        .unwrap()
+        .unwrap();
+    assert_eq!(client.limits(usd).monthly, Some(Decimal::from(80)));
+    drop(storage);
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{suffix}", db_path.display()));
+    }
+}
+
+#[test]
+fn interest_accrual() {
+    let policy = Policy::load("tests/t21_policy.toml").unwrap();
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let mut rejects = Codes::default();
+
+    transactions::process::process_transactions_reporting(
+        read_whole_csv("tests/t21_transactions.csv").unwrap(),
+        &mut clients,
+        &mut transactions,
+        &policy,
+        &mut rejects,
+    )
+    .unwrap();
+
+    // Interest can't come from a row, and an end of day has to name its day and
+    // can't skip one
+    assert_eq!(
+        rejects.0,
+        vec![
+            (9, "invalid_transaction"),
+            (12, "insufficient_funds"),
+            (13, "missed_end_of_day"),
+            (14, "missing_date")
+        ]
+    );
+
+    // The first end of day only starts the clock, then every day pays a day, and a
+    // second one for the same day pays nothing. Client 4 earns less than the
+    // smallest amount in a day, which is carried over until it adds up.
+    let expected_result = r#"client, available, held, total, locked
+1, 2000.9002, 0.0000, 2000.9002, false
+2, 100.0020, 0.0000, 100.0020, false
+3, 0.0000, 0.0000, 0.0000, false
+4, 3.0001, 0.0000, 3.0001, false
+"#;
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+
+    let usd = Currency::default();
+    let client = clients.get_client(&clients::ClientId::from(4)).unwrap();
+    assert_eq!(
+        client.interest[&usd].carry,
+        Decimal::from_str_exact("-0.000009999").unwrap()
+    );
+
+    // Client 2 earned nothing while frozen, and isn't paid for it once unlocked
+    let day = |day: &str| day.parse::<chrono::NaiveDate>().unwrap();
+    let interest = |transactions: &transactions::manager::TransactionManager| {
+        transactions
+            .postings()
+            .iter()
+            .map(|posting| {
+                (
+                    u32::from(posting.id),
+                    posting.date,
+                    u16::from(posting.client_id),
+                    posting.amount,
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let amount = |amount: &str| Decimal::from_str_exact(amount).unwrap();
+    let expected_payments = vec![
+        (1, day("2024-01-02"), 1, amount("0.3")),
+        (2, day("2024-01-02"), 2, amount("0.001")),
+        (3, day("2024-01-03"), 1, amount("0.3001")),
+        (4, day("2024-01-03"), 4, amount("0.0001")),
+        (5, day("2024-01-04"), 1, amount("0.3001")),
+        (6, day("2024-01-04"), 2, amount("0.001")),
+    ];
+    assert_eq!(interest(&transactions), expected_payments);
+
+    // The report lists the payments by their own ids, up to the time asked for
+    let report_path =
+        std::env::temp_dir().join(format!("tps2_interest_report_{}.csv", std::process::id()));
+    let report_path = report_path.to_str().unwrap();
+    write_interest_report(
+        report_path,
+        transactions.postings(),
+        Some("2024-01-03T00:00:00Z".parse().unwrap()),
+    )
+    .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(report_path).unwrap(),
+        "posting,client,amount,currency,date,at
+1,1,0.3000,USD,2024-01-02,2024-01-03T00:00:00Z
+2,2,0.0010,USD,2024-01-02,2024-01-03T00:00:00Z
+"
+    );
+    std::fs::remove_file(report_path).unwrap();
+
+    // The payments are on the ledger, without being stored transactions
+    let postings = transactions.take_postings();
+    let stored: Vec<_> = transactions.into_iter().map(Result::unwrap).collect();
+    assert!(stored
+        .iter()
+        .all(|stored| stored.transaction.tx_type != transactions::TransactionType::Interest));
+    let ledger = Ledger::from_transactions(&stored, &postings);
+    assert!(ledger.is_balanced());
+    assert!(ledger.reconcile(&clients).is_empty());
+    assert_eq!(
+        ledger.account(usd, Account::InterestExpense),
+        amount("0.9023")
+    );
+
+    // The shards pay the same interest
+    let mut sharded = ShardedProcessor::with_reject_sink(
+        4,
+        clients::ClientList::new(),
+        transactions::manager::TransactionManager::new(),
+        policy.clone(),
+        Box::new(Codes::default()),
+    )
+    .unwrap();
+    sharded
+        .process(read_whole_csv("tests/t21_transactions.csv").unwrap())
+        .unwrap();
+    let (sharded_clients, sharded_transactions) = sharded.finish().unwrap();
+    assert_clients_equal_ignore_order(&sharded_clients.to_string(), expected_result);
+    assert_eq!(interest(&sharded_transactions), expected_payments);
+
+    // So does a database, which only loads the records a row needs
+    let db_path = std::env::temp_dir().join("tps2_interest_test.db");
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{suffix}", db_path.display()));
+    }
+    let mut storage = SqliteStorage::open(&db_path).unwrap();
+    process_with_storage(
+        read_whole_csv("tests/t21_transactions.csv").unwrap(),
+        &mut storage,
+        &policy,
+        &mut Codes::default(),
+    )
+    .unwrap();
+    let ids: Vec<u32> = rusqlite::Connection::open(&db_path)
+        .unwrap()
+        .prepare("SELECT json_extract(posting, '$.id') FROM postings ORDER BY seq")
+        .unwrap()
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(ids, vec![1, 2, 3, 4, 5, 6]);
+    let (db_clients, db_transactions) = Box::new(storage).into_state().unwrap();
+    assert_clients_equal_ignore_order(&db_clients.to_string(), expected_result);
+    assert_eq!(interest(&db_transactions), expected_payments);
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{suffix}", db_path.display()));
+    }
+}
+
+#[test]
+fn double_entry_ledger() {
+    let policy = Policy::builder()
+        .house_account(clients::ClientId::from(100))
+        .admin_operators([OperatorId::from(9)])
+        .build()
+        .unwrap()
+        .with_rates(RateTable::load("tests/t17_rates.csv").unwrap())
+        .unwrap();
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let mut rejects = Codes::default();
+
+    transactions::process::process_transactions_reporting(
+        read_whole_csv("tests/t22_transactions.csv").unwrap(),
+        &mut clients,
+        &mut transactions,
+        &policy,
+        &mut rejects,
+    )
+    .unwrap();
+    assert!(rejects.0.is_empty());
+
+    // A batch that fails takes back the entries its dispute made
+    let batch: Vec<_> = CsvChunkedReader::from_reader(
+        "type,client,tx,amount\ndeposit,1,8,100\ndispute,1,1,\nwithdrawal,1,9,1000\n".as_bytes(),
+        10,
+    )
+    .flat_map(Result::unwrap)
+    .collect();
+    assert!(matches!(
+        transactions::batch::process_batch(&batch, &mut clients, &mut transactions, &policy),
+        Err(transactions::TransactionError::InsufficientFunds(_))
+    ));
+
+    let usd = Currency::default();
+    let eur: Currency = "EUR".parse().unwrap();
+    let journal_len = |tx: u32| {
+        transactions
+            .get(&TransactionId::from(tx))
+            .unwrap()
+            .journal
+            .len()
+    };
+    assert_eq!(journal_len(1), 1);
+    assert_eq!(
+        transactions.get(&TransactionId::from(1)).unwrap().journal[0].lines,
+        vec![
+            Line::debit(Account::HouseCash, Decimal::from(100)),
+            Line::credit(
+                Account::Available(clients::ClientId::from(1)),
+                Decimal::from(100)
+            ),
+        ]
+    );
+    // The transfer, then its dispute and resolve
+    assert_eq!(journal_len(4), 3);
+
+    let stored: Vec<_> = transactions.into_iter().map(Result::unwrap).collect();
+    let ledger = Ledger::from_transactions(&stored, &[]);
+    assert!(ledger.is_balanced());
+    assert!(ledger.reconcile(&clients).is_empty());
+
+    let amount = |amount: &str| Decimal::from_str_exact(amount).unwrap();
+    assert_eq!(ledger.account(usd, Account::HouseCash), amount("130"));
+    assert_eq!(ledger.account(usd, Account::ChargebackLoss), amount("-50"));
+    assert_eq!(ledger.account(usd, Account::Exchange), amount("10.85"));
+    assert_eq!(ledger.account(eur, Account::Exchange), amount("-10"));
+    assert_eq!(ledger.account(usd, Account::Adjustments), amount("-5"));
+
+    let client = clients.get_client(&clients::ClientId::from(1)).unwrap();
+    assert_eq!(ledger.balance(client.id, usd).available, amount("55.85"));
+    assert_eq!(ledger.balance(client.id, eur), client.balance(eur));
+
+    // Debits and credits come out the same in every currency
+    let path = std::env::temp_dir().join("tps2_trial_balance_test.csv");
+    write_trial_balance(path.to_str().unwrap(), &ledger).unwrap();
+    let report = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(report.contains("EUR,total,,40.0000,40.0000"));
+    assert!(report.contains("USD,total,,"));
+    assert!(report.contains("USD,house_cash,,130.0000,0.0000"));
+
+    // The shards keep the same books
+    let mut sharded = ShardedProcessor::with_reject_sink(
+        4,
+        clients::ClientList::new(),
+        transactions::manager::TransactionManager::new(),
+        policy.clone(),
+        Box::new(Codes::default()),
+    )
+    .unwrap();
+    sharded
+        .process(read_whole_csv("tests/t22_transactions.csv").unwrap())
+        .unwrap();
+    let (sharded_clients, sharded_transactions) = sharded.finish().unwrap();
+    let stored: Vec<_> = sharded_transactions
+        .into_iter()
+        .map(Result::unwrap)
+        .collect();
+    let ledger = Ledger::from_transactions(&stored, &[]);
+    assert!(ledger.is_balanced());
+    assert!(ledger.reconcile(&sharded_clients).is_empty());
+
+    // An operation posts both sides of what it does, nothing makes up the difference.
+    // Entries that don't add up, or don't match the balances, roll the operation back.
+    struct Misposted {
+        applied: Decimal,
+        lines: Vec<(Currency, Line)>,
+    }
+
+    impl TransactionOp for Misposted {
+        fn client_id(&self) -> clients::ClientId {
+            clients::ClientId::from(1)
+        }
+
+        fn validate(
+            &self,
+            _clients: &clients::ClientList,
+            _transactions: &transactions::manager::TransactionManager,
+        ) -> Result<(), transactions::TransactionError> {
+            Ok(())
+        }
+
+        fn apply(
+            &self,
+            clients: &mut clients::ClientList,
+            _transactions: &mut transactions::manager::TransactionManager,
+        ) -> Result<(), transactions::TransactionError> {
+            let balance = clients
+                .get_client_mut(&self.client_id())
+                .unwrap()
+                .balance_mut(Currency::default());
+            balance.available += self.applied;
+            balance.total += self.applied;
+            Ok(())
+        }
+
+        fn revert(
+            &self,
+            clients: &mut clients::ClientList,
+            _transactions: &mut transactions::manager::TransactionManager,
+        ) -> Result<(), transactions::TransactionError> {
+            let balance = clients
+                .get_client_mut(&self.client_id())
+                .unwrap()
+                .balance_mut(Currency::default());
+            balance.available -= self.applied;
+            balance.total -= self.applied;
+            Ok(())
+        }
+
+        fn lines(
+            &self,
+            _transactions: &transactions::manager::TransactionManager,
+        ) -> Vec<(Currency, Line)> {
+            self.lines.clone()
+        }
+    }
+
+    let available = Account::Available(clients::ClientId::from(1));
+    let before = clients.to_string();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let execute = |operation: Misposted,
+                   clients: &mut clients::ClientList,
+                   transactions: &mut transactions::manager::TransactionManager| {
+        execute(&operation, clients, transactions, &policy, None)
+    };
+
+    let one_sided = Misposted {
+        applied: amount("10"),
+        lines: vec![(usd, Line::credit(available, amount("10")))],
+    };
+    assert!(matches!(
+        execute(one_sided, &mut clients, &mut transactions),
+        Err(transactions::TransactionError::UnbalancedEntry(_))
+    ));
+
+    let too_little = Misposted {
+        applied: amount("10"),
+        lines: vec![
+            (usd, Line::debit(Account::HouseCash, amount("5"))),
+            (usd, Line::credit(available, amount("5"))),
+        ],
+    };
+    assert!(matches!(
+        execute(too_little, &mut clients, &mut transactions),
+        Err(transactions::TransactionError::JournalMismatch(..))
+    ));
+    assert_eq!(clients.to_string(), before);
+}
+
+#[test]
+fn timestamped_disputes() {
+    let policy = Policy::load("tests/t23_policy.toml").unwrap();
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let mut rejects = Codes::default();
+
+    transactions::process::process_transactions_reporting(
+        read_whole_csv("tests/t23_transactions.csv").unwrap(),
+        &mut clients,
+        &mut transactions,
+        &policy,
+        &mut rejects,
+    )
+    .unwrap();
+
+    // One second past the window, a dispute without a timestamp that can't be
+    // checked against it, a timestamp that can't be read and a dispute dated
+    // before its deposit. Exactly 120 days (counted in UTC) is still in time, a
+    // transaction without a timestamp can always be disputed, and the input goes
+    // on after the bad row.
+    assert_eq!(
+        rejects.0,
+        vec![
+            (5, "dispute_window_expired"),
+            (6, "missing_timestamp"),
+            (10, "invalid_timestamp"),
+            (12, "dispute_before_transaction"),
+        ]
+    );
+
+    let expected_result = r#"client, available, held, total, locked
+1, 100.0000, 0.0000, 100.0000, true
+2, 0.0000, 80.0000, 80.0000, false
+3, 25.0000, 0.0000, 25.0000, false
+"#;
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+
+    // The journal gives the balances as they were at any time
+    let stored: Vec<_> = transactions.into_iter().map(Result::unwrap).collect();
+    let usd = Currency::default();
+    let client_id = clients::ClientId::from(1);
+    let at = |timestamp: &str| Some(timestamp.parse().unwrap());
+    let amount = |amount: &str| Decimal::from_str_exact(amount).unwrap();
+
+    let ledger = Ledger::as_of(&stored, &[], at("2024-01-05T00:00:00Z"));
+    assert_eq!(ledger.balance(client_id, usd).total, amount("100"));
+    assert!(ledger.is_balanced());
+
+    let ledger = Ledger::as_of(&stored, &[], at("2024-05-09T00:00:00Z"));
+    assert_eq!(ledger.balance(client_id, usd).available, amount("100"));
+    assert_eq!(ledger.balance(client_id, usd).held, amount("50"));
+
+    let ledger = Ledger::as_of(&stored, &[], None);
+    assert!(ledger.reconcile(&clients).is_empty());
+
+    // The shards check the window the same way
+    let mut sharded = ShardedProcessor::with_reject_sink(
+        4,
+        clients::ClientList::new(),
+        transactions::manager::TransactionManager::new(),
+        policy,
+        Box::new(Codes::default()),
+    )
+    .unwrap();
+    sharded
+        .process(read_whole_csv("tests/t23_transactions.csv").unwrap())
+        .unwrap();
+    let (sharded_clients, _) = sharded.finish().unwrap();
+    assert_clients_equal_ignore_order(&sharded_clients.to_string(), expected_result);
+
+    assert!(Policy::builder().dispute_window_days(0).build().is_err());
+}
+
+#[test]
+fn duplicate_transaction_ids() {
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let mut rejects = Codes::default();
+
+    transactions::process::process_transactions_reporting(
+        read_whole_csv("tests/t25_transactions.csv").unwrap(),
+        &mut clients,
+        &mut transactions,
+        &Policy::default(),
+        &mut rejects,
+    )
+    .unwrap();
+
+    // Neither duplicate is applied, even the one from another client
+    assert_eq!(
+        rejects.0,
+        vec![(3, "duplicate_transaction"), (5, "duplicate_transaction")]
+    );
+
+    // The dispute and chargeback act on the original deposit
+    let expected_result = r#"client, available, held, total, locked
+1, 0.0000, 0.0000, 0.0000, true
+"#;
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+
+    let stored = transactions.get(&TransactionId::from(1)).unwrap();
+    assert_eq!(stored.transaction.amount, Some(Decimal::from(10)));
+    assert_eq!(stored.state, DisputeState::ChargedBack);
+}
+
+#[test]
+fn rejected_transactions() {
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let mut rejects = Codes::default();
+
+    let rows: Vec<_> = CsvChunkedReader::from_reader(
+        "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,\ndispute,1,2,\n\
+         chargeback,1,2,\ndeposit,1,2,1.0\n"
+            .as_bytes(),
+        10,
+    )
+    .flat_map(Result::unwrap)
+    .collect();
+    transactions::process::process_transactions_reporting(
+        rows,
+        &mut clients,
+        &mut transactions,
+        &Policy::default(),
+        &mut rejects,
+    )
+    .unwrap();
+
+    // The deposit without an amount keeps its id, but there is nothing to dispute
+    assert_eq!(
+        rejects.0,
+        vec![
+            (3, "missing_amount"),
+            (4, "invalid_state_change"),
+            (5, "invalid_state_change"),
+            (6, "duplicate_transaction")
+        ]
+    );
+    let stored = transactions.get(&TransactionId::from(2)).unwrap();
+    assert_eq!(stored.state, DisputeState::Rejected);
+
+    let expected_result = r#"client, available, held, total, locked
+1, 10.0000, 0.0000, 10.0000, false
+"#;
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+}
+
+#[test]
+fn missing_amount() {
+    let mut clients = clients::ClientList::new();
+    let mut transactions = transactions::manager::TransactionManager::new();
+    let mut rejects = Codes::default();
+
+    let rows: Vec<_> = CsvChunkedReader::from_reader(
+        "type,client,tx,amount\ndeposit,1,1,\ndeposit,1,2,2.0\nwithdrawal,1,3,\n".as_bytes(),
+        10,
+    )
+    .flat_map(Result::unwrap)
+    .collect();
+    transactions::process::process_transactions_reporting(
+        rows,
+        &mut clients,
+        &mut transactions,
+        &Policy::default(),
+        &mut rejects,
+    )
+    .unwrap();
+
+    // The rows are rejected, and the ones after them still processed
+    assert_eq!(
+        rejects.0,
+        vec![(2, "missing_amount"), (4, "missing_amount")]
+    );
+    let expected_result = r#"client, available, held, total, locked
+1, 2.0000, 0.0000, 2.0000, false
+"#;
+    assert_clients_equal_ignore_order(&clients.to_string(), expected_result);
+}
+
+// This is a helper struct to make it easier to compare the client records
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Client {
+    pub client: u16,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+// Parses the expected and result CSVs into a Vec of Client records and compares them
+fn parse_client_records(csv_text: &str) -> Result<Vec<Client>, csv::Error> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(csv_text.as_bytes());
+
+    let mut records = vec![];
+    for result in rdr.deserialize() {
+        let record: Client = result?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+// Since order does not matter, we need to sort the records by client ID and then compare
+fn assert_clients_equal_ignore_order(actual: &str, expected: &str) {
+    let mut actual_records = parse_client_records(actual).expect("Failed to parse 'actual' CSV");
+    let mut expected_records =
+        parse_client_records(expected).expect("Failed to parse 'expected' CSV");
+
+    // Sort by client ID to ignore row order
+    actual_records.sort_by_key(|r| r.client);
+    expected_records.sort_by_key(|r| r.client);
+
+    assert_eq!(actual_records.len(), expected_records.len());
+    for (actual, expected) in actual_records.iter().zip(expected_records.iter()) {
+        assert_eq!(actual, expected);
+    }
+}
+
+// Collects the line and code of every rejected row
+#[derive(Default)]
+struct Codes(Vec<(u64, &'static str)>);
+
+impl RejectSink for Codes {
+    fn reject(
+        &mut self,
+        transaction: &transactions::Transaction,
+        error: &transactions::TransactionError,
+        _client: Option<&clients::Client>,
+    ) -> Result<(), TpsError> {
+        self.0
+            .push((transaction.position.unwrap().line, error.code()));
+        Ok(())
     }
 }